@@ -16,6 +16,8 @@ fn main() {
         // bindings for.
         .header("wrapper.h")
         .blacklist_type("max_align_t")
+        // Name enum constants as the header does, e.g. ALPM_DEP_MOD_EQ
+        .prepend_enum_name(false)
         // Finish the builder and generate the bindings.
         .generate()
         // Unwrap the Result and panic on failure.
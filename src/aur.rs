@@ -1,22 +1,82 @@
+use cache::Cache;
+use curl::easy::Easy;
 use failure::Error;
+use flate2::read::GzDecoder;
+use fuzzy::{parse_package_list, PACKAGES_URLPATH};
+use metadata::MetadataDb;
+use package::{aur_packages_from_json, AurPkg};
+use pool::PoolError;
+use serde_json;
+use std::io::Read;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use transport::{parse_retry_after, sleep_unless, RateLimiter, RetryPolicy};
 use url::Url;
 
-#[derive(Copy, Clone)]
+/// Longest RPC URL we'll build before splitting an info request into
+/// several batches. The AUR rejects much longer request lines.
+const MAX_URL_LEN: usize = 4000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum RpcBy {
     SearchByName,
     SearchByNameDesc,
     SearchByMaintainer,
+    SearchByKeywords,
+    SearchByProvides,
+    SearchByReplaces,
 }
 
 #[derive(Fail, Debug)]
 pub enum AurTErrors {
     #[fail(display = "No arguments given.")]
     EmptyArgumentsVector,
+    #[fail(display = "AUR RPC error: {}", message)]
+    RpcError { message: String },
+    #[fail(display = "HTTP {} from {}", code, url)]
+    HttpStatus { code: u32, url: String },
+    #[fail(display = "Rate limited by {} after {} attempts", url, attempts)]
+    RateLimited { url: String, attempts: u32 },
+    #[fail(display = "HTTP {} from {} after {} attempts", code, url, attempts)]
+    ServerError { code: u32, url: String, attempts: u32 },
+    #[fail(display = "Unable to reach {} after {} attempts: {}", url, attempts, reason)]
+    ConnectionFailed {
+        url: String,
+        attempts: u32,
+        reason: String,
+    },
+    #[fail(display = "Can't fetch {} in offline mode", url)]
+    Offline { url: String },
+    #[fail(display = "Invalid response from {}: {}", url, reason)]
+    InvalidResponse { url: String, reason: String },
+}
+
+/// Why a single request failed in a way that's worth retrying
+enum Transient {
+    RateLimited(Option<Duration>),
+    Server(u32),
+    Connection(String),
 }
 
+struct Response {
+    code: u32,
+    body: Vec<u8>,
+    retry_after: Option<Duration>,
+}
+
+/// Clones share the same rate limiter and cache, so every worker thread
+/// counts against one limit.
+#[derive(Clone)]
 pub struct AurT {
     url_prefix: String,
     rpc_version: i64,
+    timeout: u64,
+    retry: RetryPolicy,
+    limiter: Arc<RateLimiter>,
+    cache: Option<Arc<Cache>>,
+    offline: Option<Arc<MetadataDb>>,
+    cancelled: Option<Arc<AtomicBool>>,
 }
 
 impl AurT {
@@ -27,9 +87,54 @@ impl AurT {
         AurT {
             url_prefix: url,
             rpc_version: 5,
+            timeout: 10,
+            retry: RetryPolicy::default(),
+            limiter: Arc::new(RateLimiter::new(0)),
+            cache: None,
+            offline: None,
+            cancelled: None,
+        }
+    }
+
+    /// Give up waiting to retry or for the rate limit once `cancelled`
+    /// is set, e.g. by `TaskPool::cancel_handle`
+    pub fn set_cancel_handle(&mut self, cancelled: Arc<AtomicBool>) {
+        self.cancelled = Some(cancelled);
+    }
+
+    /// Sleep for `duration`, failing if cancelled meanwhile
+    fn wait(&self, duration: Duration) -> Result<(), Error> {
+        if sleep_unless(duration, self.cancelled.as_ref().map(|c| &**c)) {
+            Ok(())
+        } else {
+            Err(Error::from(PoolError::Cancelled))
         }
     }
 
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    /// Allow at most `per_second` requests per second, 0 for no limit
+    pub fn set_rate_limit(&mut self, per_second: u64) {
+        self.limiter = Arc::new(RateLimiter::new(per_second));
+    }
+
+    /// Serve queries from `cache` while its entries are fresh
+    pub fn set_cache(&mut self, cache: Cache) {
+        self.cache = Some(Arc::new(cache));
+    }
+
+    /// Answer every query from `db` and refuse to touch the network
+    pub fn set_offline(&mut self, db: MetadataDb) {
+        self.offline = Some(Arc::new(db));
+    }
+
+    /// Set the connection timeout in seconds
+    pub fn set_timeout(&mut self, timeout: u64) {
+        self.timeout = timeout;
+    }
+
     pub fn aur_build_rpc_info_url(&self, args: &[&str]) -> Result<Url, Error> {
         // Make sure we were given an argument
         if args.is_empty() {
@@ -52,6 +157,28 @@ impl AurT {
         }
     }
 
+    /// Build as many info URLs as needed to query every argument without
+    /// any single URL growing past `MAX_URL_LEN`
+    pub fn aur_build_rpc_info_urls(&self, args: &[&str]) -> Result<Vec<Url>, Error> {
+        if args.is_empty() {
+            return Err(Error::from(AurTErrors::EmptyArgumentsVector));
+        }
+
+        let mut urls = Vec::new();
+        let mut batch: Vec<&str> = Vec::new();
+        for arg in args {
+            batch.push(arg);
+            if batch.len() > 1 && self.aur_build_rpc_info_url(&batch)?.as_str().len() > MAX_URL_LEN
+            {
+                batch.pop();
+                urls.push(self.aur_build_rpc_info_url(&batch)?);
+                batch = vec![arg];
+            }
+        }
+        urls.push(self.aur_build_rpc_info_url(&batch)?);
+        Ok(urls)
+    }
+
     pub fn aur_build_rpc_search_url(&self, rpc_by: RpcBy, arg: &str) -> Result<Url, Error> {
         // Setup url object
         let mut url = Url::parse(&self.url_prefix)?;
@@ -62,6 +189,9 @@ impl AurT {
             RpcBy::SearchByName => "name",
             RpcBy::SearchByNameDesc => "name-desc",
             RpcBy::SearchByMaintainer => "maintainer",
+            RpcBy::SearchByKeywords => "keywords",
+            RpcBy::SearchByProvides => "provides",
+            RpcBy::SearchByReplaces => "replaces",
         };
 
         // Setup query
@@ -72,11 +202,509 @@ impl AurT {
             .append_pair("by", search_by_string);
         Ok(url)
     }
+
+    /// Build the URL of a snapshot tarball from a package's `URLPath`
+    pub fn aur_build_download_url(&self, urlpath: &str) -> Result<Url, Error> {
+        Ok(Url::parse(&self.url_prefix)?.join(urlpath)?)
+    }
+
+    /// Build the URL of a package base's git repository
+    pub fn aur_build_git_url(&self, pkgbase: &str) -> Result<Url, Error> {
+        Ok(Url::parse(&self.url_prefix)?.join(&format!("{}.git", pkgbase))?)
+    }
+
+    /// Fetch `url`, returning the body of a successful response.
+    /// Connection failures, server errors and rate limiting are retried
+    /// with backoff, other failures are returned straight away.
+    pub fn fetch(&self, url: &Url) -> Result<Vec<u8>, Error> {
+        if self.offline.is_some() {
+            return Err(Error::from(AurTErrors::Offline {
+                url: url.to_string(),
+            }));
+        }
+
+        let mut attempt = 0;
+        loop {
+            self.wait(self.limiter.reserve())?;
+            debug!("GET {}", url);
+            let started = Instant::now();
+            let failure = match self.fetch_once(url) {
+                Ok(response) => match response.code {
+                    200 => {
+                        debug!(
+                            "{} bytes from {} in {:?}",
+                            response.body.len(),
+                            url,
+                            started.elapsed()
+                        );
+                        return Ok(response.body);
+                    }
+                    429 => Transient::RateLimited(response.retry_after),
+                    code if code >= 500 => Transient::Server(code),
+                    code => {
+                        return Err(Error::from(AurTErrors::HttpStatus {
+                            code,
+                            url: url.to_string(),
+                        }))
+                    }
+                },
+                Err(ref e) if is_transient(e) => Transient::Connection(e.to_string()),
+                Err(e) => return Err(Error::from(e)),
+            };
+
+            // Honour Retry-After, unless it asks for more patience than
+            // we have, like when a daily limit was hit
+            let delay = match failure {
+                Transient::RateLimited(Some(delay)) => delay,
+                _ => self.retry.backoff(attempt),
+            };
+
+            attempt += 1;
+            if attempt > self.retry.max_retries || delay > self.retry.max_delay {
+                return Err(Error::from(exhausted(failure, url, attempt)));
+            }
+            debug!("retrying {} in {:?}, attempt {}", url, delay, attempt + 1);
+            self.wait(delay)?;
+        }
+    }
+
+    /// Perform a single request without any retries
+    fn fetch_once(&self, url: &Url) -> Result<Response, ::curl::Error> {
+        let mut body = Vec::new();
+        let mut retry_after = None;
+        let mut easy = Easy::new();
+        easy.url(url.as_str())?;
+        easy.useragent(concat!("cower-rs/", env!("CARGO_PKG_VERSION")))?;
+        easy.connect_timeout(Duration::from_secs(self.timeout))?;
+        easy.follow_location(true)?;
+        {
+            let mut transfer = easy.transfer();
+            transfer.write_function(|chunk| {
+                body.extend_from_slice(chunk);
+                Ok(chunk.len())
+            })?;
+            transfer.header_function(|header| {
+                let header = String::from_utf8_lossy(header);
+                let mut parts = header.splitn(2, ':');
+                if parts.next().unwrap_or("").eq_ignore_ascii_case("retry-after") {
+                    retry_after = parts.next().and_then(parse_retry_after);
+                }
+                true
+            })?;
+            transfer.perform()?;
+        }
+
+        Ok(Response {
+            code: easy.response_code()?,
+            body,
+            retry_after,
+        })
+    }
+
+    /// Run a single RPC query and parse the packages it returns.
+    ///
+    /// In offline mode every query is answered from the metadata dump.
+    /// Otherwise search results are cached by URL and info results are
+    /// cached per package, see `cached_info`.
+    pub fn query(&self, url: &Url) -> Result<Vec<AurPkg>, Error> {
+        if let Some(ref db) = self.offline {
+            debug!("answering {} from the metadata dump", url);
+            return db.query(url);
+        }
+
+        let is_info = url.query_pairs().any(|(k, v)| k == "type" && v == "info");
+        if let (false, Some(cache)) = (is_info, self.cache.as_ref()) {
+            if let Some(json) = cache.get(url) {
+                debug!("cache hit for {}", url);
+                return parse_response(url, &json);
+            }
+        }
+
+        let json = String::from_utf8(self.fetch(url)?).map_err(|e| invalid_response(url, &e))?;
+        let pkgs = parse_response(url, &json)?;
+
+        // The cache is only an optimisation, failing to write it must
+        // not fail the query
+        if let Some(ref cache) = self.cache {
+            let _ = if is_info {
+                self.cache_packages(cache, &json)
+            } else {
+                cache.put(url, &json)
+            };
+        }
+        Ok(pkgs)
+    }
+
+    /// Split info targets into the packages the cache can answer for and
+    /// the names that still have to be fetched
+    pub fn cached_info<'a>(&self, args: &[&'a str]) -> Result<(Vec<AurPkg>, Vec<&'a str>), Error> {
+        let cache = match self.cache {
+            Some(ref cache) => cache,
+            None => return Ok((Vec::new(), args.to_vec())),
+        };
+
+        let mut pkgs = Vec::new();
+        let mut misses = Vec::new();
+        for arg in args {
+            let url = self.aur_build_rpc_info_url(&[arg])?;
+            match cache.get(&url).and_then(|json| serde_json::from_str(&json).ok()) {
+                Some(pkg) => pkgs.push(pkg),
+                None => misses.push(*arg),
+            }
+        }
+        debug!("{} of {} packages found in the cache", pkgs.len(), args.len());
+        Ok((pkgs, misses))
+    }
+
+    /// Store every package of an info response as if it had been
+    /// queried on its own
+    fn cache_packages(&self, cache: &Cache, json: &str) -> Result<(), Error> {
+        let response: serde_json::Value = serde_json::from_str(json)?;
+        if let Some(results) = response["results"].as_array() {
+            for pkg in results {
+                if let Some(name) = pkg["Name"].as_str() {
+                    cache.put(&self.aur_build_rpc_info_url(&[name])?, &pkg.to_string())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The names of every package in the AUR, from the metadata dump in
+    /// offline mode and from `packages.gz` otherwise
+    pub fn package_names(&self) -> Result<Vec<String>, Error> {
+        if let Some(ref db) = self.offline {
+            return Ok(db.names().map(|name| name.to_string()).collect());
+        }
+
+        let url = self.aur_build_download_url(PACKAGES_URLPATH)?;
+        if let Some(list) = self.cache.as_ref().and_then(|cache| cache.get(&url)) {
+            debug!("cache hit for {}", url);
+            return Ok(parse_package_list(&list));
+        }
+
+        let mut list = String::new();
+        GzDecoder::new(&self.fetch(&url)?[..]).read_to_string(&mut list)?;
+        if let Some(ref cache) = self.cache {
+            let _ = cache.put(&url, &list);
+        }
+        Ok(parse_package_list(&list))
+    }
+
+    /// The package names available without going to the network: the
+    /// metadata dump in offline mode, otherwise the last `packages.gz`
+    /// fetched, however old
+    pub fn cached_package_names(&self) -> Option<Vec<String>> {
+        if let Some(ref db) = self.offline {
+            return Some(db.names().map(|name| name.to_string()).collect());
+        }
+
+        let url = self.aur_build_download_url(PACKAGES_URLPATH).ok()?;
+        let list = self.cache.as_ref()?.get_stale(&url)?;
+        Some(parse_package_list(&list))
+    }
+
+    /// Drop any cached info for `name`
+    pub fn invalidate(&self, name: &str) -> Result<(), Error> {
+        match self.cache {
+            Some(ref cache) => cache.invalidate(&self.aur_build_rpc_info_url(&[name])?),
+            None => Ok(()),
+        }
+    }
+
+    pub fn search(&self, rpc_by: RpcBy, arg: &str) -> Result<Vec<AurPkg>, Error> {
+        self.query(&self.aur_build_rpc_search_url(rpc_by, arg)?)
+    }
+}
+
+/// The packages in an RPC response, which must be valid JSON. An error
+/// reported by the RPC itself is passed on as is.
+fn parse_response(url: &Url, json: &str) -> Result<Vec<AurPkg>, Error> {
+    aur_packages_from_json(json).map_err(|e| match e.downcast::<serde_json::Error>() {
+        Ok(e) => invalid_response(url, &e),
+        Err(e) => e,
+    })
+}
+
+fn invalid_response<E: ::std::fmt::Display>(url: &Url, e: &E) -> Error {
+    Error::from(AurTErrors::InvalidResponse {
+        url: url.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+/// Failures that might go away if we just try again
+fn is_transient(e: &::curl::Error) -> bool {
+    e.is_couldnt_connect()
+        || e.is_couldnt_resolve_host()
+        || e.is_operation_timedout()
+        || e.is_send_error()
+        || e.is_recv_error()
+        || e.is_got_nothing()
+        || e.is_partial_file()
+}
+
+/// The error reported once a transient failure has used up its retries
+fn exhausted(failure: Transient, url: &Url, attempts: u32) -> AurTErrors {
+    let url = url.to_string();
+    match failure {
+        Transient::RateLimited(_) => AurTErrors::RateLimited { url, attempts },
+        Transient::Server(code) => AurTErrors::ServerError {
+            code,
+            url,
+            attempts,
+        },
+        Transient::Connection(reason) => AurTErrors::ConnectionFailed {
+            url,
+            attempts,
+            reason,
+        },
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use tempdir::TempDir;
+
+    const EMPTY_RESULTS: &str = r#"{"version":5,"type":"multiinfo","resultcount":0,"results":[]}"#;
+
+    /// Serve one scripted response per connection, then stop listening.
+    /// Returns the address to point an AurT at and the number of
+    /// requests served so far.
+    fn stub_server(responses: Vec<String>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let served = Arc::new(AtomicUsize::new(0));
+
+        let counter = Arc::clone(&served);
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+
+                // Read the whole request head before answering
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+
+                // Count before answering, the client may check right after
+                counter.fetch_add(1, Ordering::SeqCst);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        (addr, served)
+    }
+
+    fn response(status: &str, headers: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}",
+            status,
+            body.len(),
+            headers,
+            body
+        )
+    }
+
+    fn stub_aur(addr: &str, max_retries: u32) -> AurT {
+        let mut aur = AurT::new("http", addr);
+        aur.set_retry_policy(RetryPolicy {
+            max_retries,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_secs(1),
+        });
+        aur
+    }
+
+    #[test]
+    fn test_fetch_retries_transient_failures() {
+        let (addr, served) = stub_server(vec![
+            response("503 Service Unavailable", "", ""),
+            response("429 Too Many Requests", "Retry-After: 0\r\n", ""),
+            response("200 OK", "", EMPTY_RESULTS),
+        ]);
+
+        let aur = stub_aur(&addr, 3);
+        let url = aur.aur_build_rpc_info_url(&["cower"]).unwrap();
+        let pkgs = aur.query(&url).unwrap();
+        assert!(pkgs.is_empty());
+        assert_eq!(served.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_fetch_gives_up_after_retries() {
+        let (addr, served) = stub_server(vec![
+            response("500 Internal Server Error", "", ""),
+            response("502 Bad Gateway", "", ""),
+            response("500 Internal Server Error", "", ""),
+        ]);
+
+        let aur = stub_aur(&addr, 2);
+        let url = aur.aur_build_rpc_info_url(&["cower"]).unwrap();
+        let err = aur.fetch(&url).err().unwrap();
+        match err.downcast_ref::<AurTErrors>() {
+            Some(AurTErrors::ServerError { code, attempts, .. }) => {
+                assert_eq!(*code, 500);
+                assert_eq!(*attempts, 3);
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert_eq!(served.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_cancel_interrupts_retry_delay() {
+        let (addr, served) = stub_server(vec![response(
+            "429 Too Many Requests",
+            "Retry-After: 30\r\n",
+            "",
+        )]);
+
+        let mut aur = AurT::new("http", &addr);
+        aur.set_retry_policy(RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_secs(60),
+        });
+        let cancelled = Arc::new(AtomicBool::new(false));
+        aur.set_cancel_handle(Arc::clone(&cancelled));
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            cancelled.store(true, Ordering::SeqCst);
+        });
+
+        let started = Instant::now();
+        let url = aur.aur_build_rpc_info_url(&["cower"]).unwrap();
+        let err = aur.fetch(&url).err().unwrap();
+        match err.downcast_ref::<PoolError>() {
+            Some(PoolError::Cancelled) => (),
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(served.load(Ordering::SeqCst), 1);
+        canceller.join().unwrap();
+    }
+
+    #[test]
+    fn test_query_rejects_invalid_json() {
+        let (addr, _) = stub_server(vec![response("200 OK", "", "<html>maintenance</html>")]);
+
+        let aur = stub_aur(&addr, 0);
+        let url = aur.aur_build_rpc_info_url(&["cower"]).unwrap();
+        let err = aur.query(&url).err().unwrap();
+        match err.downcast_ref::<AurTErrors>() {
+            Some(AurTErrors::InvalidResponse { .. }) => (),
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_fetch_does_not_retry_client_errors() {
+        let (addr, served) = stub_server(vec![response("404 Not Found", "", "")]);
+
+        let aur = stub_aur(&addr, 3);
+        let url = aur.aur_build_rpc_info_url(&["cower"]).unwrap();
+        let err = aur.fetch(&url).err().unwrap();
+        match err.downcast_ref::<AurTErrors>() {
+            Some(AurTErrors::HttpStatus { code: 404, .. }) => (),
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert_eq!(served.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_fetch_gives_up_on_long_retry_after() {
+        let (addr, served) = stub_server(vec![response(
+            "429 Too Many Requests",
+            "Retry-After: 86400\r\n",
+            "",
+        )]);
+
+        let aur = stub_aur(&addr, 3);
+        let url = aur.aur_build_rpc_info_url(&["cower"]).unwrap();
+        let err = aur.fetch(&url).err().unwrap();
+        match err.downcast_ref::<AurTErrors>() {
+            Some(AurTErrors::RateLimited { attempts: 1, .. }) => (),
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert_eq!(served.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_fetch_connection_failure() {
+        // Grab a free port, then stop listening on it
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+
+        let aur = stub_aur(&addr, 1);
+        let url = aur.aur_build_rpc_info_url(&["cower"]).unwrap();
+        let err = aur.fetch(&url).err().unwrap();
+        match err.downcast_ref::<AurTErrors>() {
+            Some(AurTErrors::ConnectionFailed { attempts: 2, .. }) => (),
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_info_partially_served_from_cache() {
+        let json = r#"{"version":5,"type":"multiinfo","resultcount":1,"results":[{
+            "ID":1,"Name":"cower","PackageBaseID":1,"PackageBase":"cower",
+            "Version":"17-2","Description":"","URL":"","NumVotes":0,
+            "Popularity":0,"OutOfDate":null,"Maintainer":"falconindy",
+            "FirstSubmitted":0,"LastModified":0,"URLPath":""}]}"#;
+        let (addr, served) = stub_server(vec![
+            response("200 OK", "", json),
+            response("200 OK", "", json),
+        ]);
+
+        let dir = TempDir::new("cower_test_aur_cache").unwrap();
+        let mut aur = stub_aur(&addr, 0);
+        aur.set_cache(Cache::new(dir.path().to_path_buf(), Duration::from_secs(60)));
+
+        let (cached, misses) = aur.cached_info(&["cower", "auracle"]).unwrap();
+        assert!(cached.is_empty());
+        assert_eq!(misses, vec!["cower", "auracle"]);
+
+        let url = aur.aur_build_rpc_info_url(&misses).unwrap();
+        assert_eq!(aur.query(&url).unwrap().len(), 1);
+
+        // Only the package that was actually returned gets cached
+        let (cached, misses) = aur.cached_info(&["cower", "auracle"]).unwrap();
+        assert_eq!(cached[0].name(), "cower");
+        assert_eq!(misses, vec!["auracle"]);
+
+        aur.invalidate("cower").unwrap();
+        let (cached, _) = aur.cached_info(&["cower"]).unwrap();
+        assert!(cached.is_empty());
+
+        // Searches are cached by URL
+        let url = aur.aur_build_rpc_search_url(RpcBy::SearchByName, "cow").unwrap();
+        aur.query(&url).unwrap();
+        aur.query(&url).unwrap();
+        assert_eq!(served.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_git_url() {
+        let aur = AurT::new("https", "aur.archlinux.org");
+        assert_eq!(
+            aur.aur_build_git_url("cower").unwrap().as_str(),
+            "https://aur.archlinux.org/cower.git"
+        );
+    }
 
     #[test]
     fn test_new_aur_t() {
@@ -133,6 +761,41 @@ mod tests {
         assert!(url.is_err());
     }
 
+    #[test]
+    fn test_rpc_info_urls_are_batched() {
+        let aur = AurT::new("https", "aur.archlinux.com");
+        let names: Vec<String> = (0..1000).map(|i| format!("package-{}", i)).collect();
+        let args: Vec<&str> = names.iter().map(|s| s.as_str()).collect();
+
+        let urls = aur.aur_build_rpc_info_urls(&args).unwrap();
+        assert!(urls.len() > 1);
+        assert!(urls.iter().all(|url| url.as_str().len() <= MAX_URL_LEN));
+
+        // Every argument is queried exactly once, in order
+        let queried: Vec<String> = urls
+            .iter()
+            .flat_map(|url| {
+                url.query_pairs()
+                    .filter(|(k, _)| k == "arg[]")
+                    .map(|(_, v)| v.into_owned())
+                    .collect::<Vec<String>>()
+            }).collect();
+        assert_eq!(queried, names);
+
+        assert!(aur.aur_build_rpc_info_urls(&[]).is_err());
+    }
+
+    #[test]
+    fn test_download_url() {
+        let url = AurT::new("https", "aur.archlinux.com")
+            .aur_build_download_url("/cgit/aur.git/snapshot/cower.tar.gz")
+            .unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://aur.archlinux.com/cgit/aur.git/snapshot/cower.tar.gz"
+        );
+    }
+
     #[test]
     fn test_rpc_search_url() {
         let url = AurT::new("https", "aur.archlinux.com")
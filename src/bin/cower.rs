@@ -3,50 +3,384 @@
 extern crate clap;
 #[macro_use]
 extern crate failure;
+extern crate ctrlc;
+extern crate curl;
 extern crate dirs;
+extern crate isatty;
+#[macro_use]
 extern crate log;
 extern crate regex;
 extern crate stderrlog;
 extern crate tempdir;
+extern crate url;
 
 extern crate cower_rs;
 
-use clap::{App, Arg, ArgGroup, ArgMatches};
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, Shell, SubCommand};
 use cower_rs::aur::*;
+use cower_rs::cache::Cache;
 use cower_rs::config::*;
+use cower_rs::conflicts::{find_conflicts, Conflict};
+use cower_rs::deps::{resolve_with, DepsError, Graph, GraphFormat, Providers};
+use cower_rs::download::{stage_package, DownloadError, DownloadMethod, Staged};
+use cower_rs::format::{format_date, format_pkg, ESCAPES, SPECIFIERS};
+use cower_rs::fuzzy::{fuzzy_search, suggest};
+use cower_rs::ignore::{IgnoreError, IgnoreList};
+use cower_rs::index::IndexError;
+use cower_rs::man::{bold, italic, ManPage};
+use cower_rs::manifest::{Manifest, ManifestError, Record};
+use cower_rs::metadata::{MetadataDb, MetadataError, DUMP_URLPATH};
 use cower_rs::package::*;
+use cower_rs::pacman::{Pacman, PacmanConf, PacmanError, PACMAN_CONF};
+use cower_rs::pool::{partition_results, PoolError, TaskPool};
+use cower_rs::transport::RetryPolicy;
+use cower_rs::update::{self, Status};
 use cower_rs::*;
 use failure::Error;
+use isatty::{stdin_isatty, stdout_isatty};
 use log::Level;
 use regex::Regex;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::path::PathBuf;
-use std::{env, str};
+use std::path::{Path, PathBuf};
+use std::sync::atomic;
+use std::time::{Duration, Instant};
+use std::{env, process, str};
 
 #[derive(Debug, Fail)]
 pub enum CowerError {
-    #[fail(display = "Invalid Operation")]
-    InvalidOperation,
+    #[fail(display = "{} can't be combined with {}", first, second)]
+    InvalidOperation { first: String, second: String },
+    #[fail(display = "{} only works with {}", option, operations)]
+    OptionNotAllowed { option: String, operations: String },
     #[fail(display = "Invalid Regex: {}", regex)]
     InvalidRegexes { regex: String },
+    #[fail(
+        display = "{} has no literal text of at least 2 characters to search the AUR for",
+        regex
+    )]
+    NoSearchTerm { regex: String },
+    #[fail(display = "no results found for {}", target)]
+    NotFound { target: String },
+    #[fail(display = "no results found for {}, did you mean {}?", target, suggestions)]
+    NotFoundSuggest { target: String, suggestions: String },
+    #[fail(display = "Unable to determine where to keep the package database")]
+    NoDataDir,
+    #[fail(display = "dependency {} of {} was not found", name, needed_by)]
+    MissingDependency { name: String, needed_by: String },
+    #[fail(display = "refusing to download {} conflicting package(s)", count)]
+    Conflicts { count: usize },
+    #[fail(display = "{} is not an installed foreign package", name)]
+    NotForeign { name: String },
+}
+
+/// How many names a fuzzy search shows per target
+const FUZZY_RESULTS: usize = 10;
+
+/// How many names to suggest for a target that wasn't found
+const SUGGESTIONS: usize = 3;
+
+/// Site wide defaults, overridden by the user's config file
+const SYSTEM_CONFIG: &str = "/etc/cower.conf";
+
+/// Everything worked
+const EXIT_SUCCESS: i32 = 0;
+/// Some targets weren't found or couldn't be handled
+const EXIT_NOT_FOUND: i32 = 1;
+/// Bad arguments or an invalid combination of operations
+const EXIT_USAGE: i32 = 2;
+/// The AUR couldn't be reached or gave a bad answer
+const EXIT_NETWORK: i32 = 3;
+/// A config file, environment variable or option value is invalid
+const EXIT_CONFIG: i32 = 4;
+/// Reading or writing local files failed
+const EXIT_FILESYSTEM: i32 = 5;
+/// Interrupted by a second Ctrl-C
+const EXIT_INTERRUPTED: i32 = 130;
+
+const EXIT_STATUS_HELP: &str = "EXIT STATUS:
+    0    success
+    1    some targets were not found or couldn't be handled
+    2    usage error
+    3    network error
+    4    configuration error
+    5    filesystem error
+    130  interrupted by Ctrl-C";
+
+fn main() {
+    let version = get_version_string().unwrap_or_else(|_| String::from(crate_version!()));
+    let matches = match build_cli(&version).get_matches_safe() {
+        Ok(matches) => matches,
+        // --help and --version end up here too
+        Err(ref e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            eprintln!("{}", e.message);
+            process::exit(EXIT_USAGE);
+        }
+    };
+
+    let errors = match run(&matches) {
+        Ok(errors) => errors,
+        Err(e) => vec![e],
+    };
+
+    // Failed targets don't stop the others, report them all at the end
+    let debug = matches.is_present("debug");
+    for error in &errors {
+        report_error(error, debug);
+    }
+    process::exit(exit_status(&errors));
+}
+
+/// Print `error` on a single line, and with `debug` what caused it
+fn report_error(error: &Error, debug: bool) {
+    eprintln!("error: {}", error);
+    if debug {
+        for cause in error.iter_causes() {
+            eprintln!("  caused by: {}", cause);
+        }
+    }
+}
+
+/// The exit status for a run that ended with `errors`, the most severe
+/// one winning
+fn exit_status(errors: &[Error]) -> i32 {
+    errors.iter().map(exit_code).max().unwrap_or(EXIT_SUCCESS)
+}
+
+/// The exit status for `error`, going by the first cause that has one
+fn exit_code(error: &Error) -> i32 {
+    error
+        .iter_chain()
+        .filter_map(failure_exit_code)
+        .next()
+        .unwrap_or(EXIT_NOT_FOUND)
 }
 
-fn main() -> Result<(), Error> {
+fn failure_exit_code(cause: &dyn failure::Fail) -> Option<i32> {
+    if let Some(e) = cause.downcast_ref::<CowerError>() {
+        return Some(match *e {
+            CowerError::InvalidOperation { .. }
+            | CowerError::OptionNotAllowed { .. }
+            | CowerError::InvalidRegexes { .. }
+            | CowerError::NoSearchTerm { .. } => EXIT_USAGE,
+            CowerError::NotFound { .. }
+            | CowerError::NotFoundSuggest { .. }
+            | CowerError::MissingDependency { .. }
+            | CowerError::Conflicts { .. }
+            | CowerError::NotForeign { .. } => EXIT_NOT_FOUND,
+            CowerError::NoDataDir => EXIT_FILESYSTEM,
+        });
+    }
+    if let Some(e) = cause.downcast_ref::<ConfigError>() {
+        return Some(match *e {
+            ConfigError::TargetDirNotDir { .. }
+            | ConfigError::TargetDirNotWritable { .. }
+            | ConfigError::TargetDirCreateFailed { .. } => EXIT_FILESYSTEM,
+            ConfigError::UndefinedPathVariable { .. }
+            | ConfigError::UnterminatedPathVariable { .. }
+            | ConfigError::InvalidMaxThreadArg { .. }
+            | ConfigError::InvalidConnectTimeoutArg { .. }
+            | ConfigError::InvalidMaxRetriesArg { .. }
+            | ConfigError::InvalidRateLimitArg { .. }
+            | ConfigError::InvalidCacheTTLArg { .. }
+            | ConfigError::InvalidDownloadMethodArg { .. }
+            | ConfigError::InvalidProviderArg { .. }
+            | ConfigError::InvalidColorArg { .. }
+            | ConfigError::InvalidSearchByArg { .. }
+            | ConfigError::InvalidSortByArg { .. }
+            | ConfigError::ParseError { .. }
+            | ConfigError::EnvironmentError { .. } => EXIT_CONFIG,
+        });
+    }
+    if let Some(e) = cause.downcast_ref::<AurTErrors>() {
+        return Some(match *e {
+            AurTErrors::EmptyArgumentsVector => EXIT_USAGE,
+            AurTErrors::RpcError { .. }
+            | AurTErrors::HttpStatus { .. }
+            | AurTErrors::RateLimited { .. }
+            | AurTErrors::ServerError { .. }
+            | AurTErrors::ConnectionFailed { .. }
+            | AurTErrors::Offline { .. }
+            | AurTErrors::InvalidResponse { .. } => EXIT_NETWORK,
+        });
+    }
+    if let Some(e) = cause.downcast_ref::<DepsError>() {
+        return Some(match *e {
+            DepsError::InvalidGraphFormat { .. } => EXIT_USAGE,
+            DepsError::BadProviderRule { .. } => EXIT_CONFIG,
+            DepsError::Cycle { .. } => EXIT_NOT_FOUND,
+        });
+    }
+    if let Some(e) = cause.downcast_ref::<MetadataError>() {
+        return Some(match *e {
+            MetadataError::UnsupportedQuery { .. } => EXIT_USAGE,
+            MetadataError::NotSynced { .. } | MetadataError::InvalidDump { .. } => EXIT_FILESYSTEM,
+        });
+    }
+    if let Some(e) = cause.downcast_ref::<PoolError>() {
+        return Some(match *e {
+            PoolError::Cancelled => EXIT_INTERRUPTED,
+            PoolError::WorkerPanicked => EXIT_NOT_FOUND,
+        });
+    }
+    if cause.downcast_ref::<IgnoreError>().is_some()
+        || cause.downcast_ref::<url::ParseError>().is_some()
+    {
+        return Some(EXIT_CONFIG);
+    }
+    if cause.downcast_ref::<curl::Error>().is_some() {
+        return Some(EXIT_NETWORK);
+    }
+    if let Some(e) = cause.downcast_ref::<DownloadError>() {
+        return Some(match *e {
+            DownloadError::GitRemoteFailed { .. } => EXIT_NETWORK,
+            DownloadError::AlreadyExists { .. }
+            | DownloadError::LocalChanges { .. }
+            | DownloadError::GitFailed { .. }
+            | DownloadError::UnsafeEntry { .. }
+            | DownloadError::TooLarge { .. }
+            | DownloadError::MissingPackage { .. } => EXIT_FILESYSTEM,
+        });
+    }
+    if cause.downcast_ref::<ManifestError>().is_some()
+        || cause.downcast_ref::<IndexError>().is_some()
+        || cause.downcast_ref::<PacmanError>().is_some()
+        || cause.downcast_ref::<std::io::Error>().is_some()
+    {
+        return Some(EXIT_FILESYSTEM);
+    }
+    None
+}
+
+/// Send log messages from cower and its library to stderr, at the level
+/// picked with --debug or --verbose
+fn init_logging(config: &Config<AurPkg>) -> Result<(), Error> {
+    // Timestamps only help when there's enough output to time
+    let timestamp = if config.loglevel >= Level::Debug {
+        stderrlog::Timestamp::Millisecond
+    } else {
+        stderrlog::Timestamp::Off
+    };
+
+    stderrlog::new()
+        .module(module_path!())
+        .module("cower_rs")
+        .verbosity(config.loglevel as usize - 1)
+        .timestamp(timestamp)
+        .init()?;
+    Ok(())
+}
+
+/// Run whichever operation was asked for. Errors that only affect some
+/// targets are returned, the rest end the run early.
+fn run(matches: &ArgMatches) -> Result<Vec<Error>, Error> {
+    // Shells load completions at startup, so don't let a broken config
+    // file get in the way
+    if let Some(matches) = matches.subcommand_matches("completions") {
+        print_completions(value_t!(matches, "shell", Shell)?)?;
+        return Ok(Vec::new());
+    }
+    if let Some(matches) = matches.subcommand_matches("manpage") {
+        let roff = match matches.value_of("page") {
+            Some("cower.conf") => config_man_page(),
+            _ => cower_man_page(),
+        };
+        std::io::stdout().write_all(roff.as_bytes())?;
+        return Ok(Vec::new());
+    }
+
     let mut config = Config::new(package::sort_name);
 
-    // Check for a config file
+    // Apply each configuration layer in turn, later ones win
+    let mut loaded = Vec::new();
+    let system = PathBuf::from(SYSTEM_CONFIG);
+    if system.is_file() {
+        config.layer = ConfigLayer::System;
+        config.parse_config_files(&system)?;
+        loaded.push((ConfigLayer::System, system));
+    }
+
     if let Some(path) = get_config_path() {
-        // Parse config file
+        config.layer = ConfigLayer::User;
         config.parse_config_files(&path)?;
+        loaded.push((ConfigLayer::User, path));
     }
 
+    if let Some(path) = matches.value_of("config") {
+        // Unlike the implicit files, an explicit one has to exist
+        let path = PathBuf::from(path);
+        config.layer = ConfigLayer::Project;
+        config.parse_config_files(&path)?;
+        loaded.push((ConfigLayer::Project, path));
+    }
+
+    config.layer = ConfigLayer::Environment;
+    config.parse_environment(env::vars())?;
+
     // Handle command line arguments
-    handle_command_line_args(&mut config)?;
+    config.layer = ConfigLayer::CommandLine;
+    handle_command_line_args(&mut config, matches)?;
+    init_logging(&config)?;
+    for &(layer, ref path) in &loaded {
+        debug!("read {:?} config from {}", layer, path.display());
+    }
+
+    if matches.is_present("print-config") {
+        print_config(&config, &loaded);
+        return Ok(Vec::new());
+    }
 
     // Get an Aur object
-    let _aur = AurT::new("https", &config.aur_domain);
+    let mut aur = AurT::new("https", &config.aur_domain);
+    aur.set_timeout(config.timeout);
+    aur.set_rate_limit(config.ratelimit);
+    aur.set_retry_policy(RetryPolicy {
+        max_retries: config.retries,
+        ..RetryPolicy::default()
+    });
+
+    if let Some(matches) = matches.subcommand_matches("sync-db") {
+        return sync_db(&aur, matches.value_of("file")).map(|_| Vec::new());
+    }
+
+    if config.offline {
+        let dir = MetadataDb::default_dir().ok_or(CowerError::NoDataDir)?;
+        aur.set_offline(MetadataDb::load(&dir)?);
+    } else if !config.no_cache {
+        if let Some(dir) = Cache::default_dir() {
+            let mut cache = Cache::new(dir, Duration::from_secs(config.cache_ttl));
+            cache.set_refresh(config.refresh);
+            aur.set_cache(cache);
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("complete-names") {
+        return complete_names(&aur, matches.value_of("prefix").unwrap_or("")).map(|_| Vec::new());
+    }
+
+    // Ctrl-C stops handing out new work, running tasks still finish. A
+    // second one gives up on those too, e.g. when stuck at a prompt.
+    let pool = TaskPool::new(config.maxthreads);
+    let cancel = pool.cancel_handle();
+    aur.set_cancel_handle(pool.cancel_handle());
+    ctrlc::set_handler(move || {
+        if cancel.swap(true, atomic::Ordering::SeqCst) {
+            process::exit(EXIT_INTERRUPTED);
+        }
+    })?;
+
+    // Catch bad ignore patterns before doing any work
+    let ignored = IgnoreList::new(&config.ignore_pkgs, &config.ignore_groups)?;
+
+    // Likewise make sure downloads have somewhere to go
+    if config.opmask.contains(OpMask::DOWNLOAD) {
+        config.prepare_working_dir()?;
+    } else if config.working_dir.as_os_str().is_empty() {
+        config.working_dir = env::current_dir()?;
+    }
 
     if config.srcinfo {
         let files: Vec<PathBuf> = config.args.iter().map(PathBuf::from).collect();
@@ -64,11 +398,1103 @@ fn main() -> Result<(), Error> {
         config.args.append(&mut targets);
     }
 
-    unimplemented!();
-    // Everything worked out
+    let started = Instant::now();
+    let errors = if config.opmask.contains(OpMask::SEARCH) && config.fuzzy {
+        cower_fuzzy_search(&config, &aur, &pool)?
+    } else if config.opmask.contains(OpMask::SEARCH) {
+        cower_search(&config, &aur, &pool)
+    } else if config.opmask.contains(OpMask::INFO) {
+        cower_info(&config, &aur, &pool)?
+    } else if config.opmask.contains(OpMask::ORDER) {
+        cower_build_order(&config, &aur, &pool)?
+    } else if config.opmask.contains(OpMask::GRAPH) {
+        cower_graph(&config, &aur, &pool)?
+    } else if config.opmask.contains(OpMask::STATUS) {
+        cower_status(&config, &aur, &pool)?
+    } else if config.opmask.contains(OpMask::UPDATE) {
+        cower_update(&config, &aur, &pool, &ignored)?
+    } else {
+        cower_download(&config, &aur, &pool)?
+    };
+    debug!("finished in {:?}", started.elapsed());
+    Ok(errors)
+}
+
+/// Install the AUR metadata dump used by `--offline`, either downloaded
+/// or imported from `file`
+fn sync_db(aur: &AurT, file: Option<&str>) -> Result<(), Error> {
+    let dump = match file {
+        Some(file) => {
+            let mut dump = Vec::new();
+            File::open(file)?.read_to_end(&mut dump)?;
+            dump
+        }
+        None => aur.fetch(&aur.aur_build_download_url(DUMP_URLPATH)?)?,
+    };
+
+    let dir = MetadataDb::default_dir().ok_or(CowerError::NoDataDir)?;
+    let count = MetadataDb::import(&dump, &dir)?;
+    println!(":: synced {} packages to {}", count, dir.display());
+    Ok(())
+}
+
+/// Print the completion script for `shell`, generated from the command
+/// line definition
+fn print_completions(shell: Shell) -> Result<(), Error> {
+    let mut stdout = std::io::stdout();
+    build_cli(crate_version!()).gen_completions_to("cower", shell, &mut stdout);
+    stdout.write_all(package_names_hook(shell).as_bytes())?;
+    Ok(())
+}
+
+/// Completion that offers package names from `cower complete-names` as
+/// targets, to follow the script clap generates. It stays out of the way
+/// of switches, their values and subcommands.
+fn package_names_hook(shell: Shell) -> String {
+    let mut takes_value = Vec::new();
+    for switch in operation_switches().iter().chain(&option_switches()) {
+        if switch.value.is_some() {
+            if let Some(short) = switch.short {
+                takes_value.push(format!("-{}", short));
+            }
+            takes_value.push(format!("--{}", switch.name));
+        }
+    }
+    let takes_value = takes_value.join("|");
+    let commands: Vec<&str> = COMMANDS.iter().map(|c| c.0).collect();
+    let commands = commands.join("|");
+
+    match shell {
+        Shell::Bash => format!(
+            r#"
+_cower_packages() {{
+    _cower "$@"
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" word
+    [[ ${{cur}} == -* ]] && return
+    case "${{COMP_WORDS[COMP_CWORD-1]}}" in
+        {}) return ;;
+    esac
+    for word in "${{COMP_WORDS[@]:1:COMP_CWORD-1}}"; do
+        case "${{word}}" in
+            {}) return ;;
+        esac
+    done
+    COMPREPLY+=( $(cower complete-names "${{cur}}" 2>/dev/null) )
+}}
+
+complete -F _cower_packages -o bashdefault -o default cower
+"#,
+            takes_value, commands
+        ),
+        // The script above is the body of an autoloaded function that
+        // only runs once, so this takes over from then on
+        Shell::Zsh => format!(
+            r#"
+_cower_names() {{
+    [[ $PREFIX == -* ]] && return
+    case ${{words[CURRENT-1]}} in
+        ({}) return ;;
+    esac
+    (( ${{${{words[1,CURRENT-1]}}[(I)({})]}} )) && return
+    local -a names
+    names=(${{(f)"$(cower complete-names "$PREFIX" 2>/dev/null)"}})
+    compadd -a names
+}}
+
+_cower_packages() {{
+    _cower "$@"
+    _cower_names
+}}
+
+compdef _cower_packages cower
+_cower_names
+"#,
+            takes_value, commands
+        ),
+        Shell::Fish => String::from(
+            "complete -c cower -n \"__fish_use_subcommand\" \\
+    -a \"(cower complete-names (commandline -ct) 2>/dev/null)\"\n",
+        ),
+        _ => String::new(),
+    }
+}
+
+/// cower(1), generated from the same switches as the parser
+fn cower_man_page() -> String {
+    let source = format!("cower-rs {}", crate_version!());
+    let mut page = ManPage::new("cower", 1, &source, crate_description!());
+    page.section("Synopsis");
+    page.markup(&format!(
+        "{} [{}] {} [{}...]",
+        bold("cower"),
+        italic("options"),
+        italic("operation"),
+        italic("targets")
+    ));
+    page.markup(&format!("{} {} [{}]", bold("cower"), italic("command"), italic("args")));
+
+    page.section("Operations");
+    for switch in &operation_switches() {
+        switch.man_item(&mut page);
+    }
+    page.section("Options");
+    for switch in &option_switches() {
+        switch.man_item(&mut page);
+    }
+    // clap adds these, and the help command, itself
+    page.item(&switch_term(Some('h'), Some("help"), None), "Prints help information");
+    page.item(&switch_term(Some('V'), Some("version"), None), "Prints version information");
+
+    page.section("Commands");
+    for &(name, about) in COMMANDS {
+        page.item(&bold(name), about);
+    }
+    page.item(&bold("help"), "Prints this message or the help of the given subcommand(s)");
+
+    page.section("Format strings");
+    page.markup(&format!(
+        "{} takes a string with the following specifiers, each starting with %. \
+         Lists are joined with the {} delimiter.",
+        bold("--format"),
+        bold("--listdelim")
+    ));
+    for &(spec, text) in SPECIFIERS {
+        page.item(&bold(&format!("%{}", spec)), text);
+    }
+    page.paragraph("The following escapes are also understood:");
+    for &(escape, text) in ESCAPES {
+        page.item(&bold(&format!("\\{}", escape)), text);
+    }
+    page.example(&["cower -s --format '%n %v\\t%d' pacman"]);
+
+    page.section("Exit status");
+    for line in EXIT_STATUS_HELP.lines().skip(1) {
+        let mut parts = line.trim().splitn(2, ' ');
+        if let (Some(code), Some(meaning)) = (parts.next(), parts.next()) {
+            page.item(&bold(code), meaning.trim());
+        }
+    }
+
+    page.section("Files");
+    page.item(&bold(SYSTEM_CONFIG), "system wide configuration");
+    page.item(&bold("$XDG_CONFIG_HOME/cower/config"), "user configuration, ~/.config/cower/config by default");
+    page.section("See also");
+    page.markup(&format!("{}(5)", bold("cower.conf")));
+    page.into_roff()
+}
+
+/// cower.conf(5), generated from the keys the config parser knows
+fn config_man_page() -> String {
+    let source = format!("cower-rs {}", crate_version!());
+    let mut page = ManPage::new("cower.conf", 5, &source, "cower configuration file");
+    page.section("Description");
+    page.paragraph(&format!(
+        "cower reads {}, then $XDG_CONFIG_HOME/cower/config (~/.config/cower/config by \
+         default), then any file given with --config. Later files override earlier ones, and \
+         the command line overrides them all.",
+        SYSTEM_CONFIG
+    ));
+    page.paragraph(
+        "Each line sets one key as Key = value. Boolean keys may be given on their own, or \
+         set to true, yes, 1, false, no or 0. Lists are separated by whitespace. Empty lines, \
+         lines starting with # and an [options] header are ignored.",
+    );
+    page.example(&["[options]", "TargetDir = ~/aur", "IgnorePkg = cower-git pacaur", "Strict"]);
+
+    page.section("Options");
+    let defaults = Config::new(package::sort_name);
+    for key in CONFIG_KEYS {
+        match default_value(&defaults, key) {
+            Some(val) => page.item(&bold(key.name), &format!("{} (default: {})", key.help, val)),
+            None => page.item(&bold(key.name), key.help),
+        }
+    }
+
+    page.section("Environment");
+    page.paragraph(
+        "Every key may also be set as a variable named COWER_ followed by the key in upper \
+         case, e.g. COWER_IGNOREPKG. These override the config files.",
+    );
+    page.section("See also");
+    page.markup(&format!("{}(1)", bold("cower")));
+    page.into_roff()
+}
+
+/// The default of `key` as it would be written in a config file, unless
+/// it's off or empty
+fn default_value(defaults: &Config<AurPkg>, key: &ConfigKey) -> Option<String> {
+    defaults
+        .value(key.field)
+        .filter(|val| !["", "false", "\"\""].contains(&val.as_str()))
+        .map(|val| val.to_lowercase())
+}
+
+/// The help of config key `name`, for the option that sets the same thing
+fn key_help(name: &str) -> &'static str {
+    config_key(name).map_or("", |key| key.help)
+}
+
+/// The man page term for a switch, e.g. `-t, --target dir`
+fn switch_term(short: Option<char>, long: Option<&str>, value: Option<&str>) -> String {
+    let mut names = Vec::new();
+    if let Some(short) = short {
+        names.push(bold(&format!("-{}", short)));
+    }
+    if let Some(long) = long {
+        names.push(bold(&format!("--{}", long)));
+    }
+    let mut term = names.join(", ");
+    if let Some(value) = value {
+        term.push(' ');
+        term.push_str(&italic(value));
+    }
+    term
+}
+
+/// Print the package names starting with `prefix` for shell completion.
+/// Only what's already on disk is used, a slow TAB is worse than a
+/// missing suggestion.
+fn complete_names(aur: &AurT, prefix: &str) -> Result<(), Error> {
+    let names = match aur.cached_package_names() {
+        Some(names) => names,
+        None => match MetadataDb::default_dir().and_then(|dir| MetadataDb::load(&dir).ok()) {
+            Some(db) => db.names().map(|name| name.to_string()).collect(),
+            None => return Ok(()),
+        },
+    };
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    for name in names.iter().filter(|name| name.starts_with(prefix)) {
+        writeln!(out, "{}", name)?;
+    }
+    Ok(())
+}
+
+/// Search the AUR for every target, one RPC query per target
+fn cower_search(config: &Config<AurPkg>, aur: &AurT, pool: &TaskPool) -> Vec<Error> {
+    let rpc_by = match config.search_by {
+        SearchBy::Name => RpcBy::SearchByName,
+        SearchBy::NameDesc => RpcBy::SearchByNameDesc,
+        SearchBy::Maintainer => RpcBy::SearchByMaintainer,
+        SearchBy::Keywords => RpcBy::SearchByKeywords,
+    };
+    let use_regex = allow_regex(config);
+    let aur = aur.clone();
+
+    let results = pool.map(config.args.clone(), move |arg| {
+        if !use_regex {
+            return aur.search(rpc_by, &arg);
+        }
+
+        // The RPC doesn't understand regexes, so query with the literal
+        // parts and filter what comes back
+        let regex = Regex::new(&arg)?;
+        let mut pkgs = Vec::new();
+        for term in regex_literals(&arg)? {
+            pkgs.append(&mut aur.search(rpc_by, &term)?);
+        }
+        Ok(pkgs
+            .into_iter()
+            .filter(|pkg| {
+                regex.is_match(pkg.name())
+                    || rpc_by == RpcBy::SearchByNameDesc && regex.is_match(pkg.description())
+            }).collect())
+    });
+
+    let (results, errors) = partition_results(results);
+    let mut pkgs: Vec<AurPkg> = results
+        .into_iter()
+        .flatten()
+        .filter(|pkg| !(config.ignore_ood && pkg.out_of_date().is_some()))
+        .collect();
+
+    // Several targets may have found the same package
+    pkgs.sort_by(|a, b| a.name().cmp(b.name()));
+    pkgs.dedup_by(|a, b| a.name() == b.name());
+
+    sort_packages(config, &mut pkgs);
+    for pkg in &pkgs {
+        print_search_result(config, pkg);
+    }
+    errors
+}
+
+/// Search the names of every AUR package for close matches to each
+/// target, for when the exact spelling isn't known. Results are ranked
+/// by closeness unless a sort order was asked for.
+fn cower_fuzzy_search(
+    config: &Config<AurPkg>,
+    aur: &AurT,
+    pool: &TaskPool,
+) -> Result<Vec<Error>, Error> {
+    let names = aur.package_names()?;
+
+    let mut errors = Vec::new();
+    let mut matches: Vec<String> = Vec::new();
+    for arg in &config.args {
+        let found = fuzzy_search(arg, names.iter().map(|s| s.as_str()), FUZZY_RESULTS);
+        if found.is_empty() {
+            errors.push(Error::from(CowerError::NotFound {
+                target: arg.clone(),
+            }));
+        }
+        for m in found {
+            if !matches.contains(&m.name) {
+                matches.push(m.name);
+            }
+        }
+    }
+    if matches.is_empty() {
+        return Ok(errors);
+    }
+
+    let (mut pkgs, mut failed) = fetch_info(config, aur, pool, &matches)?;
+    errors.append(&mut failed);
+
+    if config.origin("sort_func") == ConfigLayer::Default {
+        pkgs.sort_by_key(|pkg| matches.iter().position(|name| name == pkg.name()));
+    } else {
+        sort_packages(config, &mut pkgs);
+    }
+    for pkg in &pkgs {
+        print_search_result(config, pkg);
+    }
+    Ok(errors)
+}
+
+/// Show detailed information about every target
+fn cower_info(config: &Config<AurPkg>, aur: &AurT, pool: &TaskPool) -> Result<Vec<Error>, Error> {
+    let (mut pkgs, errors) = fetch_info(config, aur, pool, &config.args)?;
+
+    sort_packages(config, &mut pkgs);
+    for pkg in &pkgs {
+        print_info(config, pkg);
+    }
+    Ok(errors)
+}
+
+/// Download and extract the snapshot of every target, skipping those
+/// the manifest says are already current
+fn cower_download(
+    config: &Config<AurPkg>,
+    aur: &AurT,
+    pool: &TaskPool,
+) -> Result<Vec<Error>, Error> {
+    let manifest = Manifest::load(&config.working_dir)?;
+
+    let mut targets = Vec::new();
+    if config.resume {
+        targets.extend_from_slice(manifest.pending());
+        if targets.is_empty() && config.args.is_empty() {
+            eprintln!(":: nothing to resume");
+            return Ok(Vec::new());
+        }
+    }
+    for arg in &config.args {
+        if !targets.contains(arg) {
+            targets.push(arg.clone());
+        }
+    }
+    download_targets(config, aur, pool, manifest, targets)
+}
+
+/// Download `targets` to the working directory, along with their
+/// dependencies under -dd
+fn download_targets(
+    config: &Config<AurPkg>,
+    aur: &AurT,
+    pool: &TaskPool,
+    mut manifest: Manifest,
+    targets: Vec<String>,
+) -> Result<Vec<Error>, Error> {
+    // Remember the batch before starting, so it can be resumed if this
+    // run doesn't get through it
+    manifest.set_pending(targets.clone());
+    manifest.save()?;
+
+    let (mut pkgs, mut errors) = fetch_info(config, aur, pool, &targets)?;
+    manifest.finish(&settled_targets(&targets, &pkgs, &errors));
+    manifest.save()?;
+
+    // Dependency closures and update batches get checked as a whole
+    // before anything is downloaded
+    if (config.getdeps || config.opmask.contains(OpMask::UPDATE)) && !pkgs.is_empty() {
+        let pacman = open_pacman()?;
+        if config.getdeps {
+            let graph = resolve_deps(config, aur, pool, &pacman, pkgs)?;
+            for (name, needed_by) in graph.missing() {
+                errors.push(Error::from(CowerError::MissingDependency {
+                    name: name.to_string(),
+                    needed_by: needed_by.join(", "),
+                }));
+            }
+            pkgs = graph.aur_packages().into_iter().cloned().collect();
+        }
+
+        let set: Vec<&AurPkg> = pkgs.iter().collect();
+        check_conflicts(config, &find_conflicts(&set, &pacman))?;
+    }
+
+    // Split packages share a single snapshot
+    let mut names: HashMap<String, Vec<String>> = HashMap::new();
+    for pkg in &pkgs {
+        names
+            .entry(pkg.pkgbase().to_owned())
+            .or_default()
+            .push(pkg.name().to_owned());
+    }
+    pkgs.sort_by(|a, b| a.pkgbase().cmp(b.pkgbase()));
+    pkgs.dedup_by(|a, b| a.pkgbase() == b.pkgbase());
+
+    if !config.force {
+        let (current, outdated): (Vec<AurPkg>, Vec<AurPkg>) = pkgs
+            .into_iter()
+            .partition(|pkg| manifest.is_current(pkg.pkgbase(), pkg.version()));
+        for pkg in current {
+            if !config.quiet {
+                println!(":: {} {} is up to date", pkg.pkgbase(), pkg.version());
+            }
+            manifest.finish(&names[pkg.pkgbase()]);
+        }
+        manifest.save()?;
+        pkgs = outdated;
+    }
+
+    let aur = aur.clone();
+    let dir = config.working_dir.clone();
+    let force = config.force;
+    let method = config.download_method;
+    let results = pool.map(pkgs, move |pkg| {
+        stage_package(&aur, &pkg, &dir, method, force).map(|staged| (pkg, staged))
+    });
+
+    // Review happens one package at a time, so prompts and diffs don't
+    // interleave
+    let (staged, mut failed) = partition_results(results);
+    errors.append(&mut failed);
+    for (pkg, staged) in staged {
+        match review_and_commit(config, pool, staged) {
+            Ok(Some((pkgbase, path))) => {
+                if config.quiet {
+                    println!("{}", pkgbase);
+                } else {
+                    println!(":: {} downloaded to {}", pkgbase, path.display());
+                }
+                manifest.record(pkg.pkgbase(), pkg.name(), pkg.version());
+            }
+            Ok(None) => (),
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        }
+        manifest.finish(&names[pkg.pkgbase()]);
+        manifest.save()?;
+    }
+    Ok(errors)
+}
+
+/// Check installed foreign packages against the AUR, or just the
+/// targets if there are any. With -d, the updates are downloaded.
+fn cower_update(
+    config: &Config<AurPkg>,
+    aur: &AurT,
+    pool: &TaskPool,
+    ignored: &IgnoreList,
+) -> Result<Vec<Error>, Error> {
+    let pacman = open_pacman()?;
+    let mut foreign = pacman.foreign_packages(&config.ignore_repos);
+
+    let mut errors = Vec::new();
+    if !config.args.is_empty() {
+        for arg in &config.args {
+            if !foreign.iter().any(|f| &f.name == arg) {
+                errors.push(Error::from(CowerError::NotForeign { name: arg.clone() }));
+            }
+        }
+        foreign.retain(|f| config.args.contains(&f.name));
+    }
+
+    let names: Vec<String> = foreign.iter().map(|f| f.name.clone()).collect();
+    let (pkgs, mut failed) = lookup_info(aur, pool, &names)?;
+    if !failed.is_empty() {
+        // A failed query would make its packages look vanished
+        errors.append(&mut failed);
+        return Ok(errors);
+    }
+
+    let report = update::check(foreign, &pkgs, |name| {
+        let mut found = aur.search(RpcBy::SearchByReplaces, name)?;
+        found.append(&mut aur.search(RpcBy::SearchByProvides, name)?);
+        Ok(found)
+    })?;
+
+    for vanished in &report.vanished {
+        let name = &vanished.foreign.name;
+        if vanished.renamed.is_empty() {
+            eprintln!("warning: {} is no longer in the AUR", name);
+        } else {
+            eprintln!(
+                "warning: {} is no longer in the AUR, perhaps renamed to {}",
+                name,
+                vanished.renamed.join(", ")
+            );
+        }
+    }
+    for orphaned in &report.orphaned {
+        eprintln!("warning: {} is orphaned", orphaned.name);
+    }
+    for &(ref flagged, when) in &report.flagged {
+        eprintln!(
+            "warning: {} was flagged out of date on {}",
+            flagged.name,
+            format_date(when)
+        );
+    }
+
+    // Only worth mentioning when asked for more output
+    if config.loglevel == Level::Trace {
+        for checked in &report.packages {
+            let (name, version) = (&checked.foreign.name, &checked.foreign.version);
+            match checked.status {
+                Status::Current => println!(":: {} {} is up to date", name, version),
+                Status::LocalNewer => println!(
+                    ":: {} local ({}) is newer than aur ({})",
+                    name,
+                    version,
+                    checked.pkg.version()
+                ),
+                Status::Vcs if !config.devel => {
+                    println!(":: {} {} is a VCS package, see --devel", name, version)
+                }
+                _ => (),
+            }
+        }
+    }
+
+    let mut targets = Vec::new();
+    for update in report.updates(config.devel) {
+        let (name, pkg) = (&update.foreign.name, &update.pkg);
+        if config.ignore_ood && pkg.out_of_date().is_some() {
+            continue;
+        }
+        if let Some(rule) = ignored.check(name, pkg.groups(), pkg.version()) {
+            if config.loglevel == Level::Trace {
+                eprintln!(
+                    ":: {} ignoring update to {} ({})",
+                    name,
+                    pkg.version(),
+                    rule
+                );
+            }
+            continue;
+        }
+
+        if config.quiet {
+            println!("{}", name);
+        } else if update.status == Status::Vcs {
+            println!(":: {} {} -> latest", name, update.foreign.version);
+        } else {
+            println!(":: {} {} -> {}", name, update.foreign.version, pkg.version());
+        }
+        targets.push(name.clone());
+    }
+
+    if config.opmask.contains(OpMask::DOWNLOAD) && !targets.is_empty() {
+        let manifest = Manifest::load(&config.working_dir)?;
+        errors.append(&mut download_targets(config, aur, pool, manifest, targets)?);
+    }
+    Ok(errors)
+}
+
+/// List downloaded packages whose AUR version differs from the one in
+/// the working directory
+fn cower_status(
+    config: &Config<AurPkg>,
+    aur: &AurT,
+    pool: &TaskPool,
+) -> Result<Vec<Error>, Error> {
+    let manifest = Manifest::load(&config.working_dir)?;
+    let records: Vec<(&String, &Record)> = manifest
+        .records()
+        .filter(|&(pkgbase, _)| config.working_dir.join(pkgbase).is_dir())
+        .collect();
+
+    let names: Vec<String> = records.iter().map(|&(_, r)| r.name.clone()).collect();
+    let (pkgs, errors) = fetch_info(config, aur, pool, &names)?;
+
+    for (pkgbase, record) in records {
+        let pkg = match pkgs.iter().find(|pkg| pkg.name() == record.name) {
+            Some(pkg) => pkg,
+            None => continue,
+        };
+        if pkg.version() == record.version {
+            continue;
+        }
+
+        if config.quiet {
+            println!("{}", pkgbase);
+        } else {
+            println!("{} {} -> {}", pkgbase, record.version, pkg.version());
+        }
+    }
+    Ok(errors)
+}
+
+/// Show what changed in a staged download and put it in place, unless
+/// the user declines. Returns `None` if it was skipped.
+fn review_and_commit(
+    config: &Config<AurPkg>,
+    pool: &TaskPool,
+    staged: Staged,
+) -> Result<Option<(String, PathBuf)>, Error> {
+    let pkgbase = staged.pkgbase().to_owned();
+    let diff = staged.diff()?;
+
+    if !diff.is_empty() {
+        if shows_diff(config) {
+            show_diff(&diff)?;
+        }
+        if config.confirm_diffs && !confirm(pool, &format!("Proceed with {}?", pkgbase))? {
+            staged.abort()?;
+            eprintln!(":: skipping {}", pkgbase);
+            return Ok(None);
+        }
+    }
+
+    let path = staged.commit()?;
+    Ok(Some((pkgbase, path)))
+}
+
+/// Whether to show a pkgbase's changes. There's no confirming them
+/// unseen, so --quiet only hides them when nobody is asked.
+fn shows_diff(config: &Config<AurPkg>) -> bool {
+    config.confirm_diffs || !config.quiet
+}
+
+/// Print `diff`, through `$PAGER` when writing to a terminal
+fn show_diff(diff: &str) -> Result<(), Error> {
+    let pager = env::var("PAGER").ok().filter(|pager| !pager.is_empty());
+    let pager = match pager {
+        Some(ref pager) if stdout_isatty() => pager,
+        _ => {
+            print!("{}", diff);
+            return Ok(());
+        }
+    };
+
+    let mut child = process::Command::new("sh")
+        .arg("-c")
+        .arg(pager)
+        .stdin(process::Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager may quit before reading everything
+        let _ = stdin.write_all(diff.as_bytes());
+    }
+    child.wait()?;
     Ok(())
 }
 
+/// Ask a yes/no question on the terminal, defaulting to no. Fails once
+/// `pool` is cancelled, whether before asking or while waiting.
+fn confirm(pool: &TaskPool, question: &str) -> Result<bool, Error> {
+    if pool.is_cancelled() {
+        return Err(Error::from(PoolError::Cancelled));
+    }
+    eprint!(":: {} [y/N] ", question);
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if pool.is_cancelled() {
+        return Err(Error::from(PoolError::Cancelled));
+    }
+    let answer = answer.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+/// Look up every target with as few info queries as possible. Targets
+/// without a result are reported as errors, along with any similarly
+/// named packages.
+fn fetch_info(
+    config: &Config<AurPkg>,
+    aur: &AurT,
+    pool: &TaskPool,
+    targets: &[String],
+) -> Result<(Vec<AurPkg>, Vec<Error>), Error> {
+    let (pkgs, mut errors) = lookup_info(aur, pool, targets)?;
+
+    // A failed query may have held the missing targets
+    if !errors.is_empty() {
+        return Ok((pkgs, errors));
+    }
+
+    // Suggestions come from whatever name list is already on disk, a typo
+    // isn't worth downloading every package name for
+    let mut names = None;
+    for arg in targets {
+        if pkgs.iter().any(|pkg| pkg.name() == arg) {
+            continue;
+        }
+
+        let names = names.get_or_insert_with(|| aur.cached_package_names().unwrap_or_default());
+        let suggestions = suggest(arg, names.iter().map(|s| s.as_str()), SUGGESTIONS);
+        errors.push(Error::from(if suggestions.is_empty() {
+            CowerError::NotFound {
+                target: arg.clone(),
+            }
+        } else {
+            CowerError::NotFoundSuggest {
+                target: arg.clone(),
+                suggestions: suggestions.join(", "),
+            }
+        }));
+    }
+
+    let pkgs = pkgs
+        .into_iter()
+        .filter(|pkg| !(config.ignore_ood && pkg.out_of_date().is_some()))
+        .collect();
+    Ok((pkgs, errors))
+}
+
+/// The `targets` of a download batch that are done with before anything
+/// is downloaded: the AUR doesn't have them, or they're ignored. After a
+/// failed query nothing can be told apart, so none are.
+fn settled_targets(targets: &[String], pkgs: &[AurPkg], errors: &[Error]) -> Vec<String> {
+    let not_found = |error: &Error| match error.downcast_ref::<CowerError>() {
+        Some(CowerError::NotFound { .. }) | Some(CowerError::NotFoundSuggest { .. }) => true,
+        _ => false,
+    };
+    if !errors.iter().all(not_found) {
+        return Vec::new();
+    }
+    targets
+        .iter()
+        .filter(|target| !pkgs.iter().any(|pkg| pkg.name() == target.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// Info for whichever of `names` exist, along with any failed queries
+fn lookup_info(
+    aur: &AurT,
+    pool: &TaskPool,
+    names: &[String],
+) -> Result<(Vec<AurPkg>, Vec<Error>), Error> {
+    let args: Vec<&str> = names.iter().map(|s| s.as_str()).collect();
+    let (mut pkgs, misses) = aur.cached_info(&args)?;
+
+    let mut errors = Vec::new();
+    if !misses.is_empty() {
+        let urls = aur.aur_build_rpc_info_urls(&misses)?;
+        let aur = aur.clone();
+        let (results, mut failed) = partition_results(pool.map(urls, move |url| aur.query(&url)));
+        pkgs.extend(results.into_iter().flatten());
+        errors.append(&mut failed);
+    }
+    Ok((pkgs, errors))
+}
+
+/// Print a report of `conflicts` before anything is downloaded. Under
+/// `--strict` they stop the download.
+fn check_conflicts(config: &Config<AurPkg>, conflicts: &[Conflict]) -> Result<(), Error> {
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!(":: conflicting packages:");
+    for conflict in conflicts {
+        eprintln!("   {}", conflict);
+    }
+    if config.strict {
+        return Err(Error::from(CowerError::Conflicts {
+            count: conflicts.len(),
+        }));
+    }
+    Ok(())
+}
+
+/// Open the sync and local databases as configured in pacman.conf
+fn open_pacman() -> Result<Pacman, Error> {
+    Pacman::new(&PacmanConf::load(Path::new(PACMAN_CONF))?)
+}
+
+/// Resolve the dependency graph of `targets` against the sync repos
+/// and the AUR
+fn resolve_deps(
+    config: &Config<AurPkg>,
+    aur: &AurT,
+    pool: &TaskPool,
+    pacman: &Pacman,
+    targets: Vec<AurPkg>,
+) -> Result<Graph, Error> {
+    let lookup = |names: &[String]| {
+        // A partial answer would make dependencies look missing
+        let (pkgs, mut errors) = lookup_info(aur, pool, names)?;
+        match errors.pop() {
+            Some(e) => Err(e),
+            None => Ok(pkgs),
+        }
+    };
+    let mut providers = AurProviders { aur, pool };
+    resolve_with(targets, pacman, lookup, &mut providers, &config.provider_rules)
+}
+
+/// Finds providers of virtual dependencies in the AUR, and asks which
+/// to use when there are several
+struct AurProviders<'a> {
+    aur: &'a AurT,
+    pool: &'a TaskPool,
+}
+
+impl<'a> Providers for AurProviders<'a> {
+    fn search(&mut self, name: &str) -> Result<Vec<AurPkg>, Error> {
+        let mut pkgs = self.aur.search(RpcBy::SearchByProvides, name)?;
+        pkgs.sort_by(|a, b| sort_cmppopularity(b, a));
+        Ok(pkgs)
+    }
+
+    fn choose(&mut self, depend: &str, candidates: &[String]) -> usize {
+        // Without a terminal to ask on, or anyone left to answer, go
+        // with the first one
+        if !stdin_isatty() || self.pool.is_cancelled() {
+            return 0;
+        }
+
+        eprintln!(":: There are {} providers available for {}:", candidates.len(), depend);
+        for (idx, candidate) in candidates.iter().enumerate() {
+            eprintln!("   {}) {}", idx + 1, candidate);
+        }
+        loop {
+            eprint!(":: Enter a number (default=1): ");
+            let _ = std::io::stderr().flush();
+
+            let mut answer = String::new();
+            let read = std::io::stdin().read_line(&mut answer).unwrap_or(0);
+            if read == 0 || self.pool.is_cancelled() {
+                return 0;
+            }
+            match answer.trim() {
+                "" => return 0,
+                answer => match answer.parse::<usize>() {
+                    Ok(n) if n >= 1 && n <= candidates.len() => return n - 1,
+                    _ => eprintln!("error: invalid number: {}", answer),
+                },
+            }
+        }
+    }
+}
+
+/// Print the pkgbases needed for the targets in an order they can be
+/// built in, one layer of independent pkgbases per line
+fn cower_build_order(
+    config: &Config<AurPkg>,
+    aur: &AurT,
+    pool: &TaskPool,
+) -> Result<Vec<Error>, Error> {
+    let (pkgs, mut errors) = fetch_info(config, aur, pool, &config.args)?;
+    if pkgs.is_empty() {
+        return Ok(errors);
+    }
+
+    let graph = resolve_deps(config, aur, pool, &open_pacman()?, pkgs)?;
+    for (name, needed_by) in graph.missing() {
+        errors.push(Error::from(CowerError::MissingDependency {
+            name: name.to_string(),
+            needed_by: needed_by.join(", "),
+        }));
+    }
+
+    for layer in graph.build_order()? {
+        if config.quiet {
+            for pkgbase in layer {
+                println!("{}", pkgbase);
+            }
+        } else {
+            println!("{}", layer.join(" "));
+        }
+    }
+    Ok(errors)
+}
+
+/// Print the dependency graph of every target
+fn cower_graph(
+    config: &Config<AurPkg>,
+    aur: &AurT,
+    pool: &TaskPool,
+) -> Result<Vec<Error>, Error> {
+    let (pkgs, errors) = fetch_info(config, aur, pool, &config.args)?;
+    if pkgs.is_empty() {
+        return Ok(errors);
+    }
+
+    let graph = resolve_deps(config, aur, pool, &open_pacman()?, pkgs)?;
+    match config.graph_format {
+        GraphFormat::Dot => print!("{}", graph.to_dot()),
+        GraphFormat::Json => println!("{}", graph.to_json()?),
+    }
+    Ok(errors)
+}
+
+/// Sort packages according to the configured key and order
+fn sort_packages(config: &Config<AurPkg>, pkgs: &mut Vec<AurPkg>) {
+    pkgs.sort_by(config.sort_func);
+    if let SortOrder::Reverse = config.sortorder {
+        pkgs.reverse();
+    }
+}
+
+fn print_search_result(config: &Config<AurPkg>, pkg: &AurPkg) {
+    if config.quiet {
+        println!("{}", pkg.name());
+        return;
+    } else if !config.format.is_empty() {
+        println!("{}", format_pkg(&config.format, pkg, &config.delim));
+        return;
+    }
+
+    let ood = if pkg.out_of_date().is_some() { " <!>" } else { "" };
+    println!(
+        "aur/{} {}{} ({}, {:.2})",
+        pkg.name(),
+        pkg.version(),
+        ood,
+        pkg.votes(),
+        pkg.popularity()
+    );
+    println!("    {}", pkg.description());
+}
+
+fn print_info(config: &Config<AurPkg>, pkg: &AurPkg) {
+    if !config.format.is_empty() {
+        println!("{}", format_pkg(&config.format, pkg, &config.delim));
+        return;
+    }
+
+    let field = |name: &str, val: &str| {
+        if !val.is_empty() {
+            println!("{:<15} : {}", name, val);
+        }
+    };
+    let list = |name: &str, vals: &[String]| field(name, &vals.join(&config.delim));
+
+    field("Repository", "aur");
+    field("Name", pkg.name());
+    if pkg.name() != pkg.pkgbase() {
+        field("PackageBase", pkg.pkgbase());
+    }
+    field("Version", pkg.version());
+    field("URL", pkg.upstream_url());
+    field(
+        "AUR Page",
+        &format!("https://{}/packages/{}", config.aur_domain, pkg.name()),
+    );
+    list("Keywords", pkg.keywords());
+    list("Groups", pkg.groups());
+    list("Depends On", pkg.depends());
+    list("Makedepends", pkg.makedepends());
+    list("Checkdepends", pkg.checkdepends());
+    list("Optional Deps", pkg.optdepends());
+    list("Provides", pkg.provides());
+    list("Conflicts With", pkg.conflicts());
+    list("Replaces", pkg.replaces());
+    list("License", pkg.licenses());
+    field("Maintainer", pkg.maintainer());
+    field("Votes", &pkg.votes().to_string());
+    field("Popularity", &format!("{:.2}", pkg.popularity()));
+    field("Submitted", &format_date(pkg.submitted()));
+    field("Last Modified", &format_date(pkg.modified()));
+    match pkg.out_of_date() {
+        Some(secs) => field("Out of Date", &format!("Yes [{}]", format_date(secs))),
+        None => field("Out of Date", "No"),
+    }
+    field("Description", pkg.description());
+    println!();
+}
+
+/// The RPC search terms for a regex, one for each top-level alternative:
+/// the longest run of literal characters every match of it contains.
+/// Escapes, character classes, groups and optional characters all end a
+/// run. Fails if an alternative has no term the AUR would accept.
+fn regex_literals(regex: &str) -> Result<Vec<String>, CowerError> {
+    let chars: Vec<char> = regex.chars().collect();
+    let mut terms = Vec::new();
+    let mut longest = String::new();
+    let mut current = String::new();
+    let mut depth = 0;
+
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let mut next = i + 1;
+        let mut literal = false;
+        match c {
+            '\\' => next = i + 2,
+            '[' => next = class_end(&chars, i),
+            '{' => next = chars[i..].iter().position(|&c| c == '}').map_or(chars.len(), |end| i + end + 1),
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            '|' if depth == 0 => {
+                terms.push(longest_run(longest, &mut current));
+                longest = String::new();
+            }
+            _ => literal = depth == 0 && !".^$*+?|".contains(c),
+        }
+
+        let optional = match chars.get(next) {
+            Some('*') | Some('?') | Some('{') => true,
+            _ => false,
+        };
+        if literal && !optional {
+            current.push(c);
+        } else {
+            longest = longest_run(longest, &mut current);
+        }
+        i = next;
+    }
+    terms.push(longest_run(longest, &mut current));
+
+    if terms.iter().any(|term| term.chars().count() < 2) {
+        return Err(CowerError::NoSearchTerm {
+            regex: regex.to_string(),
+        });
+    }
+    Ok(terms)
+}
+
+/// The longer of `longest` and `current`, emptying `current`
+fn longest_run(longest: String, current: &mut String) -> String {
+    let current = current.split_off(0);
+    if current.chars().count() > longest.chars().count() {
+        current
+    } else {
+        longest
+    }
+}
+
+/// The index just past the character class opening at `start`
+fn class_end(chars: &[char], start: usize) -> usize {
+    let mut i = start + 1;
+    if chars.get(i) == Some(&'^') {
+        i += 1;
+    }
+    // A `]` right at the start is part of the class
+    if chars.get(i) == Some(&']') {
+        i += 1;
+    }
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            ']' => return i + 1,
+            _ => i += 1,
+        }
+    }
+    chars.len()
+}
+
 /// Get the path to the config file.
 /// Will first look for it in the `XDG_CONFIG_HOME` environment variable
 /// and then in the caller's home directory
@@ -89,255 +1515,561 @@ pub fn get_config_path() -> Option<PathBuf> {
     }
 }
 
-/// Handle the command line arguments
-fn handle_command_line_args(config: &mut Config<AurPkg>) -> Result<(), Error> {
-    let matches = App::new("cower-rs")
-        .version(get_version_string().unwrap().as_str())
+/// Show the effective value of every config field and where it came from
+fn print_config(config: &Config<AurPkg>, loaded: &[(ConfigLayer, PathBuf)]) {
+    for (layer, path) in loaded {
+        println!("# {}: {}", layer, path.display());
+    }
+
+    let fields = config.dump();
+    let width = fields.iter().map(|f| f.0.len()).max().unwrap_or(0);
+    for (field, val, layer) in fields {
+        println!("{:width$} = {}  ({})", field, val, layer, width = width);
+    }
+}
+
+/// Build the command line interface
+/// A command line switch. The parser and cower(1) are both built from
+/// these, so the two can't drift apart.
+#[derive(Default)]
+struct Switch {
+    name: &'static str,
+    short: Option<char>,
+    /// What the value is called, for a switch that takes one
+    value: Option<&'static str>,
+    /// The values accepted, if only some are
+    values: &'static [&'static str],
+    /// Whether the switch can be given more than once
+    multiple: bool,
+    /// How many values each occurrence takes, if it's fixed
+    number_of_values: Option<u64>,
+    conflicts_with: Option<&'static str>,
+    help: &'static str,
+}
+
+impl Switch {
+    fn arg<'b>(&self) -> Arg<'static, 'b> {
+        let mut arg = Arg::with_name(self.name)
+            .long(self.name)
+            .multiple(self.multiple)
+            .help(self.help);
+        if let Some(short) = self.short {
+            arg = arg.short(short.to_string());
+        }
+        if let Some(value) = self.value {
+            arg = arg.takes_value(true).value_name(value);
+        }
+        if !self.values.is_empty() {
+            arg = arg.possible_values(self.values);
+        }
+        if let Some(count) = self.number_of_values {
+            arg = arg.number_of_values(count);
+        }
+        if let Some(other) = self.conflicts_with {
+            arg = arg.conflicts_with(other);
+        }
+        arg
+    }
+
+    /// The switch as cower(1) lists it
+    fn man_item(&self, page: &mut ManPage) {
+        let mut help = self.help.to_string();
+        if !self.values.is_empty() {
+            help.push_str(&format!(". One of: {}.", self.values.join(", ")));
+        }
+        page.item(&switch_term(self.short, Some(self.name), self.value), &help);
+    }
+}
+
+const SORT_KEYS: &[&str] = &[
+    "name",
+    "version",
+    "maintainer",
+    "votes",
+    "popularity",
+    "outofdate",
+    "lastmodified",
+    "firstsubmitted",
+];
+
+/// The switches that pick what cower does
+fn operation_switches() -> Vec<Switch> {
+    vec![
+        Switch {
+            name: "download",
+            short: Some('d'),
+            multiple: true,
+            help: "download target(s) -- pass twice to download AUR dependencies",
+            ..Switch::default()
+        },
+        Switch {
+            name: "info",
+            short: Some('i'),
+            help: "show info for target(s)",
+            ..Switch::default()
+        },
+        Switch {
+            name: "msearch",
+            short: Some('m'),
+            help: "show packages maintained by target(s)",
+            ..Switch::default()
+        },
+        Switch {
+            name: "search",
+            short: Some('s'),
+            help: "search for target(s)",
+            ..Switch::default()
+        },
+        Switch {
+            name: "update",
+            short: Some('u'),
+            help: "check for updates against AUR -- can be combined with the -d flag",
+            ..Switch::default()
+        },
+        Switch {
+            name: "graph",
+            value: Some("format"),
+            values: &["dot", "json"],
+            help: "print the dependency graph of target(s)",
+            ..Switch::default()
+        },
+        Switch {
+            name: "build-order",
+            help: "print the pkgbases needed by target(s) in the order to build them",
+            ..Switch::default()
+        },
+        Switch {
+            name: "status",
+            help: "list downloaded packages that have changed on the AUR",
+            ..Switch::default()
+        },
+        Switch {
+            name: "resume",
+            help: "download whatever is left of the last interrupted batch",
+            ..Switch::default()
+        },
+        Switch {
+            name: "print-config",
+            help: "show the effective configuration and where each value came from",
+            ..Switch::default()
+        },
+    ]
+}
+
+/// The switches that change how an operation behaves
+fn option_switches() -> Vec<Switch> {
+    vec![
+        Switch {
+            name: "by",
+            value: Some("search-by"),
+            values: &["name", "name-desc", "maintainer", "keywords"],
+            help: "search by category",
+            ..Switch::default()
+        },
+        Switch {
+            name: "domain",
+            value: Some("fqdn"),
+            help: key_help("Domain"),
+            ..Switch::default()
+        },
+        Switch {
+            name: "force",
+            short: Some('f'),
+            help: "overwrite existing files when downloading",
+            ..Switch::default()
+        },
+        Switch {
+            name: "ignore",
+            value: Some("pkg"),
+            multiple: true,
+            help: "ignore a package upgrade (can be used more than once)",
+            ..Switch::default()
+        },
+        Switch {
+            name: "ignoregroup",
+            value: Some("group"),
+            multiple: true,
+            help: "ignore upgrades of packages in a group (can be used more than once)",
+            ..Switch::default()
+        },
+        Switch {
+            name: "ignorerepo",
+            value: Some("repo"),
+            multiple: true,
+            help: "ignore some or all binary repos",
+            ..Switch::default()
+        },
+        Switch {
+            name: "target",
+            short: Some('t'),
+            value: Some("target"),
+            help: key_help("TargetDir"),
+            ..Switch::default()
+        },
+        Switch {
+            name: "threads",
+            value: Some("threads"),
+            help: key_help("MaxThreads"),
+            ..Switch::default()
+        },
+        Switch {
+            name: "timeout",
+            value: Some("timeout"),
+            help: key_help("ConnectTimeout"),
+            ..Switch::default()
+        },
+        Switch {
+            name: "config",
+            value: Some("path"),
+            help: "read an additional config file after the system and user ones",
+            ..Switch::default()
+        },
+        Switch {
+            name: "color",
+            short: Some('c'),
+            value: Some("WHEN"),
+            values: &["never", "always", "auto"],
+            help: "use colored output",
+            ..Switch::default()
+        },
+        Switch {
+            name: "debug",
+            help: "show debug output",
+            ..Switch::default()
+        },
+        Switch {
+            name: "format",
+            value: Some("string"),
+            help: key_help("Format"),
+            ..Switch::default()
+        },
+        Switch {
+            name: "no-cache",
+            help: key_help("NoCache"),
+            ..Switch::default()
+        },
+        Switch {
+            name: "confirm",
+            help: key_help("ConfirmDiffs"),
+            ..Switch::default()
+        },
+        Switch {
+            name: "devel",
+            help: key_help("Devel"),
+            ..Switch::default()
+        },
+        Switch {
+            name: "strict",
+            help: key_help("Strict"),
+            ..Switch::default()
+        },
+        Switch {
+            name: "provider",
+            value: Some("name=pkg"),
+            multiple: true,
+            number_of_values: Some(1),
+            help: "use pkg to satisfy dependencies on name",
+            ..Switch::default()
+        },
+        Switch {
+            name: "git",
+            help: "clone package git repositories instead of downloading snapshots",
+            ..Switch::default()
+        },
+        Switch {
+            name: "fuzzy",
+            help: "search for names close to the target, allowing for typos",
+            ..Switch::default()
+        },
+        Switch {
+            name: "offline",
+            help: key_help("Offline"),
+            ..Switch::default()
+        },
+        Switch {
+            name: "refresh",
+            conflicts_with: Some("no-cache"),
+            help: "ignore cached RPC responses, but cache fresh ones",
+            ..Switch::default()
+        },
+        Switch {
+            name: "ignore-ood",
+            short: Some('o'),
+            help: key_help("IgnoreOOD"),
+            ..Switch::default()
+        },
+        Switch {
+            name: "sort",
+            value: Some("key"),
+            values: SORT_KEYS,
+            help: "sort results in ascending order by key",
+            ..Switch::default()
+        },
+        Switch {
+            name: "rsort",
+            value: Some("key"),
+            values: SORT_KEYS,
+            help: "sort results in descending order by key",
+            ..Switch::default()
+        },
+        Switch {
+            name: "listdelim",
+            value: Some("delim"),
+            help: key_help("ListDelim"),
+            ..Switch::default()
+        },
+        Switch {
+            name: "literal",
+            help: key_help("Literal"),
+            ..Switch::default()
+        },
+        Switch {
+            name: "quiet",
+            short: Some('q'),
+            help: "output less",
+            ..Switch::default()
+        },
+        Switch {
+            name: "verbose",
+            short: Some('v'),
+            help: "output more",
+            ..Switch::default()
+        },
+        Switch {
+            name: "from-srcinfo",
+            short: Some('p'),
+            help: "use .SRCINFO files to determine targets",
+            ..Switch::default()
+        },
+    ]
+}
+
+/// Subcommands that are shown in help, with what they do
+const COMMANDS: &[(&str, &str)] = &[
+    ("completions", "print a completion script for the given shell"),
+    ("manpage", "print a man page in roff"),
+    ("sync-db", "download the AUR metadata dump for use with --offline"),
+];
+
+fn command<'b>(name: &'static str) -> App<'static, 'b> {
+    let about = COMMANDS.iter().find(|c| c.0 == name).map_or("", |c| c.1);
+    SubCommand::with_name(name).about(about)
+}
+
+fn build_cli<'b>(version: &'b str) -> App<'static, 'b> {
+    let operations = operation_switches();
+    let names: Vec<&str> = operations.iter().map(|switch| switch.name).collect();
+
+    let mut app = App::new("cower-rs")
+        .version(version)
         .author(crate_authors!("\n"))
         .about(crate_description!())
-        .arg(
-            Arg::with_name("download")
-                .short("d")
-                .long("download")
-                .multiple(true)
-                .help("download target(s) -- pass twice to download AUR dependencies"),
-        ).arg(
-            Arg::with_name("info")
-                .short("i")
-                .long("info")
-                .help("show info for target(s)"),
-        ).arg(
-            Arg::with_name("msearch")
-                .short("m")
-                .long("msearch")
-                .help("show packages maintained by target(s)"),
-        ).arg(
-            Arg::with_name("search")
-                .short("s")
-                .long("search")
-                .help("search for target(s)"),
-        ).arg(
-            Arg::with_name("update")
-                .short("u")
-                .long("update")
-                .help("check for updates against AUR -- can be combined with the -d flag"),
-        ).group(
-            ArgGroup::with_name("Operations")
-                .args(&["download", "info", "msearch", "search", "update"])
-                .required(true)
-                .multiple(true),
-        ).arg(
-            Arg::with_name("by")
-                .long("by")
-                .help("search by category")
-                .takes_value(true)
-                .value_name("search-by")
-                .possible_values(&["name", "name-desc", "maintainer"]),
-        ).arg(
-            Arg::with_name("domain")
-                .long("domain")
-                .help("point cower at a different AUR (default: aur.archlinux.org)")
-                .takes_value(true)
-                .value_name("fqdn"),
-        ).arg(
-            Arg::with_name("force")
-                .long("force")
-                .short("f")
-                .help("overwrite existing files when downloading"),
-        ).arg(
-            Arg::with_name("ignore")
-                .long("ignore")
-                .help("ignore a package upgrade (can be used more than once")
-                .takes_value(true)
-                .value_name("pkg")
-                .multiple(true),
-        ).arg(
-            Arg::with_name("ignorerepo")
-                .long("ignorerepo")
-                .takes_value(true)
-                .value_name("repo")
-                .multiple(true)
-                .help("ignore some or all binary repos"),
-        ).arg(
-            Arg::with_name("target")
-                .short("t")
-                .long("target")
-                .takes_value(true)
-                .help("specify an alternate download directory"),
-        ).arg(
-            Arg::with_name("threads")
-                .long("threads")
-                .takes_value(true)
-                .help("limit number of threads created"),
-        ).arg(
-            Arg::with_name("timeout")
-                .long("timeout")
-                .takes_value(true)
-                .help("specify connection timeout in seconds"),
-        ).arg(
-            Arg::with_name("color")
-                .long("color")
-                .short("c")
-                .takes_value(true)
-                .value_name("WHEN")
-                .help("use colored output")
-                .possible_values(&["never", "always", "auto"]),
-        ).arg(
-            Arg::with_name("debug")
-                .long("debug")
-                .help("show debug output"),
-        ).arg(
-            Arg::with_name("format")
-                .long("format")
-                .takes_value(true)
-                .value_name("string")
-                .help("print package output according to format string"),
-        ).arg(
-            Arg::with_name("ignore-ood")
-                .long("ignore-ood")
-                .short("o")
-                .help("the opposite of --ignore-ood"),
-        ).arg(
-            Arg::with_name("sort")
-                .long("sort")
-                .takes_value(true)
-                .value_name("key")
-                .help("sort results in ascending order by key")
-                .possible_values(&[
-                    "name",
-                    "version",
-                    "maintainer",
-                    "votes",
-                    "popularity",
-                    "outofdate",
-                    "lastmodified",
-                    "firstsubmitted",
-                ]),
-        ).arg(
-            Arg::with_name("rsort")
-                .long("rsort")
-                .takes_value(true)
-                .value_name("key")
-                .help("sort results in descending order by key"),
-        ).arg(
-            Arg::with_name("listdelim")
-                .long("listdelim")
-                .takes_value(true)
-                .value_name("delim")
-                .help("change list format delimeter"),
-        ).arg(
-            Arg::with_name("literal")
-                .long("literal")
-                .help("disable regex search, interpret target as a literal string"),
-        ).arg(
-            Arg::with_name("quiet")
-                .long("quiet")
-                .short("q")
-                .help("output less"),
-        ).arg(
-            Arg::with_name("verbose")
-                .long("verbose")
-                .short("v")
-                .help("output more"),
-        ).arg(
-            Arg::with_name("from-srcinfo")
-                .long("from-srcinfo")
-                .short("p")
-                .help("use .SRCINFO files to determine targets"),
-        ).arg(Arg::with_name("args").multiple(true))
-        .get_matches();
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .after_help(EXIT_STATUS_HELP)
+        .subcommand(
+            command("completions").arg(
+                Arg::with_name("shell")
+                    .required(true)
+                    .possible_values(&["bash", "zsh", "fish"]),
+            ),
+        ).subcommand(
+            command("manpage").arg(
+                Arg::with_name("page")
+                    .possible_values(&["cower", "cower.conf"])
+                    .default_value("cower"),
+            ),
+        ).subcommand(
+            SubCommand::with_name("complete-names")
+                .setting(AppSettings::Hidden)
+                .about("list the AUR package names starting with prefix, without network access")
+                .arg(Arg::with_name("prefix")),
+        ).subcommand(
+            command("sync-db").arg(
+                Arg::with_name("file")
+                    .help("import a local copy of packages-meta-ext-v1.json.gz instead"),
+            ),
+        );
+    for switch in &operations {
+        app = app.arg(switch.arg());
+    }
+    app = app.group(
+        ArgGroup::with_name("Operations")
+            .args(&names)
+            .required(true)
+            .multiple(true),
+    );
+    for switch in &option_switches() {
+        app = app.arg(switch.arg());
+    }
+    app.arg(Arg::with_name("args").multiple(true))
+}
 
+/// Handle the command line arguments
+fn handle_command_line_args(
+    config: &mut Config<AurPkg>,
+    matches: &ArgMatches,
+) -> Result<(), Error> {
     // Operations
-    parse_operations(config, &matches);
+    parse_operations(config, matches);
 
     // Options
     if let Some(color) = matches.value_of("color") {
         config.set_color(color)?;
+        config.mark("color");
     }
 
     if matches.is_present("force") {
         config.force = true;
+        config.mark("force");
     }
 
     if matches.is_present("quiet") {
         config.quiet = true;
+        config.mark("quiet");
     }
 
     if let Some(path) = matches.value_of("target") {
-        config.working_dir = PathBuf::from(path);
+        config.set_working_dir(path, &env::current_dir()?)?;
+        config.mark("working_dir");
     }
 
     if matches.is_present("debug") {
         config.loglevel = Level::Debug;
+        config.mark("loglevel");
     }
 
     if matches.is_present("verbose") {
         config.loglevel = Level::Trace;
+        config.mark("loglevel");
     }
 
     if let Some(format) = matches.value_of("format") {
         config.format = String::from(format);
+        config.mark("format");
     }
 
-    if matches.is_present("rsort") {
-        config.sortorder = SortOrder::Reverse;
+    if let Some(sort) = matches.value_of("sort") {
+        config.sortorder = SortOrder::Forward;
+        config.set_sort_by(sort)?;
+        config.mark("sortorder");
+        config.mark("sort_func");
     }
 
-    if let Some(sort) = matches.value_of("sort") {
-        config.sort_func = match sort {
-            "name" => sort_name,
-            "version" => sort_cmpver,
-            "maintainer" => sort_cmpmaint,
-            "votes" => sort_cmpvotes,
-            "popularity" => sort_cmppopularity,
-            "outofdate" => sort_cmpood,
-            "lastmodified" => sort_cmplastmod,
-            "firstsubmitted" => sort_cmpfirstsub,
-            _ => unreachable!(),
-        }
+    if let Some(sort) = matches.value_of("rsort") {
+        config.sortorder = SortOrder::Reverse;
+        config.set_sort_by(sort)?;
+        config.mark("sortorder");
+        config.mark("sort_func");
     }
 
     if let Some(ignore) = matches.values_of("ignore") {
         config.ignore_pkgs = ignore.map(String::from).collect();
+        config.mark("ignore_pkgs");
+    }
+
+    if let Some(ignore) = matches.values_of("ignoregroup") {
+        config.ignore_groups = ignore.map(String::from).collect();
+        config.mark("ignore_groups");
     }
 
     if let Some(ignore) = matches.values_of("ignorerepo") {
         config.ignore_repos = ignore.map(String::from).collect();
+        config.mark("ignore_repos");
     }
 
     if let Some(by) = matches.value_of("by") {
         config.set_search_by(by)?;
+        config.mark("search_by");
     }
 
     if let Some(domain) = matches.value_of("domain") {
         config.aur_domain = String::from(domain);
+        config.mark("aur_domain");
     }
 
     if let Some(delim) = matches.value_of("listdelim") {
         config.delim = String::from(delim);
+        config.mark("delim");
     }
 
     if matches.is_present("literal") {
         config.literal = true;
+        config.mark("literal");
+    }
+
+    if matches.is_present("ignore-ood") {
+        config.ignore_ood = true;
+        config.mark("ignore_ood");
+    }
+
+    if matches.is_present("no-cache") {
+        config.no_cache = true;
+        config.mark("no_cache");
+    }
+
+    if matches.is_present("confirm") {
+        config.confirm_diffs = true;
+        config.mark("confirm_diffs");
+    }
+
+    if matches.is_present("devel") {
+        config.devel = true;
+        config.mark("devel");
+    }
+
+    if matches.is_present("strict") {
+        config.strict = true;
+        config.mark("strict");
+    }
+
+    if let Some(rules) = matches.values_of("provider") {
+        for rule in rules {
+            config.add_provider_rules(rule)?;
+        }
+        config.mark("provider_rules");
+    }
+
+    if matches.is_present("git") {
+        config.download_method = DownloadMethod::Git;
+        config.mark("download_method");
+    }
+
+    if matches.is_present("fuzzy") {
+        config.fuzzy = true;
+        config.mark("fuzzy");
+    }
+
+    if matches.is_present("offline") {
+        config.offline = true;
+        config.mark("offline");
+    }
+
+    if matches.is_present("refresh") {
+        config.refresh = true;
+        config.mark("refresh");
     }
 
     if let Some(threads) = matches.value_of("threads") {
-        config.maxthreads = threads.parse()?
+        config.set_maxthreads(threads)?;
+        config.mark("maxthreads");
     }
 
     if let Some(timeout) = matches.value_of("timeout") {
-        config.timeout = timeout.parse()?
+        config.set_timeout(timeout)?;
+        config.mark("timeout");
     }
 
     if matches.is_present("from-srcinfo") {
         config.srcinfo = true;
+        config.mark("srcinfo");
     }
 
     if let Some(args) = matches.values_of("args") {
         config.args = args.map(String::from).collect();
+        config.mark("args");
     }
 
-    check_operation_combinations(&config)?;
+    check_operation_combinations(config, matches)?;
 
     // Handle regexes
     if allow_regex(&config) {
@@ -356,6 +2088,11 @@ fn handle_command_line_args(config: &mut Config<AurPkg>) -> Result<(), Error> {
                 regex: regexes.join("\n").trim().to_string(),
             }));
         }
+
+        // Don't send the AUR queries it will refuse
+        for arg in &config.args {
+            regex_literals(arg)?;
+        }
     }
 
     Ok(())
@@ -383,27 +2120,130 @@ fn parse_operations(config: &mut Config<AurPkg>, args: &ArgMatches) {
         }
     }
 
+    if let Some(format) = args.value_of("graph") {
+        config.opmask.insert(OpMask::GRAPH);
+        // Already checked by clap
+        config.graph_format = format.parse().unwrap_or(GraphFormat::Dot);
+        config.mark("graph_format");
+    }
+
+    if args.is_present("build-order") {
+        config.opmask.insert(OpMask::ORDER);
+    }
+
+    if args.is_present("status") {
+        config.opmask.insert(OpMask::STATUS);
+    }
+
+    // Implies -d, but can be combined with new targets
+    if args.is_present("resume") {
+        config.opmask.insert(OpMask::DOWNLOAD);
+        config.resume = true;
+        config.mark("resume");
+    }
+
     if args.is_present("msearch") {
         config.opmask.insert(OpMask::SEARCH);
         config.search_by = SearchBy::Maintainer;
+        config.mark("search_by");
+    }
+
+    if !config.opmask.is_empty() {
+        config.mark("opmask");
+    }
+    if config.getdeps {
+        config.mark("getdeps");
     }
 }
 
-/// Ensure that the mode argument combinations are valid
-fn check_operation_combinations(config: &Config<AurPkg>) -> Result<(), Error> {
-    let info = OpMask::INFO;
-    let search = OpMask::SEARCH;
-    let updown = OpMask::UPDATE | OpMask::DOWNLOAD;
+/// The flag that selects each operation, for error messages
+const OPERATION_FLAGS: &[(OpMask, &str)] = &[
+    (OpMask::SEARCH, "-s/--search"),
+    (OpMask::INFO, "-i/--info"),
+    (OpMask::DOWNLOAD, "-d/--download"),
+    (OpMask::UPDATE, "-u/--update"),
+    (OpMask::STATUS, "--status"),
+    (OpMask::GRAPH, "--graph"),
+    (OpMask::ORDER, "--build-order"),
+];
+
+/// Pairs of operations that may be given together. Any other pair is
+/// an error.
+const COMPATIBLE_OPERATIONS: &[(OpMask, OpMask)] = &[(OpMask::UPDATE, OpMask::DOWNLOAD)];
+
+/// Command line options that only mean something to some operations,
+/// by argument name, with the operations they work with
+const OPTION_OPERATIONS: &[(&str, &str, &[OpMask])] = &[
+    ("by", "--by", &[OpMask::SEARCH]),
+    ("literal", "--literal", &[OpMask::SEARCH]),
+    ("fuzzy", "--fuzzy", &[OpMask::SEARCH]),
+    ("format", "--format", &[OpMask::SEARCH, OpMask::INFO]),
+    ("sort", "--sort", &[OpMask::SEARCH, OpMask::INFO]),
+    ("rsort", "--rsort", &[OpMask::SEARCH, OpMask::INFO]),
+    ("listdelim", "--listdelim", &[OpMask::SEARCH, OpMask::INFO]),
+    ("force", "-f/--force", &[OpMask::DOWNLOAD]),
+    ("git", "--git", &[OpMask::DOWNLOAD]),
+    ("confirm", "--confirm", &[OpMask::DOWNLOAD]),
+    ("strict", "--strict", &[OpMask::DOWNLOAD]),
+    ("target", "-t/--target", &[OpMask::DOWNLOAD, OpMask::STATUS]),
+    ("devel", "--devel", &[OpMask::UPDATE]),
+    ("ignore", "--ignore", &[OpMask::UPDATE]),
+    ("ignoregroup", "--ignoregroup", &[OpMask::UPDATE]),
+    ("ignorerepo", "--ignorerepo", &[OpMask::UPDATE]),
+    ("provider", "--provider", &[OpMask::DOWNLOAD, OpMask::GRAPH, OpMask::ORDER]),
+];
+
+/// Ensure the operations on the command line can be combined, and that
+/// the options given mean something to them
+fn check_operation_combinations(config: &Config<AurPkg>, matches: &ArgMatches) -> Result<(), Error> {
+    let options: Vec<&str> = OPTION_OPERATIONS
+        .iter()
+        .map(|&(name, _, _)| name)
+        .filter(|name| matches.is_present(name))
+        .collect();
+    check_combinations(config.opmask, &options).map_err(Error::from)
+}
 
-    // Check the combinations, ensure they're valid
-    if config.opmask.contains(info) && config.opmask.intersects(!info)
-        || config.opmask.contains(search) && config.opmask.intersects(!search)
-        || config.opmask.contains(updown) && config.opmask.intersects(!updown)
-    {
-        Err(Error::from(CowerError::InvalidOperation))
-    } else {
-        Ok(())
+/// Check `ops` against `COMPATIBLE_OPERATIONS`, and the named `options`
+/// against `OPTION_OPERATIONS`
+fn check_combinations(ops: OpMask, options: &[&str]) -> Result<(), CowerError> {
+    let given: Vec<&(OpMask, &str)> = OPERATION_FLAGS
+        .iter()
+        .filter(|&&(op, _)| ops.contains(op))
+        .collect();
+
+    for (i, &&(first, first_flag)) in given.iter().enumerate() {
+        for &&(second, second_flag) in &given[i + 1..] {
+            let compatible = COMPATIBLE_OPERATIONS
+                .iter()
+                .any(|&(a, b)| a | b == first | second);
+            if !compatible {
+                return Err(CowerError::InvalidOperation {
+                    first: first_flag.to_string(),
+                    second: second_flag.to_string(),
+                });
+            }
+        }
+    }
+
+    // Options can come without an operation, like with --print-config
+    if ops.is_empty() {
+        return Ok(());
+    }
+    for &(name, flag, allowed) in OPTION_OPERATIONS {
+        if options.contains(&name) && !allowed.iter().any(|&op| ops.contains(op)) {
+            let operations: Vec<&str> = OPERATION_FLAGS
+                .iter()
+                .filter(|&&(op, _)| allowed.contains(&op))
+                .map(|&(_, flag)| flag)
+                .collect();
+            return Err(CowerError::OptionNotAllowed {
+                option: flag.to_string(),
+                operations: operations.join(", "),
+            });
+        }
     }
+    Ok(())
 }
 
 /// Determine whether or not regexes as arguments are valid inputs
@@ -411,6 +2251,7 @@ fn allow_regex(config: &Config<AurPkg>) -> bool {
     config.opmask.contains(OpMask::SEARCH)
         && !config.literal
         && config.search_by != SearchBy::Maintainer
+        && config.search_by != SearchBy::Keywords
 }
 
 /// Get all the dependencies from the given files and return them in a
@@ -582,6 +2423,43 @@ pkgname = aurutils
         assert!(deps.contains(&"pacutils".to_owned()));
     }
 
+    #[test]
+    fn test_regex_literal() {
+        let terms = |regex| regex_literals(regex).unwrap();
+        assert_eq!(terms("cower"), vec!["cower"]);
+        assert_eq!(terms("^cow.*-git$"), vec!["-git"]);
+        assert_eq!(terms("pulse(audio)?-modules"), vec!["-modules"]);
+        assert_eq!(terms("^python-(foo|bar)s?$"), vec!["python-"]);
+        assert_eq!(terms("python2?"), vec!["python"]);
+        assert_eq!(terms("lib\\.so\\.[0-9]+"), vec!["lib"]);
+        assert_eq!(terms("a{2,3}bc"), vec!["bc"]);
+    }
+
+    #[test]
+    fn test_regex_literal_skips_escapes_and_classes() {
+        let terms = |regex| regex_literals(regex).unwrap();
+        assert_eq!(terms("\\bcower\\b"), vec!["cower"]);
+        assert_eq!(terms("[0-9a-f]xyz"), vec!["xyz"]);
+        assert_eq!(terms("[]|)(]xyz"), vec!["xyz"]);
+        assert_eq!(terms("[^a\\]b]cower"), vec!["cower"]);
+    }
+
+    #[test]
+    fn test_regex_literal_alternatives() {
+        assert_eq!(regex_literals("foo|barbaz").unwrap(), vec!["foo", "barbaz"]);
+        assert_eq!(regex_literals("^(a|b)cower$|yaourt").unwrap(), vec!["cower", "yaourt"]);
+    }
+
+    #[test]
+    fn test_regex_literal_too_short() {
+        for regex in &[".*", "[0-9a-f]x", "a.b", "cower|", "\\w+", "(cower)?"] {
+            match regex_literals(regex) {
+                Err(CowerError::NoSearchTerm { .. }) => (),
+                other => panic!("{}: {:?}", regex, other),
+            }
+        }
+    }
+
     #[test]
     fn test_read_targets_space_separated() {
         let deps_example = "pacman git jq   pacutils 	git\narch bin\ngit".as_bytes();
@@ -598,4 +2476,304 @@ pkgname = aurutils
         assert!(deps.contains(&"arch".to_owned()));
         assert!(deps.contains(&"bin".to_owned()));
     }
+
+    fn code<F: failure::Fail>(e: F) -> i32 {
+        exit_code(&Error::from(e))
+    }
+
+    #[test]
+    fn test_shows_diff() {
+        let mut config = Config::new(package::sort_name);
+        assert!(shows_diff(&config));
+        config.quiet = true;
+        assert!(!shows_diff(&config));
+        config.confirm_diffs = true;
+        assert!(shows_diff(&config));
+    }
+
+    #[test]
+    fn test_settled_targets() {
+        let dir = TempDir::new("cower_test_settled").unwrap();
+        let mut manifest = Manifest::load(dir.path()).unwrap();
+        let targets = vec!["cower".to_string(), "nosuchpkg".to_string()];
+        let pkgs = aur_packages_from_json(
+            r#"{"version":5,"type":"multiinfo","resultcount":1,"results":[
+            {"ID":1,"Name":"cower","PackageBaseID":1,"PackageBase":"cower","Version":"1",
+             "Description":"","URL":"","NumVotes":0,"Popularity":0,"OutOfDate":null,
+             "Maintainer":null,"FirstSubmitted":0,"LastModified":0,"URLPath":""}]}"#,
+        ).unwrap();
+
+        // Resuming can't find a package that isn't there
+        manifest.set_pending(targets.clone());
+        let not_found = vec![Error::from(CowerError::NotFound {
+            target: "nosuchpkg".to_string(),
+        })];
+        manifest.finish(&settled_targets(&targets, &pkgs, &not_found));
+        assert_eq!(manifest.pending(), &["cower".to_string()]);
+
+        // but may well find one a failed query missed
+        manifest.set_pending(targets.clone());
+        let failed = vec![format_err!("connection refused")];
+        manifest.finish(&settled_targets(&targets, &[], &failed));
+        assert_eq!(manifest.pending(), &targets[..]);
+    }
+
+    fn s() -> String {
+        String::from("x")
+    }
+
+    #[test]
+    fn test_cower_error_exit_codes() {
+        let invalid = CowerError::InvalidOperation {
+            first: s(),
+            second: s(),
+        };
+        assert_eq!(code(invalid), EXIT_USAGE);
+        let option = CowerError::OptionNotAllowed {
+            option: s(),
+            operations: s(),
+        };
+        assert_eq!(code(option), EXIT_USAGE);
+        assert_eq!(code(CowerError::InvalidRegexes { regex: s() }), EXIT_USAGE);
+        assert_eq!(code(CowerError::NoSearchTerm { regex: s() }), EXIT_USAGE);
+        assert_eq!(code(CowerError::NotFound { target: s() }), EXIT_NOT_FOUND);
+        let suggest = CowerError::NotFoundSuggest {
+            target: s(),
+            suggestions: s(),
+        };
+        assert_eq!(code(suggest), EXIT_NOT_FOUND);
+        assert_eq!(code(CowerError::NoDataDir), EXIT_FILESYSTEM);
+        let missing = CowerError::MissingDependency {
+            name: s(),
+            needed_by: s(),
+        };
+        assert_eq!(code(missing), EXIT_NOT_FOUND);
+        assert_eq!(code(CowerError::Conflicts { count: 1 }), EXIT_NOT_FOUND);
+        assert_eq!(code(CowerError::NotForeign { name: s() }), EXIT_NOT_FOUND);
+    }
+
+    #[test]
+    fn test_config_error_exit_codes() {
+        assert_eq!(code(ConfigError::TargetDirNotDir { path: s() }), EXIT_FILESYSTEM);
+        assert_eq!(code(ConfigError::TargetDirNotWritable { path: s() }), EXIT_FILESYSTEM);
+        let create = ConfigError::TargetDirCreateFailed {
+            path: s(),
+            reason: s(),
+        };
+        assert_eq!(code(create), EXIT_FILESYSTEM);
+        let undefined = ConfigError::UndefinedPathVariable { var: s(), path: s() };
+        assert_eq!(code(undefined), EXIT_CONFIG);
+        assert_eq!(code(ConfigError::UnterminatedPathVariable { path: s() }), EXIT_CONFIG);
+        assert_eq!(code(ConfigError::InvalidMaxThreadArg { val: s() }), EXIT_CONFIG);
+        assert_eq!(code(ConfigError::InvalidConnectTimeoutArg { val: s() }), EXIT_CONFIG);
+        assert_eq!(code(ConfigError::InvalidMaxRetriesArg { val: s() }), EXIT_CONFIG);
+        assert_eq!(code(ConfigError::InvalidRateLimitArg { val: s() }), EXIT_CONFIG);
+        assert_eq!(code(ConfigError::InvalidCacheTTLArg { val: s() }), EXIT_CONFIG);
+        assert_eq!(code(ConfigError::InvalidDownloadMethodArg { val: s() }), EXIT_CONFIG);
+        assert_eq!(code(ConfigError::InvalidProviderArg { val: s() }), EXIT_CONFIG);
+        assert_eq!(code(ConfigError::InvalidColorArg { val: s() }), EXIT_CONFIG);
+        assert_eq!(code(ConfigError::InvalidSearchByArg { val: s() }), EXIT_CONFIG);
+        assert_eq!(code(ConfigError::InvalidSortByArg { val: s() }), EXIT_CONFIG);
+        let parse = ConfigError::ParseError {
+            file: s(),
+            line: 1,
+            message: s(),
+        };
+        assert_eq!(code(parse), EXIT_CONFIG);
+        let env = ConfigError::EnvironmentError { var: s(), message: s() };
+        assert_eq!(code(env), EXIT_CONFIG);
+    }
+
+    #[test]
+    fn test_aur_error_exit_codes() {
+        assert_eq!(code(AurTErrors::EmptyArgumentsVector), EXIT_USAGE);
+        assert_eq!(code(AurTErrors::RpcError { message: s() }), EXIT_NETWORK);
+        assert_eq!(code(AurTErrors::HttpStatus { code: 404, url: s() }), EXIT_NETWORK);
+        let limited = AurTErrors::RateLimited {
+            url: s(),
+            attempts: 3,
+        };
+        assert_eq!(code(limited), EXIT_NETWORK);
+        let server = AurTErrors::ServerError {
+            code: 503,
+            url: s(),
+            attempts: 3,
+        };
+        assert_eq!(code(server), EXIT_NETWORK);
+        let failed = AurTErrors::ConnectionFailed {
+            url: s(),
+            attempts: 3,
+            reason: s(),
+        };
+        assert_eq!(code(failed), EXIT_NETWORK);
+        assert_eq!(code(AurTErrors::Offline { url: s() }), EXIT_NETWORK);
+        let invalid = AurTErrors::InvalidResponse {
+            url: s(),
+            reason: s(),
+        };
+        assert_eq!(code(invalid), EXIT_NETWORK);
+    }
+
+    #[test]
+    fn test_library_error_exit_codes() {
+        assert_eq!(code(DepsError::InvalidGraphFormat { format: s() }), EXIT_USAGE);
+        let rule = DepsError::BadProviderRule {
+            name: s(),
+            provider: s(),
+        };
+        assert_eq!(code(rule), EXIT_CONFIG);
+        assert_eq!(code(DepsError::Cycle { pkgbases: s() }), EXIT_NOT_FOUND);
+        assert_eq!(code(MetadataError::UnsupportedQuery { url: s() }), EXIT_USAGE);
+        assert_eq!(code(MetadataError::NotSynced { path: s() }), EXIT_FILESYSTEM);
+        assert_eq!(code(MetadataError::InvalidDump { reason: s() }), EXIT_FILESYSTEM);
+        assert_eq!(code(IndexError::Corrupt { reason: s() }), EXIT_FILESYSTEM);
+        assert_eq!(code(PoolError::Cancelled), EXIT_INTERRUPTED);
+        assert_eq!(code(PoolError::WorkerPanicked), EXIT_NOT_FOUND);
+        let pattern = IgnoreError::InvalidPattern {
+            pattern: s(),
+            reason: s(),
+        };
+        assert_eq!(code(pattern), EXIT_CONFIG);
+        assert_eq!(code(url::ParseError::RelativeUrlWithoutBase), EXIT_CONFIG);
+        let manifest = ManifestError::Corrupt {
+            path: s(),
+            message: s(),
+        };
+        assert_eq!(code(manifest), EXIT_FILESYSTEM);
+        assert_eq!(code(PacmanError::InitFailed { message: s() }), EXIT_FILESYSTEM);
+        assert_eq!(code(PacmanError::RegisterFailed { repo: s() }), EXIT_FILESYSTEM);
+    }
+
+    #[test]
+    fn test_download_error_exit_codes() {
+        let remote = DownloadError::GitRemoteFailed {
+            command: s(),
+            message: s(),
+        };
+        assert_eq!(code(remote), EXIT_NETWORK);
+        let local = DownloadError::GitFailed {
+            command: s(),
+            message: s(),
+        };
+        assert_eq!(code(local), EXIT_FILESYSTEM);
+        assert_eq!(code(DownloadError::AlreadyExists { path: s() }), EXIT_FILESYSTEM);
+        assert_eq!(code(DownloadError::TooLarge { limit: s() }), EXIT_FILESYSTEM);
+    }
+
+    #[test]
+    fn test_exit_status() {
+        assert_eq!(exit_status(&[]), EXIT_SUCCESS);
+        let errors = vec![
+            Error::from(CowerError::NotFound { target: s() }),
+            Error::from(AurTErrors::Offline { url: s() }),
+        ];
+        assert_eq!(exit_status(&errors), EXIT_NETWORK);
+
+        // Anything unknown still counts as a failure
+        assert_eq!(exit_code(&format_err!("unexpected")), EXIT_NOT_FOUND);
+        let io = std::io::Error::new(std::io::ErrorKind::Other, "disk full");
+        assert_eq!(code(io), EXIT_FILESYSTEM);
+        assert_eq!(code(curl::Error::new(1)), EXIT_NETWORK);
+    }
+
+    #[test]
+    fn test_completions_include_package_names() {
+        for &shell in &[Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let mut script = Vec::new();
+            build_cli("1.0").gen_completions_to("cower", shell, &mut script);
+            let script = String::from_utf8(script).unwrap();
+            assert!(!script.contains("2>/dev/null"));
+            let hook = package_names_hook(shell);
+            assert_eq!(hook.matches("2>/dev/null").count(), 1, "{:?}", shell);
+        }
+
+        // Targets aren't offered where a value or subcommand goes
+        let hook = package_names_hook(Shell::Bash);
+        assert!(hook.contains("-t|--target|--threads"));
+        assert!(hook.contains("completions|manpage|sync-db"));
+    }
+
+    #[test]
+    fn test_man_pages_cover_definitions() {
+        let page = cower_man_page();
+        for &(_, flag) in OPERATION_FLAGS {
+            let long = flag.rsplit('/').next().unwrap();
+            assert!(page.contains(&bold(long)), "{}", long);
+        }
+        for switch in operation_switches().iter().chain(&option_switches()) {
+            let long = format!("--{}", switch.name);
+            assert!(page.contains(&bold(&long)), "{}", long);
+        }
+        assert!(page.contains(&bold("--help")));
+        assert!(page.contains("sort results in ascending order by key. One of: name, version,"));
+        for &(name, _) in COMMANDS {
+            assert!(page.contains(&bold(name)), "{}", name);
+        }
+        for &(spec, _) in SPECIFIERS {
+            assert!(page.contains(&bold(&format!("%{}", spec))));
+        }
+        for code in EXIT_SUCCESS..=EXIT_FILESYSTEM {
+            assert!(page.contains(&format!("\\fB{}\\fR", code)));
+        }
+        assert!(!page.contains("complete-names"));
+        assert!(page.contains(&cower_rs::man::escape(key_help("Domain"))));
+
+        let page = config_man_page();
+        for key in CONFIG_KEYS {
+            assert!(page.contains(&bold(key.name)), "{}", key.name);
+        }
+        assert!(page.contains("connection timeout in seconds (default: 10)"));
+        assert!(page.contains("tarball, or git to clone package repositories (default: tarball)"));
+        assert!(!page.contains("(default: false)"));
+    }
+
+    #[test]
+    fn test_operation_pairs() {
+        let ops: Vec<OpMask> = OPERATION_FLAGS.iter().map(|&(op, _)| op).collect();
+        assert_eq!(ops.iter().fold(OpMask::empty(), |all, &op| all | op), OpMask::all());
+
+        for &op in &ops {
+            assert!(check_combinations(op, &[]).is_ok());
+        }
+        for (i, &first) in ops.iter().enumerate() {
+            for &second in &ops[i + 1..] {
+                let result = check_combinations(first | second, &[]);
+                if first | second == OpMask::UPDATE | OpMask::DOWNLOAD {
+                    assert!(result.is_ok());
+                } else {
+                    assert!(result.is_err(), "{:?} with {:?}", first, second);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_operation_error_names_flags() {
+        let err = check_combinations(OpMask::INFO | OpMask::GRAPH, &[]).unwrap_err();
+        assert_eq!(err.to_string(), "-i/--info can't be combined with --graph");
+        let err = check_combinations(OpMask::UPDATE | OpMask::DOWNLOAD | OpMask::SEARCH, &[]);
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "-s/--search can't be combined with -d/--download"
+        );
+    }
+
+    #[test]
+    fn test_option_combinations() {
+        assert!(check_combinations(OpMask::SEARCH, &["by", "literal"]).is_ok());
+        assert!(check_combinations(OpMask::UPDATE | OpMask::DOWNLOAD, &["force", "devel"]).is_ok());
+        assert!(check_combinations(OpMask::GRAPH, &["provider"]).is_ok());
+        // Nothing to check against with just --print-config
+        assert!(check_combinations(OpMask::empty(), &["force"]).is_ok());
+
+        let err = check_combinations(OpMask::INFO, &["by"]).unwrap_err();
+        assert_eq!(err.to_string(), "--by only works with -s/--search");
+        let err = check_combinations(OpMask::UPDATE, &["force"]).unwrap_err();
+        assert_eq!(err.to_string(), "-f/--force only works with -d/--download");
+        let err = check_combinations(OpMask::SEARCH, &["target"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "-t/--target only works with -d/--download, --status"
+        );
+    }
 }
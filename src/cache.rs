@@ -0,0 +1,268 @@
+use dirs;
+use failure::Error;
+use serde_json;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// Writes started by this process, to give each one its own temporary file
+static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+/// A cached RPC response
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry {
+    url: String,
+    fetched: u64,
+    body: String,
+}
+
+/// An on-disk cache of RPC responses, keyed by normalised URL.
+///
+/// Entries are only served while younger than the TTL. Every entry lives
+/// in its own file, so single packages can be invalidated or refreshed
+/// without touching the rest of the cache.
+#[derive(Debug)]
+pub struct Cache {
+    dir: PathBuf,
+    ttl: Duration,
+    refresh: bool,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf, ttl: Duration) -> Self {
+        Cache {
+            dir,
+            ttl,
+            refresh: false,
+        }
+    }
+
+    /// `$XDG_CACHE_HOME/cower`, falling back to `~/.cache/cower`
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("cower"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Ignore existing entries, but still store fresh responses
+    pub fn set_refresh(&mut self, refresh: bool) {
+        self.refresh = refresh;
+    }
+
+    /// Get the body cached for `url`, unless it is missing, stale or
+    /// unreadable
+    pub fn get(&self, url: &Url) -> Option<String> {
+        if self.refresh {
+            return None;
+        }
+
+        let entry = self.read(url)?;
+        let age = now().saturating_sub(entry.fetched);
+        if age > self.ttl.as_secs() {
+            trace!("cached {} is stale, {}s old", entry.url, age);
+            return None;
+        }
+        Some(entry.body)
+    }
+
+    /// Get the body cached for `url` however old it is, for when an
+    /// outdated answer beats going to the network
+    pub fn get_stale(&self, url: &Url) -> Option<String> {
+        self.read(url).map(|entry| entry.body)
+    }
+
+    fn read(&self, url: &Url) -> Option<Entry> {
+        let key = normalize_url(url);
+        let data = fs::read_to_string(self.path_for(&key)).ok()?;
+        let entry: Entry = serde_json::from_str(&data).ok()?;
+
+        // Guard against hash collisions
+        if entry.url != key {
+            trace!("cache collision between {} and {}", key, entry.url);
+            return None;
+        }
+        Some(entry)
+    }
+
+    /// Store `body` as the response for `url`
+    pub fn put(&self, url: &Url, body: &str) -> Result<(), Error> {
+        let key = normalize_url(url);
+        let entry = Entry {
+            url: key.clone(),
+            fetched: now(),
+            body: body.to_string(),
+        };
+
+        fs::create_dir_all(&self.dir)?;
+
+        // Write to a temporary file first, so that concurrent readers
+        // never see a half written entry. Threads of one process may write
+        // the same entry at once, so the name is unique to this write.
+        let path = self.path_for(&key);
+        let write = WRITES.fetch_add(1, Ordering::SeqCst);
+        let tmp = path.with_extension(format!("tmp.{}.{}", process::id(), write));
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(serde_json::to_string(&entry)?.as_bytes())?;
+        }
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Drop the entry for `url`, if there is one
+    pub fn invalidate(&self, url: &Url) -> Result<(), Error> {
+        let path = self.path_for(&normalize_url(url));
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn path_for(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}.json", fnv1a(key.as_bytes())))
+    }
+}
+
+/// Normalise `url` so that equivalent queries share a cache entry: the
+/// host is lowercased and the query pairs are sorted.
+pub fn normalize_url(url: &Url) -> String {
+    let mut pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+    pairs.sort();
+
+    let mut normalized = url.clone();
+    normalized.set_fragment(None);
+    if let Some(host) = url.host_str() {
+        let _ = normalized.set_host(Some(&host.to_lowercase()));
+    }
+    normalized.set_query(None);
+    if !pairs.is_empty() {
+        normalized.query_pairs_mut().extend_pairs(pairs);
+    }
+    String::from(normalized.as_str())
+}
+
+/// 64-bit FNV-1a, stable across runs and compiler versions
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use tempdir::TempDir;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_normalize_url() {
+        assert_eq!(
+            normalize_url(&url(
+                "https://AUR.archlinux.org/rpc.php?v=5&type=info&arg[]=b&arg[]=a"
+            )),
+            normalize_url(&url(
+                "https://aur.archlinux.org/rpc.php?arg[]=a&type=info&arg[]=b&v=5#top"
+            ))
+        );
+        assert_ne!(
+            normalize_url(&url("https://aur.archlinux.org/rpc.php?arg=a")),
+            normalize_url(&url("https://aur.archlinux.org/rpc.php?arg=b"))
+        );
+    }
+
+    #[test]
+    fn test_put_and_get() {
+        let dir = TempDir::new("cower_test_cache").unwrap();
+        let cache = Cache::new(dir.path().join("cower"), Duration::from_secs(60));
+        let key = url("https://aur.archlinux.org/rpc.php?v=5&type=info&arg[]=cower");
+
+        assert_eq!(cache.get(&key), None);
+        cache.put(&key, "{}").unwrap();
+        assert_eq!(cache.get(&key), Some("{}".to_string()));
+
+        cache.invalidate(&key).unwrap();
+        assert_eq!(cache.get(&key), None);
+        cache.invalidate(&key).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_puts() {
+        let dir = TempDir::new("cower_test_cache").unwrap();
+        let cache = Arc::new(Cache::new(dir.path().join("cower"), Duration::from_secs(60)));
+        let key = url("https://aur.archlinux.org/rpc.php?v=5&type=info&arg[]=cower");
+
+        // Big enough that the writes overlap
+        let body = "x".repeat(1 << 16);
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let (cache, key, body) = (cache.clone(), key.clone(), body.clone());
+                thread::spawn(move || (0..100).all(|_| cache.put(&key, &body).is_ok()))
+            }).collect();
+        for writer in writers {
+            assert!(writer.join().unwrap());
+        }
+        assert_eq!(cache.get(&key), Some(body));
+    }
+
+    #[test]
+    fn test_stale_entries() {
+        let dir = TempDir::new("cower_test_cache").unwrap();
+        let cache = Cache::new(dir.path().to_path_buf(), Duration::from_secs(60));
+        let key = url("https://aur.archlinux.org/rpc.php?v=5&type=info&arg[]=cower");
+
+        let entry = Entry {
+            url: normalize_url(&key),
+            fetched: now() - 120,
+            body: "{}".to_string(),
+        };
+        fs::write(
+            cache.path_for(&entry.url),
+            serde_json::to_string(&entry).unwrap(),
+        ).unwrap();
+        assert_eq!(cache.get(&key), None);
+        assert_eq!(cache.get_stale(&key), Some("{}".to_string()));
+    }
+
+    #[test]
+    fn test_refresh_skips_reads() {
+        let dir = TempDir::new("cower_test_cache").unwrap();
+        let mut cache = Cache::new(dir.path().to_path_buf(), Duration::from_secs(60));
+        let key = url("https://aur.archlinux.org/rpc.php?v=5&type=info&arg[]=cower");
+
+        cache.put(&key, "old").unwrap();
+        cache.set_refresh(true);
+        assert_eq!(cache.get(&key), None);
+        cache.put(&key, "new").unwrap();
+
+        cache.set_refresh(false);
+        assert_eq!(cache.get(&key), Some("new".to_string()));
+    }
+
+    #[test]
+    fn test_corrupt_entry_is_a_miss() {
+        let dir = TempDir::new("cower_test_cache").unwrap();
+        let cache = Cache::new(dir.path().to_path_buf(), Duration::from_secs(60));
+        let key = url("https://aur.archlinux.org/rpc.php?v=5&type=info&arg[]=cower");
+
+        fs::write(cache.path_for(&normalize_url(&key)), "garbage").unwrap();
+        assert_eq!(cache.get(&key), None);
+    }
+}
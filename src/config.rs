@@ -1,35 +1,238 @@
+use deps::GraphFormat;
+use download::DownloadMethod;
 use failure::Error;
 use isatty::stdout_isatty;
 use log::Level;
 use std::cmp;
-use std::fs::File;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SearchBy {
     Name,
     NameDesc,
     Maintainer,
+    Keywords,
 }
 
 #[derive(Debug, Fail)]
 pub enum ConfigError {
-    #[fail(display = "TargetDir path not absolue: {}", path)]
-    TargetDirNotAbsolute { path: String },
     #[fail(display = "TargetDir not valid directory: {}", path)]
     TargetDirNotDir { path: String },
+    #[fail(display = "TargetDir not writable: {}", path)]
+    TargetDirNotWritable { path: String },
+    #[fail(display = "Unable to create TargetDir {}: {}", path, reason)]
+    TargetDirCreateFailed { path: String, reason: String },
+    #[fail(display = "Undefined variable ${} in path: {}", var, path)]
+    UndefinedPathVariable { var: String, path: String },
+    #[fail(display = "Unterminated ${{ in path: {}", path)]
+    UnterminatedPathVariable { path: String },
     #[fail(display = "Invalid MaxThreads Argument: {}", val)]
     InvalidMaxThreadArg { val: String },
     #[fail(display = "Invalid ConnectTimeout Argument: {}", val)]
     InvalidConnectTimeoutArg { val: String },
+    #[fail(display = "Invalid MaxRetries Argument: {}", val)]
+    InvalidMaxRetriesArg { val: String },
+    #[fail(display = "Invalid RateLimit Argument: {}", val)]
+    InvalidRateLimitArg { val: String },
+    #[fail(display = "Invalid CacheTTL Argument: {}", val)]
+    InvalidCacheTTLArg { val: String },
+    #[fail(display = "Invalid DownloadMethod Argument: {}", val)]
+    InvalidDownloadMethodArg { val: String },
+    #[fail(display = "Invalid provider rule, expected name=package: {}", val)]
+    InvalidProviderArg { val: String },
     #[fail(display = "Invalid Color Argument: {}", val)]
     InvalidColorArg { val: String },
     #[fail(display = "Invalid option for 'by': {}", val)]
     InvalidSearchByArg { val: String },
     #[fail(display = "Invalid option for 'sort by': {}", val)]
     InvalidSortByArg { val: String },
+    #[fail(display = "{}:{}: {}", file, line, message)]
+    ParseError {
+        file: String,
+        line: usize,
+        message: String,
+    },
+    #[fail(display = "{}: {}", var, message)]
+    EnvironmentError { var: String, message: String },
+}
+
+/// Where the effective value of a configuration field came from. Layers
+/// are applied in declaration order, later ones overriding earlier ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigLayer {
+    Default,
+    System,
+    User,
+    Project,
+    Environment,
+    CommandLine,
+}
+
+impl fmt::Display for ConfigLayer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ConfigLayer::Default => "default",
+            ConfigLayer::System => "system",
+            ConfigLayer::User => "user",
+            ConfigLayer::Project => "project",
+            ConfigLayer::Environment => "environment",
+            ConfigLayer::CommandLine => "command line",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A key understood by the config file parser
+#[derive(Debug)]
+pub struct ConfigKey {
+    pub name: &'static str,
+    /// The `Config` field it sets
+    pub field: &'static str,
+    /// What it does, shared with the matching command line option
+    pub help: &'static str,
+}
+
+/// Every key understood by the config file parser. Each one may also be
+/// set from the environment as `COWER_<KEY>`, e.g. `COWER_IGNOREPKG`.
+pub const CONFIG_KEYS: &[ConfigKey] = &[
+    ConfigKey {
+        name: "CacheTTL",
+        field: "cache_ttl",
+        help: "seconds cached RPC responses stay fresh",
+    },
+    ConfigKey {
+        name: "Color",
+        field: "color",
+        help: "use colored output: never, always or auto",
+    },
+    ConfigKey {
+        name: "ConfirmDiffs",
+        field: "confirm_diffs",
+        help: "ask before replacing packages whose build files changed",
+    },
+    ConfigKey {
+        name: "ConnectTimeout",
+        field: "timeout",
+        help: "connection timeout in seconds",
+    },
+    ConfigKey {
+        name: "CreateTargetDir",
+        field: "create_working_dir",
+        help: "create TargetDir if it doesn't exist",
+    },
+    ConfigKey {
+        name: "Devel",
+        field: "devel",
+        help: "consider VCS packages (-git, -svn, -hg, -bzr) to always have updates",
+    },
+    ConfigKey {
+        name: "Domain",
+        field: "aur_domain",
+        help: "point cower at a different AUR",
+    },
+    ConfigKey {
+        name: "DownloadMethod",
+        field: "download_method",
+        help: "tarball, or git to clone package repositories",
+    },
+    ConfigKey {
+        name: "Format",
+        field: "format",
+        help: "print package output according to format string",
+    },
+    ConfigKey {
+        name: "IgnoreGroup",
+        field: "ignore_groups",
+        help: "groups whose upgrades are ignored",
+    },
+    ConfigKey {
+        name: "IgnoreOOD",
+        field: "ignore_ood",
+        help: "leave out packages flagged out of date",
+    },
+    ConfigKey {
+        name: "IgnorePkg",
+        field: "ignore_pkgs",
+        help: "packages whose upgrades are ignored",
+    },
+    ConfigKey {
+        name: "IgnoreRepo",
+        field: "ignore_repos",
+        help: "binary repos ignored when looking for foreign packages",
+    },
+    ConfigKey {
+        name: "ListDelim",
+        field: "delim",
+        help: "change the delimiter between list items",
+    },
+    ConfigKey {
+        name: "Literal",
+        field: "literal",
+        help: "disable regex search, interpret target as a literal string",
+    },
+    ConfigKey {
+        name: "MaxRetries",
+        field: "retries",
+        help: "times a transient network failure is retried",
+    },
+    ConfigKey {
+        name: "MaxThreads",
+        field: "maxthreads",
+        help: "limit number of threads created",
+    },
+    ConfigKey {
+        name: "NoCache",
+        field: "no_cache",
+        help: "don't read or write the RPC response cache",
+    },
+    ConfigKey {
+        name: "Offline",
+        field: "offline",
+        help: "answer queries from the database fetched by sync-db",
+    },
+    ConfigKey {
+        name: "Provider",
+        field: "provider_rules",
+        help: "name=pkg rules for which package satisfies a dependency",
+    },
+    ConfigKey {
+        name: "RateLimit",
+        field: "ratelimit",
+        help: "most RPC requests per second, 0 for no limit",
+    },
+    ConfigKey {
+        name: "SortBy",
+        field: "sort_func",
+        help: "sort key for search results",
+    },
+    ConfigKey {
+        name: "Strict",
+        field: "strict",
+        help: "refuse to download when packages conflict",
+    },
+    ConfigKey {
+        name: "TargetDir",
+        field: "working_dir",
+        help: "specify an alternate download directory",
+    },
+];
+
+/// Older names still accepted for some keys
+const KEY_ALIASES: &[(&str, &str)] = &[("Threads", "MaxThreads"), ("Timeout", "ConnectTimeout")];
+
+/// The key called `name`, or that `name` is an alias of
+pub fn config_key(name: &str) -> Option<&'static ConfigKey> {
+    let name = KEY_ALIASES
+        .iter()
+        .find(|&&(alias, _)| alias == name)
+        .map_or(name, |&(_, key)| key);
+    CONFIG_KEYS.iter().find(|key| key.name == name)
 }
 
 bitflags! {
@@ -39,19 +242,31 @@ bitflags! {
         const INFO     = 1 << 1;
         const DOWNLOAD = 1 << 2;
         const UPDATE   = 1 << 3;
+        const STATUS   = 1 << 4;
+        const GRAPH    = 1 << 5;
+        const ORDER    = 1 << 6;
     }
 }
 
+#[derive(Debug)]
 pub enum SortOrder {
     Forward,
     Reverse,
 }
 
+/// Types that can be sorted by a named key, e.g. `votes`
+pub trait Sortable: Sized {
+    fn sort_func(key: &str) -> Option<fn(&Self, &Self) -> cmp::Ordering>;
+}
+
 pub struct Config<T> {
     pub aur_domain: String,
     pub search_by: SearchBy,
 
     pub working_dir: PathBuf,
+    pub create_working_dir: bool,
+    pub download_method: DownloadMethod,
+    pub graph_format: GraphFormat,
     pub delim: String,
     pub format: String,
 
@@ -61,28 +276,53 @@ pub struct Config<T> {
     pub color: bool,
     pub sortorder: SortOrder,
     pub sort_func: fn(&T, &T) -> cmp::Ordering,
+    pub sort_key: String,
     pub force: bool,
+    pub confirm_diffs: bool,
+    /// Refuse to download a set with conflicting packages in it
+    pub strict: bool,
+    /// Treat VCS packages as always having an update
+    pub devel: bool,
     pub getdeps: bool,
+    pub resume: bool,
+    pub ignore_ood: bool,
     pub literal: bool,
+    pub fuzzy: bool,
     pub quiet: bool,
     pub skiprepos: bool,
     pub srcinfo: bool,
     pub maxthreads: u64,
     pub timeout: u64,
+    pub retries: u32,
+    pub ratelimit: u64,
+    pub cache_ttl: u64,
+    pub no_cache: bool,
+    pub refresh: bool,
+    pub offline: bool,
 
     pub ignore_pkgs: Vec<String>,
+    pub ignore_groups: Vec<String>,
     pub ignore_repos: Vec<String>,
+    /// Which package settles a virtual dependency, by name
+    pub provider_rules: HashMap<String, String>,
 
     pub args: Vec<String>,
+
+    /// The layer currently being applied
+    pub layer: ConfigLayer,
+    origins: HashMap<&'static str, ConfigLayer>,
 }
 
-impl<T> Config<T> {
+impl<T: Sortable> Config<T> {
     pub fn new(func: fn(&T, &T) -> cmp::Ordering) -> Self {
         Config {
             aur_domain: String::from("aur.archlinux.org"),
             search_by: SearchBy::NameDesc,
 
             working_dir: PathBuf::new(),
+            create_working_dir: false,
+            download_method: DownloadMethod::Tarball,
+            graph_format: GraphFormat::Dot,
             delim: String::from("  "),
             format: String::new(),
 
@@ -92,84 +332,310 @@ impl<T> Config<T> {
             color: false,
             sortorder: SortOrder::Forward,
             sort_func: func,
+            sort_key: String::from("name"),
             force: false,
+            confirm_diffs: false,
+            strict: false,
+            devel: false,
             getdeps: false,
+            resume: false,
+            ignore_ood: false,
             literal: false,
+            fuzzy: false,
             quiet: false,
             skiprepos: false,
             srcinfo: false,
             maxthreads: 10,
             timeout: 10,
+            retries: 3,
+            ratelimit: 10,
+            cache_ttl: 1800,
+            no_cache: false,
+            refresh: false,
+            offline: false,
 
             ignore_pkgs: Vec::new(),
+            ignore_groups: Vec::new(),
             ignore_repos: Vec::new(),
+            provider_rules: HashMap::new(),
 
             args: Vec::new(),
+
+            layer: ConfigLayer::Default,
+            origins: HashMap::new(),
         }
     }
 
+    /// Record that `field` was set by the layer currently being applied
+    pub fn mark(&mut self, field: &'static str) {
+        self.origins.insert(field, self.layer);
+    }
+
+    /// The layer that last set `field`
+    pub fn origin(&self, field: &str) -> ConfigLayer {
+        self.origins
+            .get(field)
+            .cloned()
+            .unwrap_or(ConfigLayer::Default)
+    }
+
+    /// Apply `COWER_<KEY>` overrides for every key in `CONFIG_KEYS` or
+    /// its aliases. The full name of a key wins over an alias.
+    pub fn parse_environment<I>(&mut self, vars: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut settings = Vec::new();
+        for (var, val) in vars {
+            if !var.starts_with("COWER_") {
+                continue;
+            }
+
+            // Silently skip variables that aren't config keys, like
+            // the ones used by tests or wrapper scripts
+            match env_key(&var["COWER_".len()..]) {
+                Some((key, alias)) => settings.push((alias, key, var, val)),
+                None => continue,
+            }
+        }
+        // Aliases go first, so the full names override them
+        settings.sort_by_key(|setting| !setting.0);
+
+        for (_, key, var, val) in settings {
+            // An empty value means the same as a bare boolean key
+            let val = val.trim();
+            let val = if val.is_empty() { None } else { Some(val) };
+            self.set_option(key.name, val, &env::current_dir()?)
+                .map_err(|message| ConfigError::EnvironmentError { var, message })?;
+        }
+        Ok(())
+    }
+
+    /// Every field with its effective value and the layer that set it
+    pub fn dump(&self) -> Vec<(&'static str, String, ConfigLayer)> {
+        let fields = vec![
+            ("aur_domain", self.aur_domain.clone()),
+            ("search_by", format!("{:?}", self.search_by)),
+            ("working_dir", self.working_dir.display().to_string()),
+            ("create_working_dir", self.create_working_dir.to_string()),
+            ("download_method", format!("{:?}", self.download_method)),
+            ("graph_format", format!("{:?}", self.graph_format)),
+            ("delim", format!("{:?}", self.delim)),
+            ("format", format!("{:?}", self.format)),
+            ("opmask", format!("{:?}", self.opmask)),
+            ("loglevel", self.loglevel.to_string()),
+            ("color", self.color.to_string()),
+            ("sortorder", format!("{:?}", self.sortorder)),
+            ("sort_func", self.sort_key.clone()),
+            ("force", self.force.to_string()),
+            ("confirm_diffs", self.confirm_diffs.to_string()),
+            ("strict", self.strict.to_string()),
+            ("devel", self.devel.to_string()),
+            ("getdeps", self.getdeps.to_string()),
+            ("resume", self.resume.to_string()),
+            ("ignore_ood", self.ignore_ood.to_string()),
+            ("literal", self.literal.to_string()),
+            ("fuzzy", self.fuzzy.to_string()),
+            ("quiet", self.quiet.to_string()),
+            ("skiprepos", self.skiprepos.to_string()),
+            ("srcinfo", self.srcinfo.to_string()),
+            ("maxthreads", self.maxthreads.to_string()),
+            ("timeout", self.timeout.to_string()),
+            ("retries", self.retries.to_string()),
+            ("ratelimit", self.ratelimit.to_string()),
+            ("cache_ttl", self.cache_ttl.to_string()),
+            ("no_cache", self.no_cache.to_string()),
+            ("refresh", self.refresh.to_string()),
+            ("offline", self.offline.to_string()),
+            ("ignore_pkgs", self.ignore_pkgs.join(" ")),
+            ("ignore_groups", self.ignore_groups.join(" ")),
+            ("ignore_repos", self.ignore_repos.join(" ")),
+            ("provider_rules", {
+                let mut rules: Vec<String> = self
+                    .provider_rules
+                    .iter()
+                    .map(|(name, provider)| format!("{}={}", name, provider))
+                    .collect();
+                rules.sort();
+                rules.join(" ")
+            }),
+            ("args", self.args.join(" ")),
+        ];
+
+        fields
+            .into_iter()
+            .map(|(field, val)| (field, val, self.origin(field)))
+            .collect()
+    }
+
+    /// The effective value of `field`, as shown by `dump`
+    pub fn value(&self, field: &str) -> Option<String> {
+        self.dump()
+            .into_iter()
+            .find(|&(name, _, _)| name == field)
+            .map(|(_, val, _)| val)
+    }
+
     /// Parse the config file
     pub fn parse_config_files(&mut self, path_buf: &PathBuf) -> Result<(), Error> {
-        if let Ok(file) = File::open(path_buf.as_path()) {
-            let reader = BufReader::new(file);
-            for line in reader.lines() {
-                let line = String::from(line.unwrap().trim());
-
-                // If the line is empty or a comment, then move on to
-                // the next line
-                if line.is_empty() {
-                    continue;
-                }
-                if let Some('#') = line.chars().next() {
-                    continue;
-                }
+        let file = File::open(path_buf.as_path()).map_err(|e| ConfigError::ParseError {
+            file: path_buf.display().to_string(),
+            line: 0,
+            message: e.to_string(),
+        })?;
+        self.parse_config(file, &path_buf.display().to_string())
+    }
+
+    /// Parse configuration from any reader. `name` prefixes error
+    /// messages and relative paths are resolved against its directory.
+    pub fn parse_config<R>(&mut self, reader: R, name: &str) -> Result<(), Error>
+    where
+        R: Read,
+    {
+        let reader = BufReader::new(reader);
+        let base = env::current_dir()?.join(Path::new(name).parent().unwrap_or(Path::new("")));
+
+        for (lineno, line) in reader.lines().enumerate() {
+            let lineno = lineno + 1;
+            let err = |message: String| ConfigError::ParseError {
+                file: name.to_string(),
+                line: lineno,
+                message,
+            };
+
+            let line = line.map_err(|e| err(e.to_string()))?;
+            let line = line.trim();
 
-                // Get args
-                let line: Vec<&str> = line.split('=').collect();
-                if line.len() == 2 {
-                    let key = line[0].trim();
-                    let val = line[1].trim();
-
-                    // Match against possible configuration options
-                    match key {
-                        "IgnoreRepo" => self.ignore_repos.push(String::from(val)),
-                        "IgnorePkg" => self.ignore_pkgs.push(String::from(val)),
-                        "TargetDir" => {
-                            // Must be an absolute path to a directory
-                            self.working_dir.push(val);
-                            if !self.working_dir.is_dir() {
-                                return Err(Error::from(ConfigError::TargetDirNotDir {
-                                    path: val.to_string(),
-                                }));
-                            } else if !self.working_dir.is_absolute() {
-                                return Err(Error::from(ConfigError::TargetDirNotAbsolute {
-                                    path: val.to_string(),
-                                }));
-                            }
-                        }
-                        "MaxThreads" => match val.parse() {
-                            Ok(val) => self.maxthreads = val,
-                            Err(_) => {
-                                return Err(Error::from(ConfigError::InvalidMaxThreadArg {
-                                    val: val.to_string(),
-                                }));
-                            }
-                        },
-                        "ConnectTimeout" => match val.parse() {
-                            Ok(val) => self.timeout = val,
-                            Err(_) => {
-                                return Err(Error::from(ConfigError::InvalidConnectTimeoutArg {
-                                    val: val.to_string(),
-                                }));
-                            }
-                        },
-                        "Color" => {
-                            self.set_color(val)?;
-                        }
-                        _ => eprintln!("ignoring unkkown option: {}", key),
+            // If the line is empty or a comment, then move on to
+            // the next line
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            // Section headers. Only [options] is understood, anything
+            // else is most likely a typo.
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(Error::from(err(format!(
+                        "malformed section header: {}",
+                        line
+                    ))));
+                }
+                match line[1..line.len() - 1].trim() {
+                    "options" => continue,
+                    section => {
+                        return Err(Error::from(err(format!("unknown section: [{}]", section))))
                     }
                 }
             }
+
+            // Only split on the first '=' so values may contain one
+            let (key, val) = match line.find('=') {
+                Some(idx) => (line[..idx].trim(), Some(line[idx + 1..].trim())),
+                None => (line, None),
+            };
+
+            self.set_option(key, val, &base).map_err(err)?;
+        }
+        Ok(())
+    }
+
+    /// Apply a single `key = val` pair from a config file. Errors are
+    /// returned as plain messages so the caller can prefix file and line.
+    fn set_option(&mut self, key: &str, val: Option<&str>, base: &Path) -> Result<(), String> {
+        self.apply_option(key, val, base)?;
+        if let Some(field) = field_for_key(key) {
+            self.mark(field);
+        }
+        Ok(())
+    }
+
+    fn apply_option(&mut self, key: &str, val: Option<&str>, base: &Path) -> Result<(), String> {
+        // Boolean keys may be given without a value
+        match key {
+            "ConfirmDiffs" => {
+                self.confirm_diffs = parse_bool(key, val)?;
+                return Ok(());
+            }
+            "CreateTargetDir" => {
+                self.create_working_dir = parse_bool(key, val)?;
+                return Ok(());
+            }
+            "Devel" => {
+                self.devel = parse_bool(key, val)?;
+                return Ok(());
+            }
+            "IgnoreOOD" => {
+                self.ignore_ood = parse_bool(key, val)?;
+                return Ok(());
+            }
+            "Literal" => {
+                self.literal = parse_bool(key, val)?;
+                return Ok(());
+            }
+            "NoCache" => {
+                self.no_cache = parse_bool(key, val)?;
+                return Ok(());
+            }
+            "Offline" => {
+                self.offline = parse_bool(key, val)?;
+                return Ok(());
+            }
+            "Strict" => {
+                self.strict = parse_bool(key, val)?;
+                return Ok(());
+            }
+            _ => (),
+        }
+
+        let val = match val {
+            Some(val) if !val.is_empty() => val,
+            _ => {
+                return if is_known_option(key) {
+                    Err(format!("option '{}' requires a value", key))
+                } else {
+                    Err(format!("unknown option: {}", key))
+                };
+            }
+        };
+
+        // Match against possible configuration options
+        match key {
+            "IgnoreRepo" => self.ignore_repos.append(&mut split_values(val)?),
+            "IgnorePkg" => self.ignore_pkgs.append(&mut split_values(val)?),
+            "IgnoreGroup" => self.ignore_groups.append(&mut split_values(val)?),
+            "TargetDir" => self
+                .set_working_dir(&unquote(val)?, base)
+                .map_err(|e| e.to_string())?,
+            "MaxThreads" | "Threads" => self
+                .set_maxthreads(&unquote(val)?)
+                .map_err(|e| e.to_string())?,
+            "ConnectTimeout" | "Timeout" => self
+                .set_timeout(&unquote(val)?)
+                .map_err(|e| e.to_string())?,
+            "MaxRetries" => self
+                .set_retries(&unquote(val)?)
+                .map_err(|e| e.to_string())?,
+            "RateLimit" => self
+                .set_ratelimit(&unquote(val)?)
+                .map_err(|e| e.to_string())?,
+            "CacheTTL" => self
+                .set_cache_ttl(&unquote(val)?)
+                .map_err(|e| e.to_string())?,
+            "Color" => self.set_color(&unquote(val)?).map_err(|e| e.to_string())?,
+            "Domain" => self.aur_domain = unquote(val)?,
+            "DownloadMethod" => self
+                .set_download_method(&unquote(val)?)
+                .map_err(|e| e.to_string())?,
+            "Provider" => self
+                .add_provider_rules(&unquote(val)?)
+                .map_err(|e| e.to_string())?,
+            "SortBy" => self
+                .set_sort_by(&unquote(val)?)
+                .map_err(|e| e.to_string())?,
+            "Format" => self.format = unquote(val)?,
+            "ListDelim" => self.delim = unquote(val)?,
+            _ => return Err(format!("unknown option: {}", key)),
         }
         Ok(())
     }
@@ -190,6 +656,40 @@ impl<T> Config<T> {
         Ok(())
     }
 
+    pub fn set_download_method(&mut self, method: &str) -> Result<(), Error> {
+        let method = method.trim();
+
+        match method {
+            "git" => self.download_method = DownloadMethod::Git,
+            "tarball" => self.download_method = DownloadMethod::Tarball,
+            _ => {
+                return Err(Error::from(ConfigError::InvalidDownloadMethodArg {
+                    val: method.to_string(),
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Add `name=package` rules, separated by whitespace
+    pub fn add_provider_rules(&mut self, rules: &str) -> Result<(), Error> {
+        for rule in rules.split_whitespace() {
+            let mut parts = rule.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(provider)) if !name.is_empty() && !provider.is_empty() => {
+                    self.provider_rules
+                        .insert(name.to_string(), provider.to_string());
+                }
+                _ => {
+                    return Err(Error::from(ConfigError::InvalidProviderArg {
+                        val: rule.to_string(),
+                    }))
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn set_search_by(&mut self, by: &str) -> Result<(), Error> {
         let by = by.trim();
 
@@ -197,6 +697,7 @@ impl<T> Config<T> {
             "maintainer" => self.search_by = SearchBy::Maintainer,
             "name-desc" => self.search_by = SearchBy::NameDesc,
             "name" => self.search_by = SearchBy::Name,
+            "keywords" => self.search_by = SearchBy::Keywords,
             _ => {
                 return Err(Error::from(ConfigError::InvalidSearchByArg {
                     val: by.to_string(),
@@ -205,4 +706,587 @@ impl<T> Config<T> {
         }
         Ok(())
     }
+
+    pub fn set_sort_by(&mut self, key: &str) -> Result<(), Error> {
+        let key = key.trim();
+
+        match T::sort_func(key) {
+            Some(func) => {
+                self.sort_func = func;
+                self.sort_key = key.to_string();
+            }
+            None => {
+                return Err(Error::from(ConfigError::InvalidSortByArg {
+                    val: key.to_string(),
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_maxthreads(&mut self, threads: &str) -> Result<(), Error> {
+        let threads = threads.trim();
+
+        match threads.parse() {
+            Ok(val) if val > 0 => self.maxthreads = val,
+            _ => {
+                return Err(Error::from(ConfigError::InvalidMaxThreadArg {
+                    val: threads.to_string(),
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_timeout(&mut self, timeout: &str) -> Result<(), Error> {
+        let timeout = timeout.trim();
+
+        match timeout.parse() {
+            Ok(val) => self.timeout = val,
+            Err(_) => {
+                return Err(Error::from(ConfigError::InvalidConnectTimeoutArg {
+                    val: timeout.to_string(),
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_retries(&mut self, retries: &str) -> Result<(), Error> {
+        let retries = retries.trim();
+
+        match retries.parse() {
+            Ok(val) => self.retries = val,
+            Err(_) => {
+                return Err(Error::from(ConfigError::InvalidMaxRetriesArg {
+                    val: retries.to_string(),
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the maximum number of requests per second, 0 for no limit
+    pub fn set_ratelimit(&mut self, ratelimit: &str) -> Result<(), Error> {
+        let ratelimit = ratelimit.trim();
+
+        match ratelimit.parse() {
+            Ok(val) => self.ratelimit = val,
+            Err(_) => {
+                return Err(Error::from(ConfigError::InvalidRateLimitArg {
+                    val: ratelimit.to_string(),
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Set how many seconds cached RPC responses stay fresh
+    pub fn set_cache_ttl(&mut self, ttl: &str) -> Result<(), Error> {
+        let ttl = ttl.trim();
+
+        match ttl.parse() {
+            Ok(val) => self.cache_ttl = val,
+            Err(_) => {
+                return Err(Error::from(ConfigError::InvalidCacheTTLArg {
+                    val: ttl.to_string(),
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the download directory. `~` and environment variables are
+    /// expanded and relative paths are resolved against `base`. The
+    /// directory itself is only checked by `prepare_working_dir`, once
+    /// every layer has been applied.
+    pub fn set_working_dir(&mut self, path: &str, base: &Path) -> Result<(), Error> {
+        self.working_dir = normalize(&base.join(expand_path(path)?));
+        Ok(())
+    }
+
+    /// Make sure the download directory exists and is writable, creating
+    /// it first if allowed to. An unset directory means the current one.
+    pub fn prepare_working_dir(&mut self) -> Result<(), Error> {
+        if self.working_dir.as_os_str().is_empty() {
+            self.working_dir = env::current_dir()?;
+        }
+        let path = self.working_dir.display().to_string();
+
+        if !self.working_dir.exists() && self.create_working_dir {
+            fs::create_dir_all(&self.working_dir).map_err(|e| {
+                ConfigError::TargetDirCreateFailed {
+                    path: path.clone(),
+                    reason: e.to_string(),
+                }
+            })?;
+        }
+
+        if !self.working_dir.is_dir() {
+            return Err(Error::from(ConfigError::TargetDirNotDir { path }));
+        }
+
+        // Permission bits don't tell the whole story (read-only mounts,
+        // ACLs), so actually try to write something
+        let probe = self
+            .working_dir
+            .join(format!(".cower-write-test.{}", ::std::process::id()));
+        match OpenOptions::new().write(true).create_new(true).open(&probe) {
+            Ok(_) => {
+                fs::remove_file(&probe)?;
+                Ok(())
+            }
+            Err(_) => Err(Error::from(ConfigError::TargetDirNotWritable { path })),
+        }
+    }
+}
+
+/// Every key understood by the config file parser, including aliases
+fn is_known_option(key: &str) -> bool {
+    field_for_key(key).is_some()
+}
+
+/// The key set by `COWER_<name>`, and whether `name` is an alias
+fn env_key(name: &str) -> Option<(&'static ConfigKey, bool)> {
+    if let Some(key) = CONFIG_KEYS.iter().find(|key| key.name.to_uppercase() == name) {
+        return Some((key, false));
+    }
+    KEY_ALIASES
+        .iter()
+        .find(|&&(alias, _)| alias.to_uppercase() == name)
+        .and_then(|&(_, key)| config_key(key))
+        .map(|key| (key, true))
+}
+
+/// The `Config` field set by a config file key
+fn field_for_key(key: &str) -> Option<&'static str> {
+    config_key(key).map(|key| key.field)
+}
+
+/// Expand a leading `~` and any `$VAR` or `${VAR}` in `path`
+fn expand_path(path: &str) -> Result<PathBuf, ConfigError> {
+    expand_path_with(path, |var| env::var(var).ok())
+}
+
+/// `expand_path`, looking variables up with `lookup`
+fn expand_path_with<F>(path: &str, lookup: F) -> Result<PathBuf, ConfigError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut expanded = String::new();
+    let mut rest = path;
+
+    if rest == "~" || rest.starts_with("~/") {
+        if let Some(home) = dirs::home_dir() {
+            expanded.push_str(&home.display().to_string());
+            rest = &rest[1..];
+        }
+    }
+
+    while let Some(idx) = rest.find('$') {
+        expanded.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+
+        let (var, len) = if rest.starts_with('{') {
+            match rest.find('}') {
+                Some(end) => (&rest[1..end], end + 1),
+                None => {
+                    return Err(ConfigError::UnterminatedPathVariable {
+                        path: path.to_string(),
+                    })
+                }
+            }
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or_else(|| rest.len());
+            (&rest[..end], end)
+        };
+
+        // A lone '$' isn't a variable
+        if var.is_empty() && len == 0 {
+            expanded.push('$');
+            continue;
+        }
+
+        match lookup(var) {
+            Some(val) => expanded.push_str(&val),
+            None => {
+                return Err(ConfigError::UndefinedPathVariable {
+                    var: var.to_string(),
+                    path: path.to_string(),
+                })
+            }
+        }
+        rest = &rest[len..];
+    }
+    expanded.push_str(rest);
+
+    Ok(PathBuf::from(expanded))
+}
+
+/// Lexically remove `.` and `..` components from an absolute path
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c.as_os_str()),
+        }
+    }
+    normalized
+}
+
+/// Parse the value of a boolean key. A missing value means `true`.
+fn parse_bool(key: &str, val: Option<&str>) -> Result<bool, String> {
+    let val = match val {
+        Some(val) => unquote(val)?,
+        None => String::new(),
+    };
+
+    match val.as_str() {
+        "" | "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" => Ok(false),
+        val => Err(format!("invalid boolean for '{}': {}", key, val)),
+    }
+}
+
+/// Strip a single pair of matching quotes from a value, if present
+fn unquote(val: &str) -> Result<String, String> {
+    let mut values = split_quoted(val, false)?;
+    match values.len() {
+        0 => Ok(String::new()),
+        1 => Ok(values.remove(0)),
+        _ => unreachable!(),
+    }
+}
+
+/// Split a whitespace separated list of values, honouring quotes
+fn split_values(val: &str) -> Result<Vec<String>, String> {
+    split_quoted(val, true)
+}
+
+/// Tokenize `val`. Single and double quotes group characters together
+/// and are removed from the output. Whitespace only separates tokens
+/// when `split` is true.
+fn split_quoted(val: &str, split: bool) -> Result<Vec<String>, String> {
+    let mut values = Vec::new();
+    let mut current = String::new();
+    let mut in_token = false;
+    let mut quote = None;
+
+    for c in val.trim().chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => current.push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                in_token = true;
+            }
+            None if split && c.is_whitespace() => {
+                if in_token {
+                    values.push(current.clone());
+                    current.clear();
+                    in_token = false;
+                }
+            }
+            None => {
+                current.push(c);
+                in_token = true;
+            }
+        }
+    }
+
+    if let Some(q) = quote {
+        return Err(format!("unterminated quote ({}) in value: {}", q, val));
+    }
+    if in_token {
+        values.push(current);
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    struct Pkg {
+        votes: u64,
+    }
+
+    fn sort_votes(a: &Pkg, b: &Pkg) -> cmp::Ordering {
+        a.votes.cmp(&b.votes)
+    }
+
+    impl Sortable for Pkg {
+        fn sort_func(key: &str) -> Option<fn(&Self, &Self) -> cmp::Ordering> {
+            match key {
+                "votes" => Some(sort_votes),
+                _ => None,
+            }
+        }
+    }
+
+    fn parse(input: &str) -> Result<Config<Pkg>, Error> {
+        let mut config = Config::new(sort_votes);
+        config.parse_config(input.as_bytes(), "test.conf")?;
+        Ok(config)
+    }
+
+    #[test]
+    fn test_parse_all_options() {
+        let config = parse(
+            r#"
+# A comment
+[options]
+IgnoreRepo = core extra
+IgnorePkg = "foo bar" baz
+IgnorePkg = qux
+IgnoreGroup = kde-*
+Threads = 4
+Timeout = 30
+MaxRetries = 5
+RateLimit = 0
+CacheTTL = 600
+NoCache
+Offline = yes
+ConfirmDiffs
+Strict = true
+Devel = no
+DownloadMethod = git
+Provider = java-environment=jdk-openjdk
+Provider = "sh=bash"
+Color = never
+Domain = aur.example.org
+SortBy = votes
+Format = "%n=%v"
+ListDelim = ', '
+IgnoreOOD
+Literal = no
+"#,
+        ).unwrap();
+
+        assert_eq!(config.ignore_repos, vec!["core", "extra"]);
+        assert_eq!(config.ignore_pkgs, vec!["foo bar", "baz", "qux"]);
+        assert_eq!(config.ignore_groups, vec!["kde-*"]);
+        assert_eq!(config.maxthreads, 4);
+        assert_eq!(config.timeout, 30);
+        assert_eq!(config.retries, 5);
+        assert_eq!(config.ratelimit, 0);
+        assert_eq!(config.cache_ttl, 600);
+        assert!(config.no_cache);
+        assert!(config.offline);
+        assert!(config.confirm_diffs);
+        assert!(config.strict);
+        assert!(!config.devel);
+        assert_eq!(config.download_method, DownloadMethod::Git);
+        assert_eq!(config.provider_rules["java-environment"], "jdk-openjdk");
+        assert_eq!(config.provider_rules["sh"], "bash");
+        assert!(!config.color);
+        assert_eq!(config.aur_domain, "aur.example.org");
+        assert_eq!(config.format, "%n=%v");
+        assert_eq!(config.delim, ", ");
+        assert!(config.ignore_ood);
+        assert!(!config.literal);
+    }
+
+    #[test]
+    fn test_parse_value_with_equals() {
+        let config = parse("Format = %n=%v").unwrap();
+        assert_eq!(config.format, "%n=%v");
+    }
+
+    #[test]
+    fn test_parse_legacy_keys_without_section() {
+        let config = parse("MaxThreads = 2\nConnectTimeout = 5").unwrap();
+        assert_eq!(config.maxthreads, 2);
+        assert_eq!(config.timeout, 5);
+    }
+
+    #[test]
+    fn test_parse_unknown_option_reports_line() {
+        let err = parse("[options]\n\nIgnorPkg = foo").err().unwrap();
+        assert_eq!(err.to_string(), "test.conf:3: unknown option: IgnorPkg");
+    }
+
+    #[test]
+    fn test_parse_unknown_section() {
+        let err = parse("[core]\nThreads = 1").err().unwrap();
+        assert_eq!(err.to_string(), "test.conf:1: unknown section: [core]");
+    }
+
+    #[test]
+    fn test_parse_missing_value() {
+        let err = parse("Domain =").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "test.conf:1: option 'Domain' requires a value"
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_values() {
+        assert!(parse("Threads = lots").is_err());
+        assert!(parse("Threads = 0").is_err());
+        assert!(parse("MaxRetries = -1").is_err());
+        assert!(parse("RateLimit = fast").is_err());
+        assert!(parse("CacheTTL = 1h").is_err());
+        assert!(parse("DownloadMethod = svn").is_err());
+        assert!(parse("Provider = java-environment").is_err());
+        assert!(parse("SortBy = size").is_err());
+        assert!(parse("Color = sometimes").is_err());
+        assert!(parse("Literal = maybe").is_err());
+        assert!(parse("Format = \"%n").is_err());
+    }
+
+    #[test]
+    fn test_layers_record_origin() {
+        let mut config = parse("Threads = 4\nDomain = aur.example.org").unwrap();
+        assert_eq!(config.origin("maxthreads"), ConfigLayer::Default);
+
+        config.layer = ConfigLayer::User;
+        config
+            .parse_config("Threads = 2".as_bytes(), "user.conf")
+            .unwrap();
+
+        config.layer = ConfigLayer::Environment;
+        config
+            .parse_environment(vec![
+                ("COWER_TIMEOUT".to_string(), "3".to_string()),
+                ("COWER_CONNECTTIMEOUT".to_string(), "7".to_string()),
+                ("COWER_IGNOREOOD".to_string(), String::new()),
+                ("COWER_NOT_A_KEY".to_string(), "x".to_string()),
+                ("PATH".to_string(), "/usr/bin".to_string()),
+            ]).unwrap();
+
+        assert_eq!(config.maxthreads, 2);
+        assert_eq!(config.origin("maxthreads"), ConfigLayer::User);
+        assert_eq!(config.timeout, 7);
+        assert_eq!(config.origin("timeout"), ConfigLayer::Environment);
+        assert!(config.ignore_ood);
+        assert_eq!(config.origin("ignore_ood"), ConfigLayer::Environment);
+        assert_eq!(config.origin("aur_domain"), ConfigLayer::Default);
+
+        let dump = config.dump();
+        assert!(dump.contains(&(
+            "maxthreads",
+            "2".to_string(),
+            ConfigLayer::User
+        )));
+    }
+
+    #[test]
+    fn test_environment_aliases() {
+        let mut config = parse("").unwrap();
+        config
+            .parse_environment(vec![
+                ("COWER_CONNECTTIMEOUT".to_string(), "7".to_string()),
+                ("COWER_TIMEOUT".to_string(), "3".to_string()),
+                ("COWER_THREADS".to_string(), "5".to_string()),
+            ]).unwrap();
+        assert_eq!(config.timeout, 7);
+        assert_eq!(config.maxthreads, 5);
+
+        let err = config
+            .parse_environment(vec![("COWER_THREADS".to_string(), "x".to_string())])
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "COWER_THREADS: Invalid MaxThreads Argument: x");
+    }
+
+    #[test]
+    fn test_environment_error_names_variable() {
+        let mut config = parse("").unwrap();
+        let err = config
+            .parse_environment(vec![("COWER_MAXTHREADS".to_string(), "x".to_string())])
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "COWER_MAXTHREADS: Invalid MaxThreads Argument: x"
+        );
+    }
+
+    #[test]
+    fn test_config_keys_are_known() {
+        // The man page is generated from CONFIG_KEYS, so it has to match
+        // what the parser accepts, and the other way round
+        let config = parse("").unwrap();
+        for key in CONFIG_KEYS {
+            assert!(is_known_option(key.name), "{}", key.name);
+            assert!(config.value(key.field).is_some(), "{}", key.field);
+        }
+        for &(alias, key) in KEY_ALIASES {
+            assert_eq!(config_key(alias).unwrap().name, key);
+        }
+        assert!(!is_known_option("TargetDirectory"));
+    }
+
+    #[test]
+    fn test_target_dir_relative_to_config_file() {
+        let mut config = parse("").unwrap();
+        config
+            .parse_config("TargetDir = ../build".as_bytes(), "/etc/cower/cower.conf")
+            .unwrap();
+        assert_eq!(config.working_dir, PathBuf::from("/etc/build"));
+
+        config
+            .set_working_dir("/srv/./aur", Path::new("/ignored"))
+            .unwrap();
+        assert_eq!(config.working_dir, PathBuf::from("/srv/aur"));
+    }
+
+    #[test]
+    fn test_expand_path() {
+        // Other tests run alongside, so leave the real environment alone
+        let lookup = |var: &str| match var {
+            "COWER_TEST_EXPAND" => Some(String::from("/tmp/cower")),
+            _ => None,
+        };
+        assert_eq!(
+            expand_path_with("$COWER_TEST_EXPAND/a/${COWER_TEST_EXPAND}", lookup).unwrap(),
+            PathBuf::from("/tmp/cower/a//tmp/cower")
+        );
+        assert_eq!(expand_path_with("a$", lookup).unwrap(), PathBuf::from("a$"));
+        assert!(expand_path_with("$COWER_TEST_UNSET_VARIABLE/x", lookup).is_err());
+        assert_eq!(
+            expand_path_with("${COWER_TEST_EXPAND/x", lookup).err().unwrap().to_string(),
+            "Unterminated ${ in path: ${COWER_TEST_EXPAND/x"
+        );
+
+        if let Some(home) = dirs::home_dir() {
+            assert_eq!(expand_path("~/aur").unwrap(), home.join("aur"));
+        }
+        assert_eq!(expand_path("a~b").unwrap(), PathBuf::from("a~b"));
+    }
+
+    #[test]
+    fn test_prepare_working_dir() {
+        let dir = TempDir::new("cower_test_target").unwrap();
+        let target = dir.path().join("nested/target");
+
+        let mut config = parse("").unwrap();
+        config
+            .set_working_dir(&target.display().to_string(), dir.path())
+            .unwrap();
+        assert!(config.prepare_working_dir().is_err());
+
+        config.create_working_dir = true;
+        assert!(config.prepare_working_dir().is_ok());
+        assert!(target.is_dir());
+        assert_eq!(fs::read_dir(&target).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_split_values() {
+        assert_eq!(
+            split_values(r#"a  'b c' "d'e" f"#).unwrap(),
+            vec!["a", "b c", "d'e", "f"]
+        );
+        assert!(split_values("a 'b").is_err());
+    }
 }
@@ -0,0 +1,223 @@
+use deps::{Depend, Repos};
+use package::AurPkg;
+use std::fmt;
+
+/// How one package rules out another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+    Conflicts,
+    Replaces,
+}
+
+impl ConflictKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConflictKind::Conflicts => "conflicts with",
+            ConflictKind::Replaces => "replaces",
+        }
+    }
+}
+
+/// Two packages that can't be installed side by side
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    /// The package whose conflicts or replaces matched
+    pub package: String,
+    /// The package it matched
+    pub with: String,
+    pub kind: ConflictKind,
+    /// The entry as written, with any version constraint
+    pub depend: String,
+    /// Whether one of the two is installed rather than in the set
+    pub installed: bool,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.package, self.kind.as_str(), self.with)?;
+        if self.depend != self.with {
+            write!(f, " ({})", self.depend)?;
+        }
+        if self.installed {
+            write!(f, " [installed]")?;
+        }
+        Ok(())
+    }
+}
+
+/// Every conflict among `pkgs`, and between them and the installed
+/// packages in `repos`. Installed packages that are part of the set are
+/// about to be upgraded, so only the new version is checked.
+pub fn find_conflicts<R: Repos + ?Sized>(pkgs: &[&AurPkg], repos: &R) -> Vec<Conflict> {
+    let in_set = |name: &str| pkgs.iter().any(|pkg| pkg.name() == name);
+    let mut conflicts = Vec::new();
+
+    for pkg in pkgs {
+        for (kind, entry) in declared(pkg) {
+            let depend = Depend::parse(entry);
+
+            for other in pkgs {
+                if other.name() != pkg.name()
+                    && depend.satisfied_by(other.name(), other.version(), other.provides())
+                {
+                    conflicts.push(Conflict {
+                        package: pkg.name().to_string(),
+                        with: other.name().to_string(),
+                        kind,
+                        depend: entry.clone(),
+                        installed: false,
+                    });
+                }
+            }
+
+            if let Some(installed) = repos.installed(entry) {
+                if installed != pkg.name() && !in_set(&installed) {
+                    conflicts.push(Conflict {
+                        package: pkg.name().to_string(),
+                        with: installed,
+                        kind,
+                        depend: entry.clone(),
+                        installed: true,
+                    });
+                }
+            }
+        }
+
+        // Installed packages can rule out the new ones too
+        for (installed, entry) in repos.conflicting(pkg.name(), pkg.version(), pkg.provides()) {
+            if installed != pkg.name() && !in_set(&installed) {
+                conflicts.push(Conflict {
+                    package: installed,
+                    with: pkg.name().to_string(),
+                    kind: ConflictKind::Conflicts,
+                    depend: entry,
+                    installed: true,
+                });
+            }
+        }
+    }
+    conflicts
+}
+
+/// The conflicts and replaces of `pkg`, with the list they came from
+fn declared(pkg: &AurPkg) -> Vec<(ConflictKind, &String)> {
+    let conflicts = pkg.conflicts().iter().map(|c| (ConflictKind::Conflicts, c));
+    let replaces = pkg.replaces().iter().map(|r| (ConflictKind::Replaces, r));
+    conflicts.chain(replaces).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use package::aur_packages_from_json;
+
+    /// An AUR package at `version` with the given relations
+    fn pkg(name: &str, version: &str, conflicts: &[&str], provides: &[&str], replaces: &[&str]) -> AurPkg {
+        let list = |deps: &[&str]| {
+            let quoted: Vec<String> = deps.iter().map(|d| format!("\"{}\"", d)).collect();
+            format!("[{}]", quoted.join(","))
+        };
+        let json = format!(
+            r#"{{"version":5,"type":"multiinfo","resultcount":1,"results":[{{
+                "ID":1,"Name":"{}","PackageBaseID":1,"PackageBase":"{}","Version":"{}",
+                "Description":"","URL":"","NumVotes":0,"Popularity":0,"OutOfDate":null,
+                "Maintainer":null,"FirstSubmitted":0,"LastModified":0,"URLPath":"",
+                "Conflicts":{},"Provides":{},"Replaces":{}}}]}}"#,
+            name,
+            name,
+            version,
+            list(conflicts),
+            list(provides),
+            list(replaces)
+        );
+        aur_packages_from_json(&json).unwrap().remove(0)
+    }
+
+    /// Installed packages as (name, version, conflicts)
+    struct Installed(Vec<(&'static str, &'static str, Vec<&'static str>)>);
+
+    impl Repos for Installed {
+        fn satisfier(&self, _depend: &str) -> Option<String> {
+            None
+        }
+
+        fn installed(&self, depend: &str) -> Option<String> {
+            let depend = Depend::parse(depend);
+            self.0
+                .iter()
+                .find(|p| depend.satisfied_by(p.0, p.1, &[]))
+                .map(|p| p.0.to_string())
+        }
+
+        fn conflicting(&self, name: &str, version: &str, provides: &[String]) -> Vec<(String, String)> {
+            let mut found = Vec::new();
+            for &(installed, _, ref conflicts) in &self.0 {
+                for entry in conflicts {
+                    if Depend::parse(entry).satisfied_by(name, version, provides) {
+                        found.push((installed.to_string(), entry.to_string()));
+                    }
+                }
+            }
+            found
+        }
+    }
+
+    #[test]
+    fn test_conflicts_within_set() {
+        let git = pkg("auracle-git", "2", &["auracle"], &["auracle=2"], &[]);
+        let release = pkg("auracle", "1", &[], &[], &[]);
+        let other = pkg("cower", "1", &["auracle<1"], &[], &["cower-git"]);
+        let pkgs = vec![&git, &release, &other];
+
+        let conflicts = find_conflicts(&pkgs, &Installed(vec![]));
+        assert_eq!(
+            conflicts,
+            vec![Conflict {
+                package: "auracle-git".to_string(),
+                with: "auracle".to_string(),
+                kind: ConflictKind::Conflicts,
+                depend: "auracle".to_string(),
+                installed: false,
+            }]
+        );
+        assert_eq!(conflicts[0].to_string(), "auracle-git conflicts with auracle");
+    }
+
+    #[test]
+    fn test_conflicts_with_installed() {
+        let git = pkg("pacman-git", "3", &["pacman"], &["pacman=3"], &[]);
+        let tool = pkg("pacutils", "1", &[], &[], &["pacutils-old"]);
+        let installed = Installed(vec![
+            ("pacman", "2", vec![]),
+            ("pacutils-old", "1", vec![]),
+            ("pacman-contrib-git", "1", vec!["pacutils"]),
+        ]);
+
+        let conflicts = find_conflicts(&[&git, &tool], &installed);
+        let report: Vec<String> = conflicts.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            report,
+            vec![
+                "pacman-git conflicts with pacman [installed]",
+                "pacutils replaces pacutils-old [installed]",
+                "pacman-contrib-git conflicts with pacutils [installed]",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_upgrades_are_not_conflicts() {
+        // The installed pacman-git is replaced by the new one, and the
+        // installed pacman is being upgraded along with it
+        let git = pkg("pacman-git", "3", &["pacman"], &["pacman=3"], &[]);
+        let release = pkg("pacman", "3", &[], &[], &[]);
+        let installed = Installed(vec![("pacman-git", "2", vec!["pacman"])]);
+
+        let conflicts = find_conflicts(&[&git], &installed);
+        assert!(conflicts.is_empty());
+        let conflicts = find_conflicts(&[&release], &installed);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].package, "pacman-git");
+        assert_eq!(find_conflicts(&[&git, &release], &installed).len(), 1);
+    }
+}
@@ -0,0 +1,1262 @@
+use failure::Error;
+use package::{vercmp, AurPkg};
+use serde_json;
+use std::cmp::{self, Ordering};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+#[derive(Debug, Fail)]
+pub enum DepsError {
+    #[fail(display = "Invalid graph format: {}", format)]
+    InvalidGraphFormat { format: String },
+    #[fail(display = "Unable to order the build, dependency cycle: {}", pkgbases)]
+    Cycle { pkgbases: String },
+    #[fail(display = "Provider rule for {} names {}, which doesn't provide it", name, provider)]
+    BadProviderRule { name: String, provider: String },
+}
+
+/// A version constraint on a dependency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Constraint {
+    Lt,
+    Le,
+    Eq,
+    Ge,
+    Gt,
+}
+
+impl Constraint {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Constraint::Lt => "<",
+            Constraint::Le => "<=",
+            Constraint::Eq => "=",
+            Constraint::Ge => ">=",
+            Constraint::Gt => ">",
+        }
+    }
+
+    /// Whether a version comparing as `ordering` to the wanted one
+    /// meets the constraint
+    pub fn allows(self, ordering: Ordering) -> bool {
+        match self {
+            Constraint::Lt => ordering == Ordering::Less,
+            Constraint::Le => ordering != Ordering::Greater,
+            Constraint::Eq => ordering == Ordering::Equal,
+            Constraint::Ge => ordering != Ordering::Less,
+            Constraint::Gt => ordering == Ordering::Greater,
+        }
+    }
+}
+
+/// A dependency like `pacman>=5.1`, as found in depends, provides and
+/// conflicts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Depend {
+    pub name: String,
+    pub constraint: Option<(Constraint, String)>,
+}
+
+impl Depend {
+    pub fn parse(dep: &str) -> Self {
+        let split = match dep.find(&['<', '>', '='][..]) {
+            Some(idx) => idx,
+            None => {
+                return Depend {
+                    name: dep.to_string(),
+                    constraint: None,
+                }
+            }
+        };
+
+        let (name, rest) = dep.split_at(split);
+        let (constraint, version) = if rest.starts_with("<=") {
+            (Constraint::Le, &rest[2..])
+        } else if rest.starts_with(">=") {
+            (Constraint::Ge, &rest[2..])
+        } else if rest.starts_with('<') {
+            (Constraint::Lt, &rest[1..])
+        } else if rest.starts_with('>') {
+            (Constraint::Gt, &rest[1..])
+        } else {
+            (Constraint::Eq, &rest[1..])
+        };
+
+        Depend {
+            name: name.to_string(),
+            constraint: Some((constraint, version.to_string())),
+        }
+    }
+
+    /// Whether `version` meets the constraint, if there is one
+    pub fn allows(&self, version: &str) -> bool {
+        match self.constraint {
+            Some((constraint, ref wanted)) => constraint.allows(vercmp(version, wanted)),
+            None => true,
+        }
+    }
+
+    /// Whether package `name` at `version`, providing `provides`,
+    /// satisfies the dependency. Like pacman, a versioned dependency is
+    /// only met by a provide that carries a version.
+    pub fn satisfied_by(&self, name: &str, version: &str, provides: &[String]) -> bool {
+        if name == self.name && self.allows(version) {
+            return true;
+        }
+
+        provides.iter().map(|p| Depend::parse(p)).any(|provide| {
+            provide.name == self.name && match (&self.constraint, &provide.constraint) {
+                (&None, _) => true,
+                (&Some(_), &Some((Constraint::Eq, ref version))) => self.allows(version),
+                _ => false,
+            }
+        })
+    }
+}
+
+impl fmt::Display for Depend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.constraint {
+            Some((constraint, ref version)) => {
+                write!(f, "{}{}{}", self.name, constraint.as_str(), version)
+            }
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Which list a dependency came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DepKind {
+    Depends,
+    MakeDepends,
+    CheckDepends,
+}
+
+impl DepKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DepKind::Depends => "depends",
+            DepKind::MakeDepends => "makedepends",
+            DepKind::CheckDepends => "checkdepends",
+        }
+    }
+}
+
+/// Where a dependency is satisfied from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Aur,
+    Repo,
+    Installed,
+    Missing,
+}
+
+impl Source {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Source::Aur => "aur",
+            Source::Repo => "repo",
+            Source::Installed => "installed",
+            Source::Missing => "missing",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub name: String,
+    pub source: Source,
+    /// The package itself, for AUR nodes named after a package
+    pub pkg: Option<AurPkg>,
+    /// The package that satisfies the name, when it isn't named after
+    /// one or comes from the repos
+    pub satisfier: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+    pub kind: DepKind,
+    /// The dependency as written, with any version constraint
+    pub depend: String,
+}
+
+/// Output formats for `--graph`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    Dot,
+    Json,
+}
+
+impl FromStr for GraphFormat {
+    type Err = DepsError;
+
+    fn from_str(s: &str) -> Result<Self, DepsError> {
+        match s {
+            "dot" => Ok(GraphFormat::Dot),
+            "json" => Ok(GraphFormat::Json),
+            _ => Err(DepsError::InvalidGraphFormat {
+                format: s.to_string(),
+            }),
+        }
+    }
+}
+
+/// Sync repositories to check dependencies against before the AUR
+pub trait Repos {
+    /// The name of the repo package that satisfies `depend` by name
+    fn satisfier(&self, depend: &str) -> Option<String>;
+
+    /// Repo packages that satisfy `depend` through their provides
+    fn providers(&self, _depend: &str) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// The name of the installed package that satisfies `depend`
+    fn installed(&self, _depend: &str) -> Option<String> {
+        None
+    }
+
+    /// Installed packages whose conflicts rule out package `name` at
+    /// `version`, with the entry that matched
+    fn conflicting(&self, _name: &str, _version: &str, _provides: &[String]) -> Vec<(String, String)> {
+        Vec::new()
+    }
+}
+
+/// Settles dependencies on names that no package has, like `sh` or
+/// `java-environment`
+pub trait Providers {
+    /// AUR packages that provide `name`
+    fn search(&mut self, name: &str) -> Result<Vec<AurPkg>, Error>;
+
+    /// Which of `candidates` to use for `depend`, when there is more
+    /// than one and no rule for it
+    fn choose(&mut self, _depend: &str, _candidates: &[String]) -> usize {
+        0
+    }
+}
+
+/// Only consider providers from the repos, and take the first one
+pub struct RepoProviders;
+
+impl Providers for RepoProviders {
+    fn search(&mut self, _name: &str) -> Result<Vec<AurPkg>, Error> {
+        Ok(Vec::new())
+    }
+}
+
+/// How a dependency without a package of its own was settled
+enum Provided {
+    Installed(String),
+    Repo(String),
+    Aur(AurPkg),
+}
+
+/// The dependency graph of a set of AUR targets
+#[derive(Debug, Default)]
+pub struct Graph {
+    targets: Vec<String>,
+    nodes: BTreeMap<String, Node>,
+    edges: Vec<Edge>,
+}
+
+/// Resolve the dependencies of `targets`, recursing through the AUR.
+/// Dependencies found in `repos` are not followed further. `lookup`
+/// fetches AUR packages by name, one batch per level of the graph.
+pub fn resolve<R, F>(targets: Vec<AurPkg>, repos: &R, lookup: F) -> Result<Graph, Error>
+where
+    R: Repos + ?Sized,
+    F: FnMut(&[String]) -> Result<Vec<AurPkg>, Error>,
+{
+    resolve_with(targets, repos, lookup, &mut RepoProviders, &HashMap::new())
+}
+
+/// Like `resolve`, but dependencies that no package is named after are
+/// settled through `providers`. An installed provider is used first,
+/// then one named in `rules`, then a repo one, then one from the AUR.
+pub fn resolve_with<R, F, P>(
+    targets: Vec<AurPkg>,
+    repos: &R,
+    mut lookup: F,
+    providers: &mut P,
+    rules: &HashMap<String, String>,
+) -> Result<Graph, Error>
+where
+    R: Repos + ?Sized,
+    F: FnMut(&[String]) -> Result<Vec<AurPkg>, Error>,
+    P: Providers + ?Sized,
+{
+    let started = Instant::now();
+    let mut graph = Graph::default();
+    for pkg in &targets {
+        graph.targets.push(pkg.name().to_string());
+        graph.add_aur(pkg.clone());
+    }
+
+    let mut frontier = targets;
+    while !frontier.is_empty() {
+        // Names to look for in the AUR, with every dependency that wants them
+        let mut wanted: Vec<(String, Vec<String>)> = Vec::new();
+        for pkg in &frontier {
+            for (kind, depend) in dependencies(pkg) {
+                let name = Depend::parse(depend).name;
+                graph.edges.push(Edge {
+                    from: pkg.name().to_string(),
+                    to: name.clone(),
+                    kind,
+                    depend: depend.clone(),
+                });
+
+                if graph.nodes.contains_key(&name) {
+                    continue;
+                }
+                if let Some(w) = wanted.iter_mut().find(|w| w.0 == name) {
+                    w.1.push(depend.clone());
+                    continue;
+                }
+                match repos.satisfier(depend) {
+                    Some(satisfier) => {
+                        debug!("{} is satisfied by {} from the repos", depend, satisfier);
+                        graph.add(name, Source::Repo, None, Some(satisfier))
+                    }
+                    None => wanted.push((name, vec![depend.clone()])),
+                }
+            }
+        }
+
+        if wanted.is_empty() {
+            break;
+        }
+
+        let names: Vec<String> = wanted.iter().map(|w| w.0.clone()).collect();
+        debug!("looking up {} in the AUR", names.join(" "));
+        let mut found: HashMap<String, AurPkg> = lookup(&names)?
+            .into_iter()
+            .map(|pkg| (pkg.name().to_string(), pkg))
+            .collect();
+
+        frontier = Vec::new();
+        for (name, depends) in wanted {
+            // The package has to meet every constraint on its name
+            let mut depend = &depends[0];
+            if let Some(pkg) = found.remove(&name) {
+                match depends.iter().find(|d| !Depend::parse(d).allows(pkg.version())) {
+                    Some(unmet) => {
+                        debug!("{} {} doesn't satisfy {}", pkg.name(), pkg.version(), unmet);
+                        depend = unmet;
+                    }
+                    None => {
+                        graph.add_aur(pkg.clone());
+                        frontier.push(pkg);
+                        continue;
+                    }
+                }
+            }
+
+            match find_provider(&name, depend, repos, providers, rules)? {
+                Some(Provided::Installed(satisfier)) => {
+                    graph.add(name, Source::Installed, None, Some(satisfier))
+                }
+                Some(Provided::Repo(satisfier)) => {
+                    graph.add(name, Source::Repo, None, Some(satisfier))
+                }
+                Some(Provided::Aur(pkg)) => {
+                    graph.add(name, Source::Aur, None, Some(pkg.name().to_string()));
+                    if !graph.nodes.contains_key(pkg.name()) {
+                        graph.add_aur(pkg.clone());
+                        frontier.push(pkg);
+                    }
+                }
+                None => {
+                    debug!("nothing satisfies {}", depend);
+                    graph.add(name, Source::Missing, None, None)
+                }
+            }
+        }
+    }
+    debug!(
+        "resolved {} nodes for {} targets in {:?}",
+        graph.nodes.len(),
+        graph.targets.len(),
+        started.elapsed()
+    );
+    Ok(graph)
+}
+
+/// Settle `depend`, which no package is named after
+fn find_provider<R, P>(
+    name: &str,
+    depend: &str,
+    repos: &R,
+    providers: &mut P,
+    rules: &HashMap<String, String>,
+) -> Result<Option<Provided>, Error>
+where
+    R: Repos + ?Sized,
+    P: Providers + ?Sized,
+{
+    if let Some(installed) = repos.installed(depend) {
+        debug!("{} is provided by installed {}", depend, installed);
+        return Ok(Some(Provided::Installed(installed)));
+    }
+
+    let wanted = Depend::parse(depend);
+    let in_repos = repos.providers(depend);
+    let rule = rules.get(name);
+
+    // Only ask the AUR when the repos can't help, or a rule may point there
+    let in_aur: Vec<AurPkg> = if in_repos.is_empty() || rule.is_some() {
+        providers
+            .search(name)?
+            .into_iter()
+            .filter(|pkg| wanted.satisfied_by(pkg.name(), pkg.version(), pkg.provides()))
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    if let Some(rule) = rule {
+        debug!("{} is provided by {}, as configured", depend, rule);
+        if in_repos.contains(rule) {
+            return Ok(Some(Provided::Repo(rule.clone())));
+        }
+        return match in_aur.into_iter().find(|pkg| pkg.name() == rule) {
+            Some(pkg) => Ok(Some(Provided::Aur(pkg))),
+            None => Err(Error::from(DepsError::BadProviderRule {
+                name: name.to_string(),
+                provider: rule.clone(),
+            })),
+        };
+    }
+
+    if !in_repos.is_empty() {
+        let idx = choose(providers, depend, &in_repos);
+        debug!("{} is provided by {} from the repos", depend, in_repos[idx]);
+        return Ok(Some(Provided::Repo(in_repos[idx].clone())));
+    }
+    if !in_aur.is_empty() {
+        let names: Vec<String> = in_aur.iter().map(|pkg| pkg.name().to_string()).collect();
+        let idx = choose(providers, depend, &names);
+        debug!("{} is provided by {} from the AUR", depend, names[idx]);
+        return Ok(in_aur.into_iter().nth(idx).map(Provided::Aur));
+    }
+    Ok(None)
+}
+
+/// Let `providers` pick among `candidates`, unless there's only one
+fn choose<P: Providers + ?Sized>(providers: &mut P, depend: &str, candidates: &[String]) -> usize {
+    if candidates.len() == 1 {
+        return 0;
+    }
+    cmp::min(providers.choose(depend, candidates), candidates.len() - 1)
+}
+
+/// Every dependency needed to build `pkg`, with the list it came from
+fn dependencies(pkg: &AurPkg) -> Vec<(DepKind, &String)> {
+    let lists = vec![
+        (DepKind::Depends, pkg.depends()),
+        (DepKind::MakeDepends, pkg.makedepends()),
+        (DepKind::CheckDepends, pkg.checkdepends()),
+    ];
+    lists
+        .into_iter()
+        .flat_map(|(kind, deps)| deps.iter().map(move |dep| (kind, dep)))
+        .collect()
+}
+
+impl Graph {
+    fn add(&mut self, name: String, source: Source, pkg: Option<AurPkg>, satisfier: Option<String>) {
+        let node = Node {
+            name: name.clone(),
+            source,
+            pkg,
+            satisfier,
+        };
+        self.nodes.insert(name, node);
+    }
+
+    fn add_aur(&mut self, pkg: AurPkg) {
+        self.add(pkg.name().to_string(), Source::Aur, Some(pkg), None);
+    }
+
+    /// The packages the graph was resolved for
+    pub fn targets(&self) -> &[String] {
+        &self.targets
+    }
+
+    pub fn node(&self, name: &str) -> Option<&Node> {
+        self.nodes.get(name)
+    }
+
+    /// Every node, by name
+    pub fn nodes(&self) -> Vec<&Node> {
+        self.nodes.values().collect()
+    }
+
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    /// Every AUR package in the graph, targets included
+    pub fn aur_packages(&self) -> Vec<&AurPkg> {
+        self.nodes.values().filter_map(|node| node.pkg.as_ref()).collect()
+    }
+
+    /// Virtual dependencies settled by an AUR package, with its name
+    fn aur_providers(&self) -> Vec<(&str, &str)> {
+        self.nodes
+            .values()
+            .filter(|node| node.source == Source::Aur)
+            .filter_map(|node| {
+                node.satisfier
+                    .as_ref()
+                    .map(|provider| (node.name.as_str(), provider.as_str()))
+            }).collect()
+    }
+
+    /// Names that couldn't be found anywhere, with the packages that
+    /// need them
+    pub fn missing(&self) -> Vec<(&str, Vec<&str>)> {
+        self.nodes
+            .values()
+            .filter(|node| node.source == Source::Missing)
+            .map(|node| {
+                let mut needed_by: Vec<&str> = self
+                    .edges
+                    .iter()
+                    .filter(|edge| edge.to == node.name)
+                    .map(|edge| edge.from.as_str())
+                    .collect();
+                needed_by.sort();
+                needed_by.dedup();
+                (node.name.as_str(), needed_by)
+            }).collect()
+    }
+
+    /// Groups of nodes that depend on each other, each sorted by name
+    pub fn cycles(&self) -> Vec<Vec<String>> {
+        let mut adjacency: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for name in self.nodes.keys() {
+            adjacency.insert(name, Vec::new());
+        }
+        for edge in &self.edges {
+            if let Some(next) = adjacency.get_mut(edge.from.as_str()) {
+                next.push(&edge.to);
+            }
+        }
+        for (name, provider) in self.aur_providers() {
+            if let Some(next) = adjacency.get_mut(name) {
+                next.push(provider);
+            }
+        }
+        find_cycles(&adjacency)
+    }
+
+    /// The pkgbases of every AUR package in an order they can be built
+    /// in. Each layer only depends on the ones before it, so the
+    /// pkgbases within a layer can be built in parallel.
+    pub fn build_order(&self) -> Result<Vec<Vec<String>>, DepsError> {
+        let pkgbase = |name: &str| {
+            let node = self.nodes.get(name)?;
+            let node = match (node.source, &node.satisfier) {
+                // Build the provider of a virtual dependency instead
+                (Source::Aur, &Some(ref provider)) => self.nodes.get(provider)?,
+                _ => node,
+            };
+            node.pkg.as_ref().map(|pkg| pkg.pkgbase().to_string())
+        };
+
+        // Split packages collapse onto their pkgbase, and dependencies
+        // within one pkgbase don't count
+        let mut needs: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for pkg in self.aur_packages() {
+            needs.entry(pkg.pkgbase().to_string()).or_default();
+        }
+        for edge in &self.edges {
+            if let (Some(from), Some(to)) = (pkgbase(&edge.from), pkgbase(&edge.to)) {
+                if from != to {
+                    needs.entry(from).or_default().insert(to);
+                }
+            }
+        }
+
+        let mut layers = Vec::new();
+        let mut built: BTreeSet<String> = BTreeSet::new();
+        while built.len() < needs.len() {
+            let layer: Vec<String> = needs
+                .iter()
+                .filter(|&(base, deps)| !built.contains(base) && deps.is_subset(&built))
+                .map(|(base, _)| base.clone())
+                .collect();
+
+            if layer.is_empty() {
+                return Err(DepsError::Cycle {
+                    pkgbases: pkgbase_cycles(&needs, &built),
+                });
+            }
+            built.extend(layer.iter().cloned());
+            layers.push(layer);
+        }
+        Ok(layers)
+    }
+
+    /// Render the graph for Graphviz. AUR packages are boxes, repo
+    /// packages grey ellipses and missing ones red octagons. Make and
+    /// check dependencies are dashed and dotted, and cycles are red.
+    pub fn to_dot(&self) -> String {
+        let cycles = self.cycles();
+        let in_cycle = cycle_members(&cycles);
+        let mut dot = String::from("digraph dependencies {\n    rankdir=LR;\n");
+
+        for node in self.nodes.values() {
+            let mut attrs = match (node.source, &node.pkg) {
+                (Source::Aur, &Some(ref pkg)) => vec![
+                    String::from("shape=box"),
+                    format!("label={}", quote(&format!("{}\n{}", node.name, pkg.version()))),
+                ],
+                // A virtual dependency
+                (Source::Aur, &None) => vec![String::from("shape=box"), String::from("style=dashed")],
+                (Source::Repo, _) => vec![String::from("shape=ellipse"), String::from("color=grey")],
+                (Source::Installed, _) => {
+                    vec![String::from("shape=ellipse"), String::from("color=blue")]
+                }
+                (Source::Missing, _) => vec![
+                    String::from("shape=octagon"),
+                    String::from("color=red"),
+                    String::from("style=dashed"),
+                ],
+            };
+            match (node.source, &node.satisfier) {
+                (Source::Repo, &Some(ref satisfier)) | (Source::Installed, &Some(ref satisfier))
+                    if *satisfier != node.name =>
+                {
+                    let label = format!("{}\n({})", node.name, satisfier);
+                    attrs.push(format!("label={}", quote(&label)));
+                }
+                _ => (),
+            }
+            if self.targets.contains(&node.name) {
+                attrs.push(String::from("penwidth=2"));
+            }
+            if in_cycle.contains(node.name.as_str()) {
+                attrs.push(String::from("color=red"));
+            }
+            dot.push_str(&format!("    {} [{}];\n", quote(&node.name), attrs.join(", ")));
+        }
+
+        for edge in &self.edges {
+            let mut attrs = vec![format!("label={}", quote(edge.kind.as_str()))];
+            match edge.kind {
+                DepKind::Depends => (),
+                DepKind::MakeDepends => attrs.push(String::from("style=dashed")),
+                DepKind::CheckDepends => attrs.push(String::from("style=dotted")),
+            }
+            if is_cycle_edge(edge, &cycles) {
+                attrs.push(String::from("color=red"));
+            }
+            dot.push_str(&format!(
+                "    {} -> {} [{}];\n",
+                quote(&edge.from),
+                quote(&edge.to),
+                attrs.join(", ")
+            ));
+        }
+
+        for (name, provider) in self.aur_providers() {
+            dot.push_str(&format!(
+                "    {} -> {} [label=\"provided by\", style=bold];\n",
+                quote(name),
+                quote(provider)
+            ));
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Render the graph as JSON, for tools of our own
+    pub fn to_json(&self) -> Result<String, Error> {
+        let cycles = self.cycles();
+        let in_cycle = cycle_members(&cycles);
+        let nodes: Vec<JsonNode> = self
+            .nodes
+            .values()
+            .map(|node| JsonNode {
+                name: &node.name,
+                source: node.source.as_str(),
+                target: self.targets.contains(&node.name),
+                pkgbase: node.pkg.as_ref().map(|pkg| pkg.pkgbase()),
+                version: node.pkg.as_ref().map(|pkg| pkg.version()),
+                satisfier: node.satisfier.as_ref().map(|s| s.as_str()),
+                cycle: in_cycle.contains(node.name.as_str()),
+            }).collect();
+        let edges: Vec<JsonEdge> = self
+            .edges
+            .iter()
+            .map(|edge| JsonEdge {
+                from: &edge.from,
+                to: &edge.to,
+                kind: edge.kind.as_str(),
+                depend: &edge.depend,
+                cycle: is_cycle_edge(edge, &cycles),
+            }).collect();
+
+        let graph = JsonGraph {
+            targets: &self.targets,
+            nodes,
+            edges,
+            cycles,
+        };
+        Ok(serde_json::to_string_pretty(&graph)?)
+    }
+}
+
+fn cycle_members(cycles: &[Vec<String>]) -> HashSet<&str> {
+    cycles.iter().flat_map(|cycle| cycle.iter().map(|name| name.as_str())).collect()
+}
+
+/// Whether both ends of `edge` are in the same cycle
+fn is_cycle_edge(edge: &Edge, cycles: &[Vec<String>]) -> bool {
+    edge.from == edge.to
+        || cycles
+            .iter()
+            .any(|cycle| cycle.contains(&edge.from) && cycle.contains(&edge.to))
+}
+
+#[derive(Serialize)]
+struct JsonGraph<'a> {
+    targets: &'a [String],
+    nodes: Vec<JsonNode<'a>>,
+    edges: Vec<JsonEdge<'a>>,
+    cycles: Vec<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct JsonNode<'a> {
+    name: &'a str,
+    source: &'static str,
+    target: bool,
+    pkgbase: Option<&'a str>,
+    version: Option<&'a str>,
+    satisfier: Option<&'a str>,
+    cycle: bool,
+}
+
+#[derive(Serialize)]
+struct JsonEdge<'a> {
+    from: &'a str,
+    to: &'a str,
+    kind: &'static str,
+    depend: &'a str,
+    cycle: bool,
+}
+
+/// Describe the cycles among the pkgbases that couldn't be ordered
+fn pkgbase_cycles(needs: &BTreeMap<String, BTreeSet<String>>, built: &BTreeSet<String>) -> String {
+    let adjacency: BTreeMap<&str, Vec<&str>> = needs
+        .iter()
+        .filter(|&(base, _)| !built.contains(base))
+        .map(|(base, deps)| (base.as_str(), deps.iter().map(|d| d.as_str()).collect()))
+        .collect();
+    let cycles: Vec<String> = find_cycles(&adjacency)
+        .iter()
+        .map(|cycle| cycle.join(", "))
+        .collect();
+    cycles.join("; ")
+}
+
+/// Every group of names in `adjacency` that depend on each other, each
+/// sorted by name
+fn find_cycles(adjacency: &BTreeMap<&str, Vec<&str>>) -> Vec<Vec<String>> {
+    let mut tarjan = Tarjan {
+        adjacency,
+        index: 0,
+        indices: HashMap::new(),
+        lowlinks: HashMap::new(),
+        stack: Vec::new(),
+        on_stack: HashSet::new(),
+        cycles: Vec::new(),
+    };
+    for name in adjacency.keys() {
+        if !tarjan.indices.contains_key(name) {
+            tarjan.visit(name);
+        }
+    }
+
+    let mut cycles = tarjan.cycles;
+    for cycle in &mut cycles {
+        cycle.sort();
+    }
+    cycles.sort();
+    cycles
+}
+
+/// Tarjan's strongly connected components, keeping only those that
+/// form a cycle
+struct Tarjan<'a, 'b: 'a> {
+    adjacency: &'a BTreeMap<&'b str, Vec<&'b str>>,
+    index: usize,
+    indices: HashMap<&'b str, usize>,
+    lowlinks: HashMap<&'b str, usize>,
+    stack: Vec<&'b str>,
+    on_stack: HashSet<&'b str>,
+    cycles: Vec<Vec<String>>,
+}
+
+impl<'a, 'b> Tarjan<'a, 'b> {
+    fn visit(&mut self, name: &'b str) {
+        self.indices.insert(name, self.index);
+        self.lowlinks.insert(name, self.index);
+        self.index += 1;
+        self.stack.push(name);
+        self.on_stack.insert(name);
+
+        let mut self_loop = false;
+        let adjacency = self.adjacency;
+        for &next in adjacency.get(name).into_iter().flatten() {
+            if next == name {
+                self_loop = true;
+            }
+            if !self.indices.contains_key(next) {
+                self.visit(next);
+                let low = cmp::min(self.lowlinks[name], self.lowlinks[next]);
+                self.lowlinks.insert(name, low);
+            } else if self.on_stack.contains(next) {
+                let low = cmp::min(self.lowlinks[name], self.indices[next]);
+                self.lowlinks.insert(name, low);
+            }
+        }
+
+        if self.lowlinks[name] != self.indices[name] {
+            return;
+        }
+
+        let mut component = Vec::new();
+        while let Some(member) = self.stack.pop() {
+            self.on_stack.remove(member);
+            component.push(member.to_string());
+            if member == name {
+                break;
+            }
+        }
+        if component.len() > 1 || self_loop {
+            self.cycles.push(component);
+        }
+    }
+}
+
+/// Quote `s` as a DOT identifier
+fn quote(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use package::aur_packages_from_json;
+
+    /// An AUR package with the given dependencies
+    fn pkg(name: &str, pkgbase: &str, depends: &[&str], makedepends: &[&str]) -> AurPkg {
+        pkg_providing(name, pkgbase, depends, makedepends, &[])
+    }
+
+    fn pkg_providing(
+        name: &str,
+        pkgbase: &str,
+        depends: &[&str],
+        makedepends: &[&str],
+        provides: &[&str],
+    ) -> AurPkg {
+        let list = |deps: &[&str]| {
+            let quoted: Vec<String> = deps.iter().map(|d| format!("\"{}\"", d)).collect();
+            format!("[{}]", quoted.join(","))
+        };
+        let json = format!(
+            r#"{{"version":5,"type":"multiinfo","resultcount":1,"results":[{{
+                "ID":1,"Name":"{}","PackageBaseID":1,"PackageBase":"{}","Version":"1.0-1",
+                "Description":"","URL":"","NumVotes":0,"Popularity":0,"OutOfDate":null,
+                "Maintainer":null,"FirstSubmitted":0,"LastModified":0,"URLPath":"",
+                "Depends":{},"MakeDepends":{},"Provides":{}}}]}}"#,
+            name,
+            pkgbase,
+            list(depends),
+            list(makedepends),
+            list(provides)
+        );
+        aur_packages_from_json(&json).unwrap().remove(0)
+    }
+
+    /// Repos holding exactly `names`
+    struct FakeRepos(Vec<&'static str>);
+
+    impl Repos for FakeRepos {
+        fn satisfier(&self, depend: &str) -> Option<String> {
+            let name = Depend::parse(depend).name;
+            self.0.iter().find(|n| **n == name).map(|n| n.to_string())
+        }
+    }
+
+    /// Repos where `providers` lists (package, provided name) pairs,
+    /// some of which are installed
+    struct ProvidingRepos {
+        providers: Vec<(&'static str, &'static str)>,
+        installed: Vec<&'static str>,
+    }
+
+    impl Repos for ProvidingRepos {
+        fn satisfier(&self, _depend: &str) -> Option<String> {
+            None
+        }
+
+        fn providers(&self, depend: &str) -> Vec<String> {
+            let name = Depend::parse(depend).name;
+            self.providers
+                .iter()
+                .filter(|p| p.1 == name && !self.installed.contains(&p.0))
+                .map(|p| p.0.to_string())
+                .collect()
+        }
+
+        fn installed(&self, depend: &str) -> Option<String> {
+            let name = Depend::parse(depend).name;
+            self.providers
+                .iter()
+                .find(|p| p.1 == name && self.installed.contains(&p.0))
+                .map(|p| p.0.to_string())
+        }
+    }
+
+    /// AUR providers, with a canned answer for which one to choose
+    struct FakeProviders {
+        aur: Vec<AurPkg>,
+        choice: usize,
+        asked: Vec<String>,
+    }
+
+    impl Providers for FakeProviders {
+        fn search(&mut self, name: &str) -> Result<Vec<AurPkg>, Error> {
+            Ok(self
+                .aur
+                .iter()
+                .filter(|pkg| pkg.provides().iter().any(|p| Depend::parse(p).name == name))
+                .cloned()
+                .collect())
+        }
+
+        fn choose(&mut self, depend: &str, candidates: &[String]) -> usize {
+            self.asked.push(format!("{}: {}", depend, candidates.join(" ")));
+            self.choice
+        }
+    }
+
+    /// A lookup that serves `pkgs` and counts the batches asked for
+    fn lookup<'a>(
+        pkgs: &'a [AurPkg],
+        batches: &'a mut usize,
+    ) -> impl FnMut(&[String]) -> Result<Vec<AurPkg>, Error> + 'a {
+        move |names| {
+            *batches += 1;
+            Ok(pkgs
+                .iter()
+                .filter(|pkg| names.iter().any(|name| name == pkg.name()))
+                .cloned()
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_parse_depend() {
+        assert_eq!(Depend::parse("git").constraint, None);
+        let dep = Depend::parse("pacman>=5.1");
+        assert_eq!(dep.name, "pacman");
+        assert_eq!(dep.constraint, Some((Constraint::Ge, "5.1".to_string())));
+        assert_eq!(dep.to_string(), "pacman>=5.1");
+        assert_eq!(
+            Depend::parse("glibc<3").constraint,
+            Some((Constraint::Lt, "3".to_string()))
+        );
+        assert_eq!(
+            Depend::parse("sh=1").constraint,
+            Some((Constraint::Eq, "1".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_satisfied_by() {
+        let dep = Depend::parse("pacman>=5");
+        assert!(dep.satisfied_by("pacman", "5.1", &[]));
+        assert!(!dep.satisfied_by("pacman", "4.2", &[]));
+        assert!(!dep.satisfied_by("pacman-git", "6", &[]));
+        assert!(dep.satisfied_by("pacman-git", "6", &["pacman=6".to_string()]));
+        // An unversioned provide can't meet a versioned dependency
+        assert!(!dep.satisfied_by("pacman-git", "6", &["pacman".to_string()]));
+        assert!(Depend::parse("sh").satisfied_by("bash", "5", &["sh".to_string()]));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let aur = vec![
+            pkg("auracle-git", "auracle-git", &["pacman", "libfoo"], &["meson"]),
+            pkg("libfoo", "libfoo", &["libbar"], &[]),
+            pkg("libbar", "libbar", &["glibc"], &[]),
+        ];
+        let repos = FakeRepos(vec!["pacman", "meson", "glibc"]);
+        let mut batches = 0;
+
+        let targets = vec![aur[0].clone()];
+        let graph = resolve(targets, &repos, lookup(&aur, &mut batches)).unwrap();
+
+        // One lookup per level of AUR dependencies
+        assert_eq!(batches, 2);
+        assert_eq!(graph.node("libbar").unwrap().source, Source::Aur);
+        assert_eq!(graph.node("meson").unwrap().source, Source::Repo);
+        assert_eq!(graph.aur_packages().len(), 3);
+        assert!(graph.edges().contains(&Edge {
+            from: "auracle-git".to_string(),
+            to: "meson".to_string(),
+            kind: DepKind::MakeDepends,
+            depend: "meson".to_string(),
+        }));
+        assert!(graph.cycles().is_empty());
+    }
+
+    #[test]
+    fn test_missing_and_cycles() {
+        let aur = vec![
+            pkg("a", "a", &["b"], &[]),
+            pkg("b", "b", &["c", "nonexistent>=2"], &[]),
+            pkg("c", "c", &["a"], &[]),
+        ];
+        let mut batches = 0;
+        let targets = vec![aur[0].clone()];
+        let graph = resolve(targets, &FakeRepos(vec![]), lookup(&aur, &mut batches)).unwrap();
+
+        assert_eq!(graph.missing(), vec![("nonexistent", vec!["b"])]);
+        assert_eq!(graph.cycles(), vec![vec!["a", "b", "c"]]);
+    }
+
+    #[test]
+    fn test_aur_version_constraints() {
+        let aur = vec![
+            pkg("z", "z", &["libfoo>=3", "a"], &["libfoo"]),
+            pkg("a", "a", &["libfoo>=3"], &[]),
+            pkg("libfoo", "libfoo", &[], &[]),
+        ];
+        let mut batches = 0;
+        let targets = vec![aur[0].clone()];
+        let graph = resolve(targets, &FakeRepos(vec![]), lookup(&aur, &mut batches)).unwrap();
+
+        // libfoo 1.0-1 is too old; whatever needs it is listed once, by name
+        assert_eq!(graph.node("libfoo").unwrap().source, Source::Missing);
+        assert_eq!(graph.missing(), vec![("libfoo", vec!["a", "z"])]);
+        assert_eq!(graph.aur_packages().len(), 2);
+    }
+
+    #[test]
+    fn test_every_constraint_checked() {
+        // The bare dependency comes first, but libfoo 1.0-1 is still too old
+        let aur = vec![
+            pkg("z", "z", &["libfoo"], &["libfoo>=3"]),
+            pkg("libfoo", "libfoo", &[], &[]),
+        ];
+        let mut batches = 0;
+        let targets = vec![aur[0].clone()];
+        let graph = resolve(targets, &FakeRepos(vec![]), lookup(&aur, &mut batches)).unwrap();
+
+        assert_eq!(graph.node("libfoo").unwrap().source, Source::Missing);
+        assert_eq!(graph.aur_packages().len(), 1);
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let aur = vec![
+            pkg("cower", "cower", &["curl", "nope"], &["perl"]),
+            pkg("perl", "perl", &[], &[]),
+        ];
+        let mut batches = 0;
+        let targets = vec![aur[0].clone()];
+        let graph = resolve(targets, &FakeRepos(vec!["curl"]), lookup(&aur, &mut batches)).unwrap();
+
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph dependencies {\n"));
+        assert!(dot.contains("    \"cower\" [shape=box, label=\"cower\\n1.0-1\", penwidth=2];\n"));
+        assert!(dot.contains("    \"curl\" [shape=ellipse, color=grey];\n"));
+        assert!(dot.contains("    \"nope\" [shape=octagon, color=red, style=dashed];\n"));
+        assert!(dot.contains("    \"cower\" -> \"perl\" [label=\"makedepends\", style=dashed];\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+
+    #[test]
+    fn test_to_json() {
+        let aur = vec![pkg("a", "a", &["a"], &[])];
+        let mut batches = 0;
+        let graph = resolve(aur.clone(), &FakeRepos(vec![]), lookup(&aur, &mut batches)).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&graph.to_json().unwrap()).unwrap();
+        assert_eq!(json["targets"][0], "a");
+        assert_eq!(json["nodes"][0]["source"], "aur");
+        assert_eq!(json["nodes"][0]["cycle"], true);
+        assert_eq!(json["edges"][0]["kind"], "depends");
+        assert_eq!(json["edges"][0]["cycle"], true);
+        assert_eq!(json["cycles"][0][0], "a");
+    }
+
+    #[test]
+    fn test_build_order() {
+        let aur = vec![
+            pkg("app", "app", &["libfoo", "python-bar", "glibc"], &["tool"]),
+            // Split packages, where one part needs the other
+            pkg("libfoo", "foo", &["libfoo-data"], &[]),
+            pkg("libfoo-data", "foo", &[], &[]),
+            pkg("python-bar", "bar", &["libfoo"], &[]),
+            pkg("tool", "tool", &[], &[]),
+        ];
+        let mut batches = 0;
+        let targets = vec![aur[0].clone()];
+        let graph = resolve(targets, &FakeRepos(vec!["glibc"]), lookup(&aur, &mut batches)).unwrap();
+
+        assert_eq!(
+            graph.build_order().unwrap(),
+            vec![vec!["foo", "tool"], vec!["bar"], vec!["app"]]
+        );
+    }
+
+    #[test]
+    fn test_build_order_cycle() {
+        // No cycle between packages, but one between pkgbases
+        let aur = vec![
+            pkg("a", "x", &["b"], &[]),
+            pkg("b", "y", &["c"], &[]),
+            pkg("c", "x", &[], &[]),
+            pkg("d", "d", &["a"], &[]),
+        ];
+        let mut batches = 0;
+        let targets = vec![aur[3].clone()];
+        let graph = resolve(targets, &FakeRepos(vec![]), lookup(&aur, &mut batches)).unwrap();
+        assert!(graph.cycles().is_empty());
+
+        match graph.build_order() {
+            Err(DepsError::Cycle { pkgbases }) => assert_eq!(pkgbases, "x, y"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    fn resolve_providers(
+        repos: &ProvidingRepos,
+        providers: &mut FakeProviders,
+        rules: &[(&str, &str)],
+    ) -> Result<Graph, Error> {
+        let app = pkg("app", "app", &["sh", "java-environment>=2"], &[]);
+        let rules = rules
+            .iter()
+            .map(|&(name, provider)| (name.to_string(), provider.to_string()))
+            .collect();
+        let mut batches = 0;
+        resolve_with(vec![app], repos, lookup(&[], &mut batches), providers, &rules)
+    }
+
+    fn repos(installed: Vec<&'static str>) -> ProvidingRepos {
+        ProvidingRepos {
+            providers: vec![
+                ("bash", "sh"),
+                ("jdk-openjdk", "java-environment"),
+                ("jdk11-openjdk", "java-environment"),
+            ],
+            installed,
+        }
+    }
+
+    fn aur_providers(choice: usize) -> FakeProviders {
+        FakeProviders {
+            aur: vec![
+                pkg_providing("jdk-bin", "jdk-bin", &[], &[], &["java-environment=3"]),
+                pkg_providing("jdk1-bin", "jdk1-bin", &[], &[], &["java-environment=1"]),
+                pkg_providing("jre-bin", "jre-bin", &[], &[], &["java-runtime=3"]),
+            ],
+            choice,
+            asked: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_repo_providers() {
+        let mut providers = aur_providers(1);
+        let graph = resolve_providers(&repos(vec![]), &mut providers, &[]).unwrap();
+
+        // A single provider needs no choice, several do
+        assert_eq!(graph.node("sh").unwrap().satisfier, Some("bash".to_string()));
+        assert_eq!(
+            providers.asked,
+            vec!["java-environment>=2: jdk-openjdk jdk11-openjdk"]
+        );
+        let java = graph.node("java-environment").unwrap();
+        assert_eq!(java.source, Source::Repo);
+        assert_eq!(java.satisfier, Some("jdk11-openjdk".to_string()));
+    }
+
+    #[test]
+    fn test_installed_provider_first() {
+        let mut providers = aur_providers(0);
+        let graph = resolve_providers(&repos(vec!["jdk11-openjdk"]), &mut providers, &[]).unwrap();
+
+        let java = graph.node("java-environment").unwrap();
+        assert_eq!(java.source, Source::Installed);
+        assert_eq!(java.satisfier, Some("jdk11-openjdk".to_string()));
+        assert!(providers.asked.is_empty());
+    }
+
+    #[test]
+    fn test_provider_rules() {
+        let mut providers = aur_providers(0);
+        let rules = [("java-environment", "jdk-bin")];
+        let graph = resolve_providers(&repos(vec![]), &mut providers, &rules).unwrap();
+
+        // Rules can pick AUR providers over repo ones
+        let java = graph.node("java-environment").unwrap();
+        assert_eq!(java.source, Source::Aur);
+        assert_eq!(java.satisfier, Some("jdk-bin".to_string()));
+        assert!(graph.node("jdk-bin").unwrap().pkg.is_some());
+        assert!(providers.asked.is_empty());
+
+        // jdk1-bin provides too old a version
+        let rules = [("java-environment", "jdk1-bin")];
+        assert!(resolve_providers(&repos(vec![]), &mut providers, &rules).is_err());
+    }
+
+    #[test]
+    fn test_aur_providers() {
+        let repos = ProvidingRepos {
+            providers: vec![("bash", "sh")],
+            installed: vec![],
+        };
+        let mut providers = aur_providers(0);
+        let graph = resolve_providers(&repos, &mut providers, &[]).unwrap();
+
+        // Only jdk-bin provides a recent enough version
+        assert!(providers.asked.is_empty());
+        assert_eq!(
+            graph.node("java-environment").unwrap().satisfier,
+            Some("jdk-bin".to_string())
+        );
+        assert_eq!(graph.build_order().unwrap(), vec![vec!["jdk-bin"], vec!["app"]]);
+        assert!(graph.to_dot().contains(
+            "    \"java-environment\" -> \"jdk-bin\" [label=\"provided by\", style=bold];\n"
+        ));
+    }
+
+    #[test]
+    fn test_graph_format() {
+        assert_eq!("dot".parse::<GraphFormat>().unwrap(), GraphFormat::Dot);
+        assert_eq!("json".parse::<GraphFormat>().unwrap(), GraphFormat::Json);
+        assert!("svg".parse::<GraphFormat>().is_err());
+    }
+}
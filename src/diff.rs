@@ -0,0 +1,154 @@
+use std::cmp;
+
+/// Lines of unchanged context shown around each change
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// A unified diff between `old` and `new`, labelled with their names.
+/// Empty when the two are the same.
+pub fn unified_diff(old_name: &str, new_name: &str, old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let ops = diff_lines(&old, &new);
+    if ops.iter().all(|&(op, _, _)| op == Op::Equal) {
+        return String::new();
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+    for hunk in hunks(&ops) {
+        let ops = &ops[hunk.0..hunk.1];
+        let (old_start, new_start) = (ops[0].1, ops[0].2);
+        let old_len = ops.iter().filter(|op| op.0 != Op::Insert).count();
+        let new_len = ops.iter().filter(|op| op.0 != Op::Delete).count();
+
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(old_start, old_len),
+            range(new_start, new_len)
+        ));
+        for &(op, i, j) in ops {
+            match op {
+                Op::Equal => out.push_str(&format!(" {}\n", old[i])),
+                Op::Delete => out.push_str(&format!("-{}\n", old[i])),
+                Op::Insert => out.push_str(&format!("+{}\n", new[j])),
+            }
+        }
+    }
+    out
+}
+
+/// A hunk range, `start,len` with 1-based line numbers. Empty ranges
+/// name the line before them, as diff(1) does.
+fn range(start: usize, len: usize) -> String {
+    if len == 0 {
+        format!("{},0", start)
+    } else if len == 1 {
+        format!("{}", start + 1)
+    } else {
+        format!("{},{}", start + 1, len)
+    }
+}
+
+/// Every line of both inputs as an edit operation, along with its
+/// index in the old and the new lines
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<(Op, usize, usize)> {
+    // lcs[i][j] is the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push((Op::Equal, i, j));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            // Removals go first, like diff(1) shows them
+            ops.push((Op::Delete, i, j));
+            i += 1;
+        } else {
+            ops.push((Op::Insert, i, j));
+            j += 1;
+        }
+    }
+    ops
+}
+
+/// Group the changes into hunks with some context, as ranges of `ops`
+fn hunks(ops: &[(Op, usize, usize)]) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (idx, op) in ops.iter().enumerate() {
+        if op.0 == Op::Equal {
+            continue;
+        }
+
+        let start = idx.saturating_sub(CONTEXT);
+        let end = cmp::min(idx + 1 + CONTEXT, ops.len());
+        match hunks.last_mut() {
+            // Merge changes whose context touches
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    hunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identical() {
+        assert_eq!(unified_diff("a", "b", "x\ny\n", "x\ny\n"), "");
+        assert_eq!(unified_diff("a", "b", "", ""), "");
+    }
+
+    #[test]
+    fn test_single_change() {
+        let old = "pkgname=cower\npkgver=17\npkgrel=1\n";
+        let new = "pkgname=cower\npkgver=18\npkgrel=1\n";
+        assert_eq!(
+            unified_diff("a/PKGBUILD", "b/PKGBUILD", old, new),
+            "--- a/PKGBUILD\n+++ b/PKGBUILD\n@@ -1,3 +1,3 @@\n pkgname=cower\n-pkgver=17\n+pkgver=18\n pkgrel=1\n"
+        );
+    }
+
+    #[test]
+    fn test_new_and_removed_files() {
+        assert_eq!(
+            unified_diff("/dev/null", "b/cower.install", "", "post_install() {\n}\n"),
+            "--- /dev/null\n+++ b/cower.install\n@@ -0,0 +1,2 @@\n+post_install() {\n+}\n"
+        );
+        assert_eq!(
+            unified_diff("a/cower.install", "/dev/null", "true\n", ""),
+            "--- a/cower.install\n+++ /dev/null\n@@ -1 +0,0 @@\n-true\n"
+        );
+    }
+
+    #[test]
+    fn test_separate_hunks() {
+        let old: Vec<String> = (1..=20).map(|i| i.to_string()).collect();
+        let mut new = old.clone();
+        new[1] = "two".to_string();
+        new[17] = "eighteen".to_string();
+
+        let diff = unified_diff("a", "b", &old.join("\n"), &new.join("\n"));
+        let headers: Vec<&str> = diff.lines().filter(|l| l.starts_with("@@")).collect();
+        assert_eq!(headers, vec!["@@ -1,5 +1,5 @@", "@@ -15,6 +15,6 @@"]);
+    }
+}
@@ -0,0 +1,836 @@
+use aur::AurT;
+use diff::unified_diff;
+use failure::Error;
+use flate2::read::GzDecoder;
+use package::AurPkg;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command};
+use tar::{Archive, EntryType};
+
+#[derive(Debug, Fail)]
+pub enum DownloadError {
+    #[fail(display = "`{}' already exists. Use -f to overwrite.", path)]
+    AlreadyExists { path: String },
+    #[fail(display = "`{}' has local changes. Use -f to discard them.", path)]
+    LocalChanges { path: String },
+    #[fail(display = "git {} failed: {}", command, message)]
+    GitFailed { command: String, message: String },
+    #[fail(display = "git {} couldn't reach the AUR: {}", command, message)]
+    GitRemoteFailed { command: String, message: String },
+    #[fail(display = "refusing to extract `{}': {}", path, reason)]
+    UnsafeEntry { path: String, reason: &'static str },
+    #[fail(display = "snapshot is too large: more than {}", limit)]
+    TooLarge { limit: String },
+    #[fail(display = "snapshot contains no `{}' directory", pkgbase)]
+    MissingPackage { pkgbase: String },
+}
+
+/// Most bytes a snapshot may unpack to
+pub const MAX_UNPACKED_SIZE: u64 = 64 * 1024 * 1024;
+/// Most files and directories a snapshot may contain
+pub const MAX_ENTRIES: usize = 4096;
+
+/// How package sources are fetched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadMethod {
+    /// Snapshot tarballs, no history
+    Tarball,
+    /// Clones of the package's git repository
+    Git,
+}
+
+/// A download that has been fetched but not yet put in place, so that
+/// the changes can be reviewed first
+pub struct Staged {
+    pkgbase: String,
+    target: PathBuf,
+    staging: Staging,
+}
+
+enum Staging {
+    /// A snapshot or fresh clone in a directory next to the target
+    Tarball { dir: PathBuf },
+    /// A fetched clone, waiting to be fast-forwarded or reset
+    GitUpdate { force: bool },
+    /// Already in place, nothing to review
+    Done,
+}
+
+impl Staged {
+    pub fn pkgbase(&self) -> &str {
+        &self.pkgbase
+    }
+
+    /// A unified diff of the build files that would change: the
+    /// PKGBUILD, .SRCINFO and any install scripts. Empty for new
+    /// packages and when nothing changed.
+    pub fn diff(&self) -> Result<String, Error> {
+        let (old, new) = match self.staging {
+            Staging::Tarball { ref dir } => {
+                if !self.target.exists() {
+                    return Ok(String::new());
+                }
+                (
+                    read_build_files(&self.target)?,
+                    read_build_files(&dir.join(&self.pkgbase))?,
+                )
+            }
+            Staging::GitUpdate { .. } => (
+                git_build_files(&self.target, "HEAD")?,
+                git_build_files(&self.target, "@{upstream}")?,
+            ),
+            Staging::Done => return Ok(String::new()),
+        };
+
+        let mut names: Vec<&String> = old.keys().chain(new.keys()).collect();
+        names.sort();
+        names.dedup();
+
+        let mut diff = String::new();
+        for name in names {
+            let label = |side: &str, files: &BTreeMap<String, String>| {
+                if files.contains_key(name) {
+                    format!("{}/{}/{}", side, self.pkgbase, name)
+                } else {
+                    String::from("/dev/null")
+                }
+            };
+            diff.push_str(&unified_diff(
+                &label("a", &old),
+                &label("b", &new),
+                old.get(name).map_or("", |s| s.as_str()),
+                new.get(name).map_or("", |s| s.as_str()),
+            ));
+        }
+        Ok(diff)
+    }
+
+    /// Put the download in place, returning its path
+    pub fn commit(mut self) -> Result<PathBuf, Error> {
+        match self.staging {
+            Staging::Tarball { ref dir } => {
+                // Swap the copies with renames, so the target is never
+                // missing or half written
+                let old = dir.join(".old");
+                if self.target.exists() {
+                    fs::rename(&self.target, &old)?;
+                }
+                if let Err(e) = fs::rename(dir.join(&self.pkgbase), &self.target) {
+                    if old.exists() && fs::rename(&old, &self.target).is_err() {
+                        // The only copy of the old download is in there
+                        self.staging = Staging::Done;
+                    }
+                    return Err(Error::from(e));
+                }
+                fs::remove_dir_all(dir)?;
+                debug!("moved {} into {}", self.pkgbase, self.target.display());
+            }
+            Staging::GitUpdate { force: true } => {
+                git(Some(&self.target), &["reset", "--quiet", "--hard", "@{upstream}"])?;
+                git(Some(&self.target), &["clean", "--quiet", "-fdx"])?;
+            }
+            Staging::GitUpdate { force: false } => {
+                git(Some(&self.target), &["merge", "--quiet", "--ff-only", "@{upstream}"])?;
+            }
+            Staging::Done => (),
+        }
+        self.staging = Staging::Done;
+        Ok(self.target.clone())
+    }
+
+    /// Throw the download away, leaving any earlier copy as it was
+    pub fn abort(mut self) -> Result<(), Error> {
+        if let Staging::Tarball { ref dir } = self.staging {
+            fs::remove_dir_all(dir)?;
+        }
+        self.staging = Staging::Done;
+        Ok(())
+    }
+}
+
+impl Drop for Staged {
+    /// Don't leave the staging directory behind when a download is
+    /// neither committed nor aborted, e.g. after an error
+    fn drop(&mut self) {
+        if let Staging::Tarball { ref dir } = self.staging {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// Fetch the sources of `pkg` with `method` and stage them for `dir`
+pub fn stage_package(
+    aur: &AurT,
+    pkg: &AurPkg,
+    dir: &Path,
+    method: DownloadMethod,
+    force: bool,
+) -> Result<Staged, Error> {
+    match method {
+        DownloadMethod::Tarball => {
+            check_target(&dir.join(pkg.pkgbase()), force)?;
+            let url = aur.aur_build_download_url(pkg.aur_urlpath())?;
+            stage_tarball(&aur.fetch(&url)?, pkg.pkgbase(), dir, force)
+        }
+        DownloadMethod::Git => {
+            let remote = aur.aur_build_git_url(pkg.pkgbase())?;
+            stage_git(remote.as_str(), &dir.join(pkg.pkgbase()), force)
+        }
+    }
+}
+
+/// Download the snapshot tarball for `pkg` and unpack it into `dir`,
+/// returning the path of the new package directory
+pub fn download_package(
+    aur: &AurT,
+    pkg: &AurPkg,
+    dir: &Path,
+    force: bool,
+) -> Result<PathBuf, Error> {
+    stage_package(aur, pkg, dir, DownloadMethod::Tarball, force)?.commit()
+}
+
+/// Clone the git repository of `pkg` into `dir`, or bring an earlier
+/// clone up to date, returning the path of the clone
+pub fn clone_package(aur: &AurT, pkg: &AurPkg, dir: &Path, force: bool) -> Result<PathBuf, Error> {
+    stage_package(aur, pkg, dir, DownloadMethod::Git, force)?.commit()
+}
+
+/// Clone `remote` into `target`. An existing clone is fetched and
+/// fast-forwarded instead, unless it has local changes. With `force`,
+/// local changes are thrown away and anything that isn't a clone is
+/// replaced.
+pub fn git_download(remote: &str, target: &Path, force: bool) -> Result<PathBuf, Error> {
+    stage_git(remote, target, force)?.commit()
+}
+
+/// Unpack `tarball` next to where `pkgbase` goes in `dir`
+fn stage_tarball(tarball: &[u8], pkgbase: &str, dir: &Path, force: bool) -> Result<Staged, Error> {
+    let target = dir.join(pkgbase);
+    check_target(&target, force)?;
+
+    let staging = create_staging(dir, pkgbase)?;
+    if let Err(e) = extract_tarball(tarball, pkgbase, &staging) {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+
+    Ok(Staged {
+        pkgbase: pkgbase.to_string(),
+        target,
+        staging: Staging::Tarball { dir: staging },
+    })
+}
+
+/// An empty directory next to where `pkgbase` goes in `dir`, to put a
+/// download together in
+fn create_staging(dir: &Path, pkgbase: &str) -> Result<PathBuf, Error> {
+    let staging = dir.join(format!(".cower-{}.{}", pkgbase, process::id()));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir(&staging)?;
+    Ok(staging)
+}
+
+/// Clone `remote` into `target`, or fetch into an existing clone
+fn stage_git(remote: &str, target: &Path, force: bool) -> Result<Staged, Error> {
+    let pkgbase = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let staged = |staging| Staged {
+        pkgbase: pkgbase.clone(),
+        target: target.to_path_buf(),
+        staging,
+    };
+
+    // Anything that isn't a clone yet is only replaced once cloning worked
+    if !target.join(".git").is_dir() {
+        check_target(target, force)?;
+        let dir = target.parent().unwrap_or_else(|| Path::new("."));
+        let staging = create_staging(dir, &pkgbase)?;
+        let clone = staging.join(&pkgbase).to_string_lossy().into_owned();
+        if let Err(e) = git(None, &["clone", "--quiet", remote, &clone]) {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+        return Ok(staged(Staging::Tarball { dir: staging }));
+    }
+
+    let dirty = !git(Some(target), &["status", "--porcelain"])?.is_empty();
+    if dirty && !force {
+        return Err(Error::from(DownloadError::LocalChanges {
+            path: target.display().to_string(),
+        }));
+    }
+
+    git(Some(target), &["fetch", "--quiet", "origin"])?;
+    Ok(staged(Staging::GitUpdate { force }))
+}
+
+/// Refuse to replace an existing download without `force`
+fn check_target(target: &Path, force: bool) -> Result<(), Error> {
+    if target.exists() && !force {
+        return Err(Error::from(DownloadError::AlreadyExists {
+            path: target.display().to_string(),
+        }));
+    }
+    Ok(())
+}
+
+/// Files worth reviewing before building a package
+fn is_build_file(name: &str) -> bool {
+    name == "PKGBUILD" || name == ".SRCINFO" || name.ends_with(".install")
+}
+
+/// The build files in `dir` by name
+fn read_build_files(dir: &Path) -> Result<BTreeMap<String, String>, Error> {
+    let mut files = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if is_build_file(&name) && entry.file_type()?.is_file() {
+            let contents = fs::read(entry.path())?;
+            files.insert(name, String::from_utf8_lossy(&contents).into_owned());
+        }
+    }
+    Ok(files)
+}
+
+/// The build files of the clone in `dir` as of `rev`
+fn git_build_files(dir: &Path, rev: &str) -> Result<BTreeMap<String, String>, Error> {
+    let mut files = BTreeMap::new();
+    for name in git(Some(dir), &["ls-tree", "--name-only", rev])?.lines() {
+        if is_build_file(name) {
+            let contents = git(Some(dir), &["show", &format!("{}:{}", rev, name)])?;
+            files.insert(name.to_string(), contents);
+        }
+    }
+    Ok(files)
+}
+
+/// Run git, in `dir` if given, returning what it printed
+fn git(dir: Option<&Path>, args: &[&str]) -> Result<String, Error> {
+    let mut command = Command::new("git");
+    if let Some(dir) = dir {
+        command.arg("-C").arg(dir);
+    }
+    debug!("running git {}", args.join(" "));
+
+    let output = command.args(args).output()?;
+    if !output.status.success() {
+        let command = args[0].to_string();
+        let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        // Only these talk to the remote
+        return Err(Error::from(match args[0] {
+            "clone" | "fetch" => DownloadError::GitRemoteFailed { command, message },
+            _ => DownloadError::GitFailed { command, message },
+        }));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Unpack a gzipped snapshot of `pkgbase` into `dir`.
+///
+/// Snapshots are untrusted, so every entry must be a plain file,
+/// directory or link that stays inside `pkgbase/`, without setuid or
+/// setgid bits, and the whole archive must stay under `MAX_ENTRIES` and
+/// `MAX_UNPACKED_SIZE`.
+pub fn extract_tarball(tarball: &[u8], pkgbase: &str, dir: &Path) -> Result<(), Error> {
+    unpack_checked(tarball, pkgbase, dir, MAX_UNPACKED_SIZE, MAX_ENTRIES)
+}
+
+fn unpack_checked(
+    tarball: &[u8],
+    pkgbase: &str,
+    dir: &Path,
+    max_size: u64,
+    max_entries: usize,
+) -> Result<(), Error> {
+    let mut archive = Archive::new(GzDecoder::new(tarball));
+    let (mut size, mut count) = (0u64, 0usize);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+
+        // git archive stores the commit id in a global header
+        if kind.is_pax_global_extensions() || kind.is_pax_local_extensions() {
+            continue;
+        }
+
+        let path = entry.path()?.into_owned();
+        let unsafe_entry = |reason| {
+            Error::from(DownloadError::UnsafeEntry {
+                path: path.display().to_string(),
+                reason,
+            })
+        };
+
+        count += 1;
+        if count > max_entries {
+            return Err(Error::from(DownloadError::TooLarge {
+                limit: format!("{} entries", max_entries),
+            }));
+        }
+        // Honours a PAX size record, which the ustar field may understate
+        size += entry.size();
+        if size > max_size {
+            return Err(Error::from(DownloadError::TooLarge {
+                limit: format!("{} bytes", max_size),
+            }));
+        }
+
+        let depth = package_depth(&path, pkgbase).map_err(unsafe_entry)?;
+        if entry.header().mode()? & 0o6000 != 0 {
+            return Err(unsafe_entry("setuid or setgid bit"));
+        }
+
+        if kind.is_symlink() {
+            let link = entry.link_name()?.ok_or_else(|| unsafe_entry("empty link"))?;
+            // A link in place of the package directory resolves next to it
+            if depth == 1 || !descends(&link) {
+                return Err(unsafe_entry("link points outside the package"));
+            }
+        } else if kind.is_hard_link() {
+            let link = entry.link_name()?.ok_or_else(|| unsafe_entry("empty link"))?;
+            // Relative to the top of the archive
+            package_depth(&link, pkgbase)
+                .map_err(|_| unsafe_entry("link points outside the package"))?;
+        } else if !kind.is_file() && !kind.is_dir() && kind != EntryType::Continuous {
+            return Err(unsafe_entry("not a file, directory or link"));
+        }
+
+        entry.set_preserve_permissions(false);
+        if !entry.unpack_in(dir)? {
+            return Err(unsafe_entry("path leaves the target directory"));
+        }
+    }
+
+    if !dir.join(pkgbase).is_dir() {
+        return Err(Error::from(DownloadError::MissingPackage {
+            pkgbase: pkgbase.to_string(),
+        }));
+    }
+    debug!("unpacked {} entries, {} bytes, of {}", count, size, pkgbase);
+    Ok(())
+}
+
+/// How many components deep `path` is below `pkgbase/`, which it must
+/// start with, counting `pkgbase` itself
+fn package_depth(path: &Path, pkgbase: &str) -> Result<usize, &'static str> {
+    let mut depth = 0;
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => {
+                if depth == 0 && name != pkgbase {
+                    return Err("not inside the package directory");
+                }
+                depth += 1;
+            }
+            Component::CurDir => (),
+            Component::ParentDir => return Err("path contains `..'"),
+            Component::RootDir | Component::Prefix(_) => return Err("absolute path"),
+        }
+    }
+    if depth == 0 {
+        return Err("not inside the package directory");
+    }
+    Ok(depth)
+}
+
+/// Whether `link` only leads down from the directory holding it. Any
+/// `..` is refused, even one that looks harmless: it may climb out of a
+/// directory that is itself a link, and links in one archive can be
+/// chained, e.g. `s2 -> .` and `s1 -> s2/..`. Links that only lead down
+/// can't escape however they're combined.
+fn descends(link: &Path) -> bool {
+    link.components().all(|component| match component {
+        Component::Normal(_) | Component::CurDir => true,
+        Component::ParentDir | Component::RootDir | Component::Prefix(_) => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tar::{Builder, Header};
+    use tempdir::TempDir;
+
+    fn tarball(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, contents) in files {
+            let mut header = Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn test_extract_tarball() {
+        let dir = TempDir::new("cower_test_extract").unwrap();
+        let data = tarball(&[
+            ("cower/PKGBUILD", "pkgname=cower\n"),
+            ("cower/.SRCINFO", "pkgbase = cower\n"),
+        ]);
+
+        extract_tarball(&data, "cower", dir.path()).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("cower/PKGBUILD")).unwrap(),
+            "pkgname=cower\n"
+        );
+        assert!(dir.path().join("cower/.SRCINFO").is_file());
+    }
+
+    #[test]
+    fn test_staged_tarball_diff() {
+        let dir = TempDir::new("cower_test_stage").unwrap();
+        let old = tarball(&[
+            ("cower/PKGBUILD", "pkgver=17\n"),
+            ("cower/cower.install", "true\n"),
+            ("cower/README", "old\n"),
+        ]);
+        let new = tarball(&[
+            ("cower/PKGBUILD", "pkgver=18\n"),
+            ("cower/.SRCINFO", "pkgver = 18\n"),
+            ("cower/README", "new\n"),
+        ]);
+
+        // Nothing to compare against the first time
+        let staged = stage_tarball(&old, "cower", dir.path(), false).unwrap();
+        assert_eq!(staged.diff().unwrap(), "");
+        let target = staged.commit().unwrap();
+
+        assert!(stage_tarball(&new, "cower", dir.path(), false).is_err());
+        let staged = stage_tarball(&new, "cower", dir.path(), true).unwrap();
+        assert_eq!(
+            staged.diff().unwrap(),
+            "--- /dev/null\n+++ b/cower/.SRCINFO\n@@ -0,0 +1 @@\n+pkgver = 18\n\
+             --- a/cower/PKGBUILD\n+++ b/cower/PKGBUILD\n@@ -1 +1 @@\n-pkgver=17\n+pkgver=18\n\
+             --- a/cower/cower.install\n+++ /dev/null\n@@ -1 +0,0 @@\n-true\n"
+        );
+
+        // Aborting leaves the old copy alone
+        staged.abort().unwrap();
+        assert_eq!(fs::read_to_string(target.join("PKGBUILD")).unwrap(), "pkgver=17\n");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // Nor does dropping it, and the staging directory goes too
+        drop(stage_tarball(&new, "cower", dir.path(), true).unwrap());
+        assert_eq!(fs::read_to_string(target.join("PKGBUILD")).unwrap(), "pkgver=17\n");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let staged = stage_tarball(&new, "cower", dir.path(), true).unwrap();
+        staged.commit().unwrap();
+        assert_eq!(fs::read_to_string(target.join("PKGBUILD")).unwrap(), "pkgver=18\n");
+        assert!(!target.join("cower.install").exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_extract_garbage() {
+        let dir = TempDir::new("cower_test_extract").unwrap();
+        assert!(extract_tarball(b"not a tarball", "cower", dir.path()).is_err());
+    }
+
+    /// A snapshot holding a single entry built by `build`
+    fn raw_tarball<F: FnOnce(&mut Header)>(path: &str, build: F) -> Vec<u8> {
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        let mut header = Header::new_gnu();
+        header.set_size(0);
+        header.set_mode(0o644);
+        build(&mut header);
+        // Set the raw name, since set_path refuses `..` and absolute paths
+        {
+            let name = &mut header.as_old_mut().name;
+            name[..path.len()].copy_from_slice(path.as_bytes());
+        }
+        header.set_cksum();
+        builder.append(&header, &[][..]).unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn assert_rejected(data: &[u8]) {
+        let dir = TempDir::new("cower_test_extract").unwrap();
+        let err = extract_tarball(data, "cower", dir.path()).unwrap_err();
+        match err.downcast_ref::<DownloadError>() {
+            Some(DownloadError::UnsafeEntry { .. }) => (),
+            _ => panic!("unexpected error: {}", err),
+        }
+
+        // Nothing may have been written for the rejected entry
+        let written = fs::read_dir(dir.path().join("cower")).map_or(0, |dir| dir.count());
+        assert_eq!(written, 0);
+    }
+
+    #[test]
+    fn test_extract_rejects_unsafe_paths() {
+        assert_rejected(&raw_tarball("/etc/cower", |_| ()));
+        assert_rejected(&raw_tarball("cower/../../evil", |_| ()));
+        assert_rejected(&raw_tarball("other/PKGBUILD", |_| ()));
+        assert_rejected(&raw_tarball("PKGBUILD", |_| ()));
+    }
+
+    #[test]
+    fn test_extract_rejects_unsafe_entries() {
+        assert_rejected(&raw_tarball("cower/evil", |h| {
+            h.set_entry_type(EntryType::Symlink);
+            h.set_link_name("../../etc/passwd").unwrap();
+        }));
+        assert_rejected(&raw_tarball("cower/evil", |h| {
+            h.set_entry_type(EntryType::Symlink);
+            h.set_link_name("/etc/passwd").unwrap();
+        }));
+        // Fine inside the staging directory, but a sibling package or
+        // the working directory once committed
+        assert_rejected(&raw_tarball("cower/x", |h| {
+            h.set_entry_type(EntryType::Symlink);
+            h.set_link_name("../sibling").unwrap();
+        }));
+        assert_rejected(&raw_tarball("cower/x", |h| {
+            h.set_entry_type(EntryType::Symlink);
+            h.set_link_name("..").unwrap();
+        }));
+        assert_rejected(&raw_tarball("cower/sub/x", |h| {
+            h.set_entry_type(EntryType::Symlink);
+            h.set_link_name("../../cower-git").unwrap();
+        }));
+        assert_rejected(&raw_tarball("cower", |h| {
+            h.set_entry_type(EntryType::Symlink);
+            h.set_link_name(".").unwrap();
+        }));
+        assert_rejected(&raw_tarball("cower/evil", |h| {
+            h.set_entry_type(EntryType::Link);
+            h.set_link_name("other/PKGBUILD").unwrap();
+        }));
+        assert_rejected(&raw_tarball("cower/tty", |h| {
+            h.set_entry_type(EntryType::Char);
+        }));
+        assert_rejected(&raw_tarball("cower/suid", |h| h.set_mode(0o4755)));
+    }
+
+    /// A snapshot holding the symlinks `links`, as (path, target) pairs
+    fn links_tarball(links: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, target) in links {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Symlink);
+            header.set_size(0);
+            header.set_mode(0o777);
+            builder.append_link(&mut header, path, target).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn test_extract_rejects_chained_links() {
+        // Each looks fine alone, but s1 resolves to the parent of `cower`
+        let data = links_tarball(&[("cower/s2", "."), ("cower/s1", "s2/..")]);
+        let dir = TempDir::new("cower_test_extract").unwrap();
+        let err = extract_tarball(&data, "cower", dir.path()).unwrap_err();
+        match err.downcast_ref::<DownloadError>() {
+            Some(DownloadError::UnsafeEntry { path, .. }) => assert_eq!(path, "cower/s1"),
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_extract_allows_links_inside_package() {
+        let dir = TempDir::new("cower_test_extract").unwrap();
+        let data = links_tarball(&[("cower/sub/link", "PKGBUILD"), ("cower/here", ".")]);
+        extract_tarball(&data, "cower", dir.path()).unwrap();
+        assert!(fs::symlink_metadata(dir.path().join("cower/sub/link")).is_ok());
+        assert!(fs::symlink_metadata(dir.path().join("cower/here")).is_ok());
+    }
+
+    #[test]
+    fn test_extract_limits_pax_size() {
+        // The ustar size says 1 byte, the PAX record the real size
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        let contents = [b'x'; 64];
+        let mut pax = Header::new_ustar();
+        pax.set_entry_type(EntryType::XHeader);
+        let record = b"11 size=64\n";
+        pax.set_size(record.len() as u64);
+        pax.set_mode(0o644);
+        pax.set_cksum();
+        builder.append_data(&mut pax, "cower/PaxHeader", &record[..]).unwrap();
+        let mut header = Header::new_ustar();
+        header.set_size(1);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "cower/big", &contents[..]).unwrap();
+        let data = builder.into_inner().unwrap().finish().unwrap();
+
+        let dir = TempDir::new("cower_test_extract").unwrap();
+        let err = unpack_checked(&data, "cower", dir.path(), 10, 10).unwrap_err();
+        match err.downcast_ref::<DownloadError>() {
+            Some(DownloadError::TooLarge { .. }) => (),
+            _ => panic!("unexpected error: {}", err),
+        }
+        unpack_checked(&data, "cower", dir.path(), 64, 10).unwrap();
+        assert_eq!(fs::read(dir.path().join("cower/big")).unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_extract_limits() {
+        let dir = TempDir::new("cower_test_extract").unwrap();
+        let data = tarball(&[("cower/a", "12345"), ("cower/b", "12345")]);
+
+        assert!(unpack_checked(&data, "cower", dir.path(), 10, 1).is_err());
+        assert!(unpack_checked(&data, "cower", dir.path(), 9, 10).is_err());
+        unpack_checked(&data, "cower", dir.path(), 10, 2).unwrap();
+    }
+
+    #[test]
+    fn test_extract_requires_package_dir() {
+        let dir = TempDir::new("cower_test_extract").unwrap();
+        let data = tarball(&[("cower/PKGBUILD", "")]);
+        assert!(extract_tarball(&data, "auracle-git", dir.path()).is_err());
+    }
+
+    /// Commit `contents` as the PKGBUILD of the bare repository `remote`
+    fn push_pkgbuild(remote: &Path, contents: &str) {
+        let work = TempDir::new("cower_test_git_work").unwrap();
+        let work = work.path().join("work");
+        let run = |args: &[&str]| {
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(&work)
+                .args(&["-c", "user.name=cower", "-c", "user.email=cower@localhost"])
+                .args(args)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "git {:?} failed", args);
+        };
+
+        git(None, &["clone", "--quiet", &remote.to_string_lossy(), &work.to_string_lossy()]).unwrap();
+        fs::write(work.join("PKGBUILD"), contents).unwrap();
+        run(&["add", "PKGBUILD"]);
+        run(&["commit", "--quiet", "-m", "update"]);
+        run(&["push", "--quiet", "origin", "HEAD"]);
+    }
+
+    fn bare_remote(dir: &Path) -> PathBuf {
+        let remote = dir.join("cower.git");
+        git(None, &["init", "--quiet", "--bare", &remote.to_string_lossy()]).unwrap();
+        push_pkgbuild(&remote, "pkgver=1\n");
+        remote
+    }
+
+    #[test]
+    fn test_git_clone_and_update() {
+        let dir = TempDir::new("cower_test_git").unwrap();
+        let remote = bare_remote(dir.path());
+        let target = dir.path().join("cower");
+
+        git_download(&remote.to_string_lossy(), &target, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(target.join("PKGBUILD")).unwrap(),
+            "pkgver=1\n"
+        );
+
+        push_pkgbuild(&remote, "pkgver=2\n");
+        git_download(&remote.to_string_lossy(), &target, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(target.join("PKGBUILD")).unwrap(),
+            "pkgver=2\n"
+        );
+    }
+
+    #[test]
+    fn test_staged_git_diff() {
+        let dir = TempDir::new("cower_test_git").unwrap();
+        let remote = bare_remote(dir.path());
+        let target = dir.path().join("cower");
+
+        let staged = stage_git(&remote.to_string_lossy(), &target, false).unwrap();
+        assert_eq!(staged.diff().unwrap(), "");
+        staged.commit().unwrap();
+
+        push_pkgbuild(&remote, "pkgver=2\n");
+        let staged = stage_git(&remote.to_string_lossy(), &target, false).unwrap();
+        assert_eq!(
+            staged.diff().unwrap(),
+            "--- a/cower/PKGBUILD\n+++ b/cower/PKGBUILD\n@@ -1 +1 @@\n-pkgver=1\n+pkgver=2\n"
+        );
+
+        staged.abort().unwrap();
+        assert_eq!(fs::read_to_string(target.join("PKGBUILD")).unwrap(), "pkgver=1\n");
+    }
+
+    #[test]
+    fn test_git_refuses_local_changes() {
+        let dir = TempDir::new("cower_test_git").unwrap();
+        let remote = bare_remote(dir.path());
+        let target = dir.path().join("cower");
+
+        git_download(&remote.to_string_lossy(), &target, false).unwrap();
+        fs::write(target.join("PKGBUILD"), "pkgver=mine\n").unwrap();
+        fs::write(target.join("notes"), "mine\n").unwrap();
+
+        let err = git_download(&remote.to_string_lossy(), &target, false)
+            .err()
+            .unwrap();
+        match err.downcast_ref::<DownloadError>() {
+            Some(DownloadError::LocalChanges { .. }) => (),
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert_eq!(
+            fs::read_to_string(target.join("PKGBUILD")).unwrap(),
+            "pkgver=mine\n"
+        );
+
+        git_download(&remote.to_string_lossy(), &target, true).unwrap();
+        assert_eq!(
+            fs::read_to_string(target.join("PKGBUILD")).unwrap(),
+            "pkgver=1\n"
+        );
+        assert!(!target.join("notes").exists());
+    }
+
+    #[test]
+    fn test_git_replaces_snapshot_only_with_force() {
+        let dir = TempDir::new("cower_test_git").unwrap();
+        let remote = bare_remote(dir.path());
+        let target = dir.path().join("cower");
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join("PKGBUILD"), "pkgver=0\n").unwrap();
+
+        assert!(git_download(&remote.to_string_lossy(), &target, false).is_err());
+
+        // A failed clone leaves the snapshot alone, and nothing behind
+        assert!(git_download("/nonexistent/cower.git", &target, true).is_err());
+        assert_eq!(fs::read_to_string(target.join("PKGBUILD")).unwrap(), "pkgver=0\n");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        let staged = stage_git(&remote.to_string_lossy(), &target, true).unwrap();
+        assert!(staged.diff().unwrap().contains("-pkgver=0\n+pkgver=1\n"));
+        staged.commit().unwrap();
+        assert!(target.join(".git").is_dir());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_git_bad_remote() {
+        let dir = TempDir::new("cower_test_git").unwrap();
+        let err = git_download("/nonexistent/cower.git", &dir.path().join("cower"), false)
+            .err()
+            .unwrap();
+        match err.downcast_ref::<DownloadError>() {
+            Some(DownloadError::GitRemoteFailed { command, .. }) => assert_eq!(command, "clone"),
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+}
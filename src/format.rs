@@ -0,0 +1,146 @@
+use package::AurPkg;
+use time;
+
+/// What each `%` specifier of a format string expands to
+pub const SPECIFIERS: &[(char, &str)] = &[
+    ('a', "date last modified"),
+    ('b', "package base"),
+    ('c', "conflicts"),
+    ('C', "check dependencies"),
+    ('d', "description"),
+    ('D', "dependencies"),
+    ('g', "groups"),
+    ('k', "keywords"),
+    ('l', "licenses"),
+    ('m', "maintainer"),
+    ('M', "make dependencies"),
+    ('n', "name"),
+    ('o', "votes"),
+    ('O', "optional dependencies"),
+    ('p', "popularity"),
+    ('P', "provides"),
+    ('r', "replaces"),
+    ('s', "date first submitted"),
+    ('t', "date flagged out of date, empty if it isn't"),
+    ('u', "upstream URL"),
+    ('v', "version"),
+    ('%', "a literal %"),
+];
+
+/// Backslash escapes understood in format strings
+pub const ESCAPES: &[(char, &str)] = &[
+    ('n', "newline"),
+    ('t', "tab"),
+    ('\\', "a literal backslash"),
+];
+
+/// Expand the specifiers and escapes of `format` for `pkg`, joining lists
+/// with `delim`. Anything unknown is printed as written.
+pub fn format_pkg(format: &str, pkg: &AurPkg, delim: &str) -> String {
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => match chars.next() {
+                Some(spec) => match expand(spec, pkg, delim) {
+                    Some(val) => out.push_str(&val),
+                    None => {
+                        out.push('%');
+                        out.push(spec);
+                    }
+                },
+                None => out.push('%'),
+            },
+            '\\' => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some('\\') => out.push('\\'),
+                Some(other) => {
+                    out.push('\\');
+                    out.push(other);
+                }
+                None => out.push('\\'),
+            },
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn expand(spec: char, pkg: &AurPkg, delim: &str) -> Option<String> {
+    let list = |vals: &[String]| vals.join(delim);
+    Some(match spec {
+        'a' => format_date(pkg.modified()),
+        'b' => pkg.pkgbase().to_string(),
+        'c' => list(pkg.conflicts()),
+        'C' => list(pkg.checkdepends()),
+        'd' => pkg.description().to_string(),
+        'D' => list(pkg.depends()),
+        'g' => list(pkg.groups()),
+        'k' => list(pkg.keywords()),
+        'l' => list(pkg.licenses()),
+        'm' => pkg.maintainer().to_string(),
+        'M' => list(pkg.makedepends()),
+        'n' => pkg.name().to_string(),
+        'o' => pkg.votes().to_string(),
+        'O' => list(pkg.optdepends()),
+        'p' => format!("{:.2}", pkg.popularity()),
+        'P' => list(pkg.provides()),
+        'r' => list(pkg.replaces()),
+        's' => format_date(pkg.submitted()),
+        't' => pkg.out_of_date().map(format_date).unwrap_or_default(),
+        'u' => pkg.upstream_url().to_string(),
+        'v' => pkg.version().to_string(),
+        '%' => "%".to_string(),
+        _ => return None,
+    })
+}
+
+/// A timestamp from the AUR in the local format
+pub fn format_date(secs: u64) -> String {
+    let tm = time::at(time::Timespec::new(secs as i64, 0));
+    time::strftime("%c", &tm).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use package::aur_packages_from_json;
+
+    fn cower() -> AurPkg {
+        let mut pkgs = aur_packages_from_json(
+            r#"{"version":5,"type":"multiinfo","resultcount":1,"results":[
+            {"ID":1,"Name":"cower","PackageBaseID":1,"PackageBase":"cower","Version":"2",
+             "Description":"A simple AUR agent","URL":"","NumVotes":42,"Popularity":1.5,
+             "OutOfDate":null,"Maintainer":"falconindy","FirstSubmitted":0,
+             "LastModified":0,"URLPath":"","Depends":["curl","pacman"]}]}"#,
+        ).unwrap();
+        pkgs.remove(0)
+    }
+
+    #[test]
+    fn test_format_pkg() {
+        let pkg = cower();
+        assert_eq!(format_pkg("%n %v", &pkg, " "), "cower 2");
+        assert_eq!(format_pkg("%D|%t|%o", &pkg, ", "), "curl, pacman||42");
+        assert_eq!(format_pkg("%n\\t%p\\n", &pkg, " "), "cower\t1.50\n");
+        assert_eq!(format_pkg("100%% %m", &pkg, " "), "100% falconindy");
+    }
+
+    #[test]
+    fn test_unknown_specifiers_are_kept() {
+        let pkg = cower();
+        assert_eq!(format_pkg("%z \\q %", &pkg, " "), "%z \\q %");
+    }
+
+    #[test]
+    fn test_every_specifier_expands() {
+        let pkg = cower();
+        for &(spec, _) in SPECIFIERS {
+            assert!(expand(spec, &pkg, " ").is_some(), "%{}", spec);
+        }
+        for &(escape, _) in ESCAPES {
+            assert_ne!(format_pkg(&format!("\\{}", escape), &pkg, " ").len(), 2);
+        }
+    }
+}
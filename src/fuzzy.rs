@@ -0,0 +1,183 @@
+use std::cmp;
+
+/// Where the AUR publishes the names of all its packages, relative to
+/// the domain
+pub const PACKAGES_URLPATH: &str = "/packages.gz";
+
+/// A package name close to what was asked for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    pub name: String,
+    /// Edits needed to turn the query into the name
+    pub distance: usize,
+    /// Whether the query appears in the name in order, e.g. `pamod` in
+    /// `pulseaudio-modules`
+    pub subsequence: bool,
+}
+
+/// Parse the AUR's `packages.gz` list, one name per line with `#`
+/// comments
+pub fn parse_package_list(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+/// The most edits a name may be away from `query` to still count as a
+/// typo of it
+pub fn max_distance(query: &str) -> usize {
+    cmp::max(2, query.chars().count() / 3)
+}
+
+/// Rank `names` by how closely they match `query`, best first. Names
+/// are kept if they are a likely typo of the query or contain it as a
+/// subsequence, and `limit` caps how many are returned.
+pub fn fuzzy_search<'a, I>(query: &str, names: I, limit: usize) -> Vec<Match>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let query = query.to_lowercase();
+    let query_len = query.chars().count();
+    let threshold = max_distance(&query);
+
+    let mut matches: Vec<Match> = names
+        .into_iter()
+        .filter_map(|name| {
+            let lower = name.to_lowercase();
+            let subsequence = is_subsequence(&query, &lower);
+
+            // The length difference is a lower bound on the distance,
+            // so most names can be skipped without computing it
+            let name_len = lower.chars().count();
+            let len_diff = cmp::max(name_len, query_len) - cmp::min(name_len, query_len);
+            if !subsequence && len_diff > threshold {
+                return None;
+            }
+
+            let distance = levenshtein(&query, &lower);
+            if subsequence || distance <= threshold {
+                Some(Match {
+                    name: name.to_string(),
+                    distance,
+                    subsequence,
+                })
+            } else {
+                None
+            }
+        }).collect();
+
+    matches.sort_by(|a, b| {
+        a.distance
+            .cmp(&b.distance)
+            .then_with(|| b.subsequence.cmp(&a.subsequence))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    matches.truncate(limit);
+    matches
+}
+
+/// Names that are probably what was meant by a `target` that wasn't found
+pub fn suggest<'a, I>(target: &str, names: I, limit: usize) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let threshold = max_distance(target);
+    fuzzy_search(target, names, limit)
+        .into_iter()
+        .filter(|m| m.distance > 0 && m.distance <= threshold)
+        .map(|m| m.name)
+        .collect()
+}
+
+/// Edit distance between two strings, counting insertions, deletions
+/// and substitutions of single characters
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + if ca == *cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = cmp::min(substitution, cmp::min(row[j], row[j + 1]) + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Whether every character of `needle` appears in `haystack`, in order
+fn is_subsequence(needle: &str, haystack: &str) -> bool {
+    let mut haystack = haystack.chars();
+    needle.chars().all(|c| haystack.any(|h| h == c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMES: &[&str] = &[
+        "pulseaudio-modules-bt",
+        "pulseaudio-modules-bt-git",
+        "pulseaudio-module-xrdp",
+        "pulseaudio-ctl",
+        "cower",
+        "cower-git",
+        "tower",
+        "auracle-git",
+    ];
+
+    fn names(matches: &[Match]) -> Vec<&str> {
+        matches.iter().map(|m| m.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_levenshtein() {
+        assert_eq!(levenshtein("", ""), 0);
+        assert_eq!(levenshtein("cower", "cower"), 0);
+        assert_eq!(levenshtein("cower", "tower"), 1);
+        assert_eq!(levenshtein("cower", "cowr"), 1);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("", "abc"), 3);
+    }
+
+    #[test]
+    fn test_is_subsequence() {
+        assert!(is_subsequence("pamod", "pulseaudio-modules"));
+        assert!(is_subsequence("", "cower"));
+        assert!(!is_subsequence("cwoer", "cower"));
+    }
+
+    #[test]
+    fn test_fuzzy_search() {
+        let matches = fuzzy_search("pulseaudo-modules", NAMES.iter().cloned(), 10);
+        assert_eq!(
+            names(&matches),
+            vec!["pulseaudio-modules-bt", "pulseaudio-modules-bt-git"]
+        );
+
+        // Typos and subsequences are ranked together by distance
+        let matches = fuzzy_search("COWR", NAMES.iter().cloned(), 10);
+        assert_eq!(names(&matches), vec!["cower", "tower", "cower-git"]);
+        assert!(matches[0].subsequence);
+        assert!(!matches[1].subsequence);
+
+        assert_eq!(fuzzy_search("cowr", NAMES.iter().cloned(), 1).len(), 1);
+    }
+
+    #[test]
+    fn test_suggest() {
+        assert_eq!(suggest("cwoer", NAMES.iter().cloned(), 3), vec!["cower"]);
+        assert_eq!(suggest("cower", NAMES.iter().cloned(), 3), vec!["tower"]);
+        assert!(suggest("firefox", NAMES.iter().cloned(), 3).is_empty());
+    }
+
+    #[test]
+    fn test_parse_package_list() {
+        let list = "# AUR package list, generated on Sun, 18 Oct 2026\ncower\n\nauracle-git\n";
+        assert_eq!(parse_package_list(list), vec!["cower", "auracle-git"]);
+    }
+}
@@ -0,0 +1,236 @@
+use deps::{Constraint, Depend};
+use failure::Error;
+use package::vercmp;
+use regex::Regex;
+use std::fmt;
+
+#[derive(Debug, Fail)]
+pub enum IgnoreError {
+    #[fail(display = "Invalid ignore pattern '{}': {}", pattern, reason)]
+    InvalidPattern { pattern: String, reason: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IgnoreKind {
+    Package,
+    Group,
+}
+
+/// A single `IgnorePkg` or `IgnoreGroup` entry.
+///
+/// Names are shell globs (`python-*`, `lib?`, `[ab]*`) unless wrapped in
+/// slashes, in which case they are regular expressions (`/^python2?-/`).
+/// Package rules may carry a version pin like `foo<2.0`, which only
+/// ignores updates to versions that don't satisfy the pin.
+pub struct IgnoreRule {
+    kind: IgnoreKind,
+    source: String,
+    pattern: Regex,
+    pin: Option<(Constraint, String)>,
+}
+
+impl IgnoreRule {
+    pub fn new(kind: IgnoreKind, rule: &str) -> Result<Self, Error> {
+        let rule = rule.trim();
+        let invalid = |reason: &str| IgnoreError::InvalidPattern {
+            pattern: rule.to_string(),
+            reason: reason.to_string(),
+        };
+
+        // Split off a version pin, but not from inside a regex
+        let is_regex = rule.len() > 1 && rule.starts_with('/');
+        let (name, pin) = if kind == IgnoreKind::Package && !is_regex {
+            let dep = Depend::parse(rule);
+            if let Some((_, ref version)) = dep.constraint {
+                if version.is_empty() {
+                    return Err(Error::from(invalid("missing version")));
+                }
+            }
+            (dep.name, dep.constraint)
+        } else {
+            (rule.to_string(), None)
+        };
+
+        if name.is_empty() {
+            return Err(Error::from(invalid("missing name")));
+        }
+
+        let regex = if is_regex {
+            if !name.ends_with('/') {
+                return Err(Error::from(invalid("unterminated regex")));
+            }
+            name[1..name.len() - 1].to_string()
+        } else {
+            glob_to_regex(&name)
+        };
+        let pattern = Regex::new(&regex).map_err(|e| invalid(&e.to_string()))?;
+
+        Ok(IgnoreRule {
+            kind,
+            source: rule.to_string(),
+            pattern,
+            pin,
+        })
+    }
+
+    pub fn kind(&self) -> IgnoreKind {
+        self.kind
+    }
+
+    /// Whether this rule suppresses updating `name`, a member of
+    /// `groups`, to `version`
+    pub fn matches(&self, name: &str, groups: &[String], version: &str) -> bool {
+        match self.kind {
+            IgnoreKind::Group => groups.iter().any(|g| self.pattern.is_match(g)),
+            IgnoreKind::Package => {
+                if !self.pattern.is_match(name) {
+                    return false;
+                }
+                match self.pin {
+                    Some((constraint, ref pinned)) => !constraint.allows(vercmp(version, pinned)),
+                    None => true,
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for IgnoreRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            IgnoreKind::Package => write!(f, "IgnorePkg = {}", self.source)?,
+            IgnoreKind::Group => write!(f, "IgnoreGroup = {}", self.source)?,
+        }
+        if let Some((constraint, ref pinned)) = self.pin {
+            write!(f, " (pinned to {} {})", constraint.as_str(), pinned)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every ignore rule from the configuration
+#[derive(Default)]
+pub struct IgnoreList {
+    rules: Vec<IgnoreRule>,
+}
+
+impl IgnoreList {
+    pub fn new(pkgs: &[String], groups: &[String]) -> Result<Self, Error> {
+        let mut rules = Vec::new();
+        for rule in pkgs {
+            rules.push(IgnoreRule::new(IgnoreKind::Package, rule)?);
+        }
+        for rule in groups {
+            rules.push(IgnoreRule::new(IgnoreKind::Group, rule)?);
+        }
+        Ok(IgnoreList { rules })
+    }
+
+    /// Get the first rule suppressing an update of `name` to `version`
+    pub fn check(&self, name: &str, groups: &[String], version: &str) -> Option<&IgnoreRule> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(name, groups, version))
+    }
+}
+
+/// Translate a shell glob into an anchored regular expression
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut in_class = false;
+
+    for c in glob.chars() {
+        if in_class {
+            match c {
+                ']' => {
+                    in_class = false;
+                    regex.push(']');
+                }
+                '!' if regex.ends_with('[') => regex.push('^'),
+                '\\' => regex.push_str("\\\\"),
+                c => regex.push(c),
+            }
+            continue;
+        }
+
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => {
+                in_class = true;
+                regex.push('[');
+            }
+            c => regex.push_str(&::regex::escape(&c.to_string())),
+        }
+    }
+
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(pkgs: &[&str], groups: &[&str]) -> IgnoreList {
+        let pkgs: Vec<String> = pkgs.iter().map(|s| s.to_string()).collect();
+        let groups: Vec<String> = groups.iter().map(|s| s.to_string()).collect();
+        IgnoreList::new(&pkgs, &groups).unwrap()
+    }
+
+    #[test]
+    fn test_glob_to_regex() {
+        assert_eq!(glob_to_regex("python-*"), "^python\\-.*$");
+        assert_eq!(glob_to_regex("lib?.so"), "^lib.\\.so$");
+        assert_eq!(glob_to_regex("[ab]*"), "^[ab].*$");
+        assert_eq!(glob_to_regex("[!ab]*"), "^[^ab].*$");
+    }
+
+    #[test]
+    fn test_exact_and_glob() {
+        let ignore = list(&["cower", "python-*", "lib[xy]"], &[]);
+        assert!(ignore.check("cower", &[], "1").is_some());
+        assert!(ignore.check("cower-git", &[], "1").is_none());
+        assert!(ignore.check("python-requests", &[], "1").is_some());
+        assert!(ignore.check("python2-requests", &[], "1").is_none());
+        assert!(ignore.check("libx", &[], "1").is_some());
+        assert!(ignore.check("libz", &[], "1").is_none());
+    }
+
+    #[test]
+    fn test_regex() {
+        let ignore = list(&["/^python2?-/"], &[]);
+        assert!(ignore.check("python-foo", &[], "1").is_some());
+        assert!(ignore.check("python2-foo", &[], "1").is_some());
+        assert!(ignore.check("cpython-foo", &[], "1").is_none());
+    }
+
+    #[test]
+    fn test_groups() {
+        let ignore = list(&[], &["kde-*"]);
+        let groups = vec!["kde-applications".to_string()];
+        let rule = ignore.check("dolphin-git", &groups, "1").unwrap();
+        assert_eq!(rule.kind(), IgnoreKind::Group);
+        assert_eq!(rule.to_string(), "IgnoreGroup = kde-*");
+        assert!(ignore.check("dolphin-git", &[], "1").is_none());
+    }
+
+    #[test]
+    fn test_version_pin() {
+        let ignore = list(&["foo<2.0"], &[]);
+        assert!(ignore.check("foo", &[], "1.9-1").is_none());
+        assert!(ignore.check("foo", &[], "2.1-1").is_some());
+        assert_eq!(
+            ignore.check("foo", &[], "2.1-1").unwrap().to_string(),
+            "IgnorePkg = foo<2.0 (pinned to < 2.0)"
+        );
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(IgnoreRule::new(IgnoreKind::Package, "foo<").is_err());
+        assert!(IgnoreRule::new(IgnoreKind::Package, "<2.0").is_err());
+        assert!(IgnoreRule::new(IgnoreKind::Package, "/(unclosed/").is_err());
+        assert!(IgnoreRule::new(IgnoreKind::Package, "/foo").is_err());
+    }
+}
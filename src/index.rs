@@ -0,0 +1,397 @@
+use failure::Error;
+use package::AurPkg;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::process;
+
+const MAGIC: &[u8] = b"CWRIDX1\n";
+
+#[derive(Debug, Fail)]
+pub enum IndexError {
+    #[fail(display = "Corrupt search index: {}", reason)]
+    Corrupt { reason: String },
+}
+
+/// What part of a package a search looks at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Name,
+    NameDesc,
+    Keywords,
+}
+
+/// An inverted index over the packages of a metadata dump.
+///
+/// Names and descriptions are indexed by their lowercased byte
+/// trigrams, so any substring of three or more bytes can be narrowed
+/// down to a handful of candidates before checking them for real.
+/// Keywords are indexed as whole tokens.
+pub struct SearchIndex {
+    /// Identifies the dump the index was built from
+    fingerprint: u64,
+    count: u32,
+    trigrams: HashMap<[u8; 3], Vec<u32>>,
+    keywords: HashMap<String, Vec<u32>>,
+}
+
+impl SearchIndex {
+    pub fn build(pkgs: &[AurPkg], fingerprint: u64) -> Self {
+        let mut trigrams: HashMap<[u8; 3], Vec<u32>> = HashMap::new();
+        let mut keywords: HashMap<String, Vec<u32>> = HashMap::new();
+
+        for (id, pkg) in pkgs.iter().enumerate() {
+            let id = id as u32;
+            let text = searchable_text(pkg);
+            for trigram in text.as_bytes().windows(3) {
+                let postings = trigrams.entry([trigram[0], trigram[1], trigram[2]]).or_default();
+                // Ids only ever grow, so a repeat is always the last entry
+                if postings.last() != Some(&id) {
+                    postings.push(id);
+                }
+            }
+            for keyword in pkg.keywords() {
+                let postings = keywords.entry(keyword.to_lowercase()).or_default();
+                if postings.last() != Some(&id) {
+                    postings.push(id);
+                }
+            }
+        }
+
+        SearchIndex {
+            fingerprint,
+            count: pkgs.len() as u32,
+            trigrams,
+            keywords,
+        }
+    }
+
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    /// How many packages the index was built over
+    pub fn package_count(&self) -> usize {
+        self.count as usize
+    }
+
+    /// Ids of the packages matching `arg`, best ranked first
+    pub fn search(&self, pkgs: &[AurPkg], field: Field, arg: &str) -> Vec<u32> {
+        let arg = arg.to_lowercase();
+        let mut ids: Vec<u32> = match field {
+            Field::Keywords => self.keywords.get(&arg).cloned().unwrap_or_default(),
+            Field::Name | Field::NameDesc => self
+                .candidates(&arg)
+                .into_iter()
+                .filter(|&id| {
+                    let pkg = &pkgs[id as usize];
+                    pkg.name().to_lowercase().contains(&arg)
+                        || field == Field::NameDesc
+                            && pkg.description().to_lowercase().contains(&arg)
+                }).collect(),
+        };
+
+        ids.sort_by(|&a, &b| rank(&pkgs[a as usize], &pkgs[b as usize]));
+        ids
+    }
+
+    /// Ids of every package whose name or description might contain
+    /// `literal`. Lets regex searches skip most of the dump by checking
+    /// only the candidates for the regex's longest literal.
+    pub fn candidates(&self, literal: &str) -> Vec<u32> {
+        let literal = literal.to_lowercase();
+        if literal.len() < 3 {
+            return (0..self.count).collect();
+        }
+
+        let mut lists: Vec<&Vec<u32>> = Vec::new();
+        for trigram in literal.as_bytes().windows(3) {
+            match self.trigrams.get(&[trigram[0], trigram[1], trigram[2]]) {
+                Some(postings) => lists.push(postings),
+                None => return Vec::new(),
+            }
+        }
+
+        // Start from the rarest trigram to keep the intersections small
+        lists.sort_by_key(|postings| postings.len());
+        let mut ids = lists[0].clone();
+        for postings in &lists[1..] {
+            ids = intersect(&ids, postings);
+            if ids.is_empty() {
+                break;
+            }
+        }
+        ids
+    }
+
+    /// Write the index to `path`, replacing it atomically
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let tmp = path.with_extension(format!("tmp.{}", process::id()));
+        {
+            let mut out = BufWriter::new(File::create(&tmp)?);
+            out.write_all(MAGIC)?;
+            out.write_all(&self.fingerprint.to_le_bytes())?;
+            write_u32(&mut out, self.count)?;
+
+            write_u32(&mut out, self.trigrams.len() as u32)?;
+            for (trigram, postings) in &self.trigrams {
+                out.write_all(trigram)?;
+                write_postings(&mut out, postings)?;
+            }
+
+            write_u32(&mut out, self.keywords.len() as u32)?;
+            for (keyword, postings) in &self.keywords {
+                write_u32(&mut out, keyword.len() as u32)?;
+                out.write_all(keyword.as_bytes())?;
+                write_postings(&mut out, postings)?;
+            }
+            out.flush()?;
+        }
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        // Nothing stored can be longer than the file itself
+        let size = file.metadata()?.len();
+        let mut input = BufReader::new(file);
+
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(corrupt("bad magic"));
+        }
+
+        let mut fingerprint = [0; 8];
+        input.read_exact(&mut fingerprint)?;
+        let fingerprint = u64::from_le_bytes(fingerprint);
+        let count = read_u32(&mut input)?;
+
+        let mut trigrams = HashMap::new();
+        for _ in 0..read_u32(&mut input)? {
+            let mut trigram = [0; 3];
+            input.read_exact(&mut trigram)?;
+            trigrams.insert(trigram, read_postings(&mut input, count, size)?);
+        }
+
+        let mut keywords = HashMap::new();
+        for _ in 0..read_u32(&mut input)? {
+            let mut keyword = vec![0; read_len(&mut input, size)?];
+            input.read_exact(&mut keyword)?;
+            let keyword = String::from_utf8(keyword).map_err(|_| corrupt("bad keyword"))?;
+            keywords.insert(keyword, read_postings(&mut input, count, size)?);
+        }
+
+        Ok(SearchIndex {
+            fingerprint,
+            count,
+            trigrams,
+            keywords,
+        })
+    }
+}
+
+/// Most popular first, votes breaking ties
+fn rank(a: &AurPkg, b: &AurPkg) -> Ordering {
+    b.popularity()
+        .partial_cmp(&a.popularity())
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| b.votes().cmp(&a.votes()))
+        .then_with(|| a.name().cmp(b.name()))
+}
+
+/// The text trigrams are taken from. The separator keeps trigrams from
+/// spanning the name and the description.
+fn searchable_text(pkg: &AurPkg) -> String {
+    format!("{}\n{}", pkg.name(), pkg.description()).to_lowercase()
+}
+
+/// Intersect two sorted lists of ids
+fn intersect(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            Ordering::Less => i += 1,
+            Ordering::Greater => j += 1,
+            Ordering::Equal => {
+                out.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    out
+}
+
+fn corrupt(reason: &str) -> Error {
+    Error::from(IndexError::Corrupt {
+        reason: reason.to_string(),
+    })
+}
+
+fn write_u32<W: Write>(out: &mut W, val: u32) -> Result<(), Error> {
+    out.write_all(&val.to_le_bytes())?;
+    Ok(())
+}
+
+fn read_u32<R: Read>(input: &mut R) -> Result<u32, Error> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Read a length, which can't be more than `limit`
+fn read_len<R: Read>(input: &mut R, limit: u64) -> Result<usize, Error> {
+    let len = read_u32(input)?;
+    if u64::from(len) > limit {
+        return Err(corrupt("length past the end of the file"));
+    }
+    Ok(len as usize)
+}
+
+fn write_postings<W: Write>(out: &mut W, postings: &[u32]) -> Result<(), Error> {
+    write_u32(out, postings.len() as u32)?;
+    for id in postings {
+        write_u32(out, *id)?;
+    }
+    Ok(())
+}
+
+fn read_postings<R: Read>(input: &mut R, count: u32, size: u64) -> Result<Vec<u32>, Error> {
+    let len = read_len(input, size / 4)?;
+    if len > count as usize {
+        return Err(corrupt("posting list too long"));
+    }
+
+    let mut postings = Vec::with_capacity(len);
+    for _ in 0..len {
+        let id = read_u32(input)?;
+        if id >= count || postings.last().map_or(false, |&last| id <= last) {
+            return Err(corrupt("bad package id"));
+        }
+        postings.push(id);
+    }
+    Ok(postings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use tempdir::TempDir;
+
+    fn pkg(name: &str, description: &str, keywords: &[&str], popularity: f64) -> AurPkg {
+        let json = format!(
+            r#"{{"ID":1,"Name":"{0}","PackageBaseID":1,"PackageBase":"{0}",
+                "Version":"1-1","Description":"{1}","URL":"","NumVotes":0,
+                "Popularity":{2},"OutOfDate":null,"Maintainer":"",
+                "FirstSubmitted":0,"LastModified":0,"URLPath":"","Keywords":{3}}}"#,
+            name,
+            description,
+            popularity,
+            serde_json::to_string(keywords).unwrap()
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn pkgs() -> Vec<AurPkg> {
+        vec![
+            pkg("cower", "A simple AUR agent with a pretentious name", &["aur"], 1.0),
+            pkg("auracle-git", "A flexible client for the AUR", &["aur", "git"], 3.0),
+            pkg("python-requests", "HTTP for Humans", &["http"], 2.0),
+        ]
+    }
+
+    fn names(pkgs: &[AurPkg], ids: &[u32]) -> Vec<String> {
+        ids.iter()
+            .map(|&id| pkgs[id as usize].name().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_search() {
+        let pkgs = pkgs();
+        let index = SearchIndex::build(&pkgs, 0);
+
+        // Ranked by popularity
+        assert_eq!(
+            names(&pkgs, &index.search(&pkgs, Field::NameDesc, "AUR")),
+            vec!["auracle-git", "cower"]
+        );
+        assert_eq!(
+            names(&pkgs, &index.search(&pkgs, Field::Name, "aur")),
+            vec!["auracle-git"]
+        );
+        assert_eq!(
+            names(&pkgs, &index.search(&pkgs, Field::Keywords, "HTTP")),
+            vec!["python-requests"]
+        );
+        assert!(index.search(&pkgs, Field::Name, "zzz").is_empty());
+
+        // Too short for trigrams, still found
+        assert_eq!(
+            names(&pkgs, &index.search(&pkgs, Field::Name, "co")),
+            vec!["cower"]
+        );
+    }
+
+    #[test]
+    fn test_trigrams_do_not_span_fields() {
+        let pkgs = vec![pkg("ab", "cd", &[], 0.0)];
+        let index = SearchIndex::build(&pkgs, 0);
+        assert!(index.candidates("bcd").is_empty());
+        assert!(index.candidates("b\nc").len() == 1);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = TempDir::new("cower_test_index").unwrap();
+        let path = dir.path().join("index");
+        let pkgs = pkgs();
+        SearchIndex::build(&pkgs, 42).save(&path).unwrap();
+
+        let index = SearchIndex::load(&path).unwrap();
+        assert_eq!(index.fingerprint(), 42);
+        assert_eq!(
+            names(&pkgs, &index.search(&pkgs, Field::NameDesc, "humans")),
+            vec!["python-requests"]
+        );
+        assert_eq!(
+            names(&pkgs, &index.search(&pkgs, Field::Keywords, "git")),
+            vec!["auracle-git"]
+        );
+
+        fs::write(&path, b"CWRIDX1\nshort").unwrap();
+        assert!(SearchIndex::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_load_rejects_huge_lengths() {
+        let dir = TempDir::new("cower_test_index").unwrap();
+        let path = dir.path().join("index");
+
+        // One keyword claiming to be 4 GiB long
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&[0; 8]);
+        for val in &[1u32, 0, 1, u32::max_value()] {
+            data.extend_from_slice(&val.to_le_bytes());
+        }
+        fs::write(&path, &data).unwrap();
+        assert!(SearchIndex::load(&path).is_err());
+
+        // A posting list longer than the file, with a package count to match
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&[0; 8]);
+        for val in &[u32::max_value(), 1] {
+            data.extend_from_slice(&val.to_le_bytes());
+        }
+        data.extend_from_slice(b"aur");
+        data.extend_from_slice(&u32::max_value().to_le_bytes());
+        fs::write(&path, &data).unwrap();
+        assert!(SearchIndex::load(&path).is_err());
+    }
+}
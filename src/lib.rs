@@ -1,23 +1,47 @@
 #![feature(test)]
 #[macro_use]
 extern crate bitflags;
+extern crate curl;
+extern crate dirs;
 extern crate ferris_says;
+extern crate flate2;
 extern crate isatty;
+#[macro_use]
 extern crate log;
+extern crate regex;
 extern crate serde;
 extern crate serde_json;
+extern crate tar;
 extern crate time;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate failure;
+#[cfg(test)]
+extern crate tempdir;
 extern crate test;
 extern crate url;
 
 pub mod alpm;
 pub mod aur;
+pub mod cache;
 pub mod config;
+pub mod conflicts;
+pub mod deps;
+pub mod diff;
+pub mod download;
+pub mod format;
+pub mod fuzzy;
+pub mod ignore;
+pub mod index;
+pub mod man;
+pub mod manifest;
+pub mod metadata;
 pub mod package;
+pub mod pacman;
+pub mod pool;
+pub mod transport;
+pub mod update;
 
 use ferris_says::say;
 use std::io::BufWriter;
@@ -0,0 +1,119 @@
+/// A man page in roff, built one section at a time
+#[derive(Debug)]
+pub struct ManPage {
+    roff: String,
+}
+
+impl ManPage {
+    /// Start page `name(section)` with its title line and NAME section.
+    /// `source` is shown in the footer, e.g. the program and version.
+    pub fn new(name: &str, section: u8, source: &str, summary: &str) -> Self {
+        let mut page = ManPage {
+            roff: format!(
+                ".TH \"{}\" \"{}\" \"\" \"{}\"\n",
+                escape(&name.to_uppercase()),
+                section,
+                escape(source)
+            ),
+        };
+        page.section("Name");
+        page.roff
+            .push_str(&format!("{} \\- {}\n", escape(name), escape(summary)));
+        page
+    }
+
+    pub fn section(&mut self, title: &str) {
+        self.roff
+            .push_str(&format!(".SH {}\n", escape(&title.to_uppercase())));
+    }
+
+    /// A paragraph of plain text
+    pub fn paragraph(&mut self, text: &str) {
+        self.roff.push_str(&format!(".PP\n{}\n", escape(text)));
+    }
+
+    /// A paragraph already marked up with `bold` and `italic`
+    pub fn markup(&mut self, roff: &str) {
+        self.roff.push_str(&format!(".PP\n{}\n", roff));
+    }
+
+    /// A tagged paragraph, with `term` already marked up
+    pub fn item(&mut self, term: &str, text: &str) {
+        self.roff
+            .push_str(&format!(".TP\n{}\n{}\n", term, escape(text)));
+    }
+
+    /// Lines shown as written, e.g. an example
+    pub fn example(&mut self, lines: &[&str]) {
+        self.roff.push_str(".PP\n.RS 4\n.nf\n");
+        for line in lines {
+            self.roff.push_str(&escape(line));
+            self.roff.push('\n');
+        }
+        self.roff.push_str(".fi\n.RE\n");
+    }
+
+    pub fn into_roff(self) -> String {
+        self.roff
+    }
+}
+
+/// Protect `text` from roff: backslashes, hyphens and control
+/// characters at the start of a line
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for line in text.split('\n') {
+        if !escaped.is_empty() {
+            escaped.push('\n');
+        }
+        if line.starts_with('.') || line.starts_with('\'') {
+            escaped.push_str("\\&");
+        }
+        for c in line.chars() {
+            match c {
+                '\\' => escaped.push_str("\\e"),
+                '-' => escaped.push_str("\\-"),
+                _ => escaped.push(c),
+            }
+        }
+    }
+    escaped
+}
+
+pub fn bold(text: &str) -> String {
+    format!("\\fB{}\\fR", escape(text))
+}
+
+pub fn italic(text: &str) -> String {
+    format!("\\fI{}\\fR", escape(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("--force"), "\\-\\-force");
+        assert_eq!(escape("a\\nb"), "a\\enb");
+        assert_eq!(escape(".hidden\n'quoted"), "\\&.hidden\n\\&'quoted");
+        assert_eq!(bold("-d"), "\\fB\\-d\\fR");
+    }
+
+    #[test]
+    fn test_page() {
+        let mut page = ManPage::new("cower", 1, "cower-rs 1.0", "a simple AUR agent");
+        page.section("Options");
+        page.item(&bold("-f"), "overwrite existing files");
+        page.example(&["cower -d cower"]);
+        assert_eq!(
+            page.into_roff(),
+            ".TH \"COWER\" \"1\" \"\" \"cower\\-rs 1.0\"\n\
+             .SH NAME\n\
+             cower \\- a simple AUR agent\n\
+             .SH OPTIONS\n\
+             .TP\n\\fB\\-f\\fR\noverwrite existing files\n\
+             .PP\n.RS 4\n.nf\ncower \\-d cower\n.fi\n.RE\n"
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use failure::Error;
+use serde_json;
+use std::collections::btree_map;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the manifest is kept, relative to the working directory
+pub const MANIFEST_FILE: &str = ".cower-manifest.json";
+
+#[derive(Debug, Fail)]
+pub enum ManifestError {
+    #[fail(display = "Unable to read download manifest {}: {}", path, message)]
+    Corrupt { path: String, message: String },
+}
+
+/// What was downloaded for a pkgbase
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// A package built from the pkgbase, to look it up by later
+    pub name: String,
+    pub version: String,
+    /// Seconds since the epoch
+    pub downloaded: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Contents {
+    packages: BTreeMap<String, Record>,
+    /// Targets of the last batch that haven't been downloaded yet
+    #[serde(default)]
+    pending: Vec<String>,
+}
+
+/// The record of what has been downloaded to a working directory, so
+/// that re-runs can skip current packages and failed batches can be
+/// picked up where they stopped.
+#[derive(Debug)]
+pub struct Manifest {
+    dir: PathBuf,
+    contents: Contents,
+}
+
+impl Manifest {
+    /// Load the manifest of `dir`, or start an empty one
+    pub fn load(dir: &Path) -> Result<Self, Error> {
+        let path = dir.join(MANIFEST_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(data) => {
+                serde_json::from_str(&data).map_err(|e| ManifestError::Corrupt {
+                    path: path.display().to_string(),
+                    message: e.to_string(),
+                })?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Contents::default(),
+            Err(e) => return Err(Error::from(e)),
+        };
+
+        Ok(Manifest {
+            dir: dir.to_path_buf(),
+            contents,
+        })
+    }
+
+    /// Write the manifest back to its directory
+    pub fn save(&self) -> Result<(), Error> {
+        // Like the cache, go through a temporary file so an interrupted
+        // run never leaves a truncated manifest behind
+        let path = self.dir.join(MANIFEST_FILE);
+        let tmp = path.with_extension(format!("tmp.{}", process::id()));
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(serde_json::to_string_pretty(&self.contents)?.as_bytes())?;
+        }
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, pkgbase: &str) -> Option<&Record> {
+        self.contents.packages.get(pkgbase)
+    }
+
+    /// Every recorded pkgbase, in order
+    pub fn records(&self) -> btree_map::Iter<'_, String, Record> {
+        self.contents.packages.iter()
+    }
+
+    /// Whether `version` of `pkgbase` was downloaded and is still there
+    pub fn is_current(&self, pkgbase: &str, version: &str) -> bool {
+        self.get(pkgbase)
+            .map_or(false, |record| record.version == version)
+            && self.dir.join(pkgbase).is_dir()
+    }
+
+    /// Note that `version` of `pkgbase` was just downloaded
+    pub fn record(&mut self, pkgbase: &str, name: &str, version: &str) {
+        let record = Record {
+            name: name.to_string(),
+            version: version.to_string(),
+            downloaded: now(),
+        };
+        self.contents.packages.insert(pkgbase.to_string(), record);
+    }
+
+    /// Targets left over from an unfinished batch
+    pub fn pending(&self) -> &[String] {
+        &self.contents.pending
+    }
+
+    /// Start a batch of `targets`
+    pub fn set_pending(&mut self, targets: Vec<String>) {
+        self.contents.pending = targets;
+    }
+
+    /// Take `targets` off the pending list
+    pub fn finish(&mut self, targets: &[String]) {
+        self.contents.pending.retain(|target| !targets.contains(target));
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_missing_manifest_is_empty() {
+        let dir = TempDir::new("cower_test_manifest").unwrap();
+        let manifest = Manifest::load(dir.path()).unwrap();
+        assert_eq!(manifest.records().count(), 0);
+        assert!(manifest.pending().is_empty());
+    }
+
+    #[test]
+    fn test_record_and_reload() {
+        let dir = TempDir::new("cower_test_manifest").unwrap();
+        let mut manifest = Manifest::load(dir.path()).unwrap();
+        manifest.record("pulseaudio-modules-bt", "pulseaudio-modules-bt-git", "1.0-1");
+        manifest.set_pending(vec!["cower".to_string(), "auracle-git".to_string()]);
+        manifest.finish(&["cower".to_string()]);
+        manifest.save().unwrap();
+
+        let manifest = Manifest::load(dir.path()).unwrap();
+        let record = manifest.get("pulseaudio-modules-bt").unwrap();
+        assert_eq!(record.name, "pulseaudio-modules-bt-git");
+        assert_eq!(record.version, "1.0-1");
+        assert_eq!(manifest.pending(), &["auracle-git".to_string()]);
+    }
+
+    #[test]
+    fn test_is_current() {
+        let dir = TempDir::new("cower_test_manifest").unwrap();
+        let mut manifest = Manifest::load(dir.path()).unwrap();
+        manifest.record("cower", "cower", "18-1");
+
+        // The directory has to still be there
+        assert!(!manifest.is_current("cower", "18-1"));
+        fs::create_dir(dir.path().join("cower")).unwrap();
+        assert!(manifest.is_current("cower", "18-1"));
+        assert!(!manifest.is_current("cower", "18-2"));
+        assert!(!manifest.is_current("auracle-git", "18-1"));
+    }
+
+    #[test]
+    fn test_corrupt_manifest() {
+        let dir = TempDir::new("cower_test_manifest").unwrap();
+        fs::write(dir.path().join(MANIFEST_FILE), "garbage").unwrap();
+        assert!(Manifest::load(dir.path()).is_err());
+    }
+}
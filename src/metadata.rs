@@ -0,0 +1,379 @@
+use cache::fnv1a;
+use deps::Depend;
+use dirs;
+use failure::Error;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use index::{Field, SearchIndex};
+use package::AurPkg;
+use serde_json;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use url::Url;
+
+/// Where the AUR publishes its metadata dump, relative to the domain
+pub const DUMP_URLPATH: &str = "/packages-meta-ext-v1.json.gz";
+
+const DB_FILE: &str = "packages-meta-ext-v1.json.gz";
+const INDEX_FILE: &str = "search-index.bin";
+
+#[derive(Debug, Fail)]
+pub enum MetadataError {
+    #[fail(display = "No package database at {}, run `cower sync-db' first", path)]
+    NotSynced { path: String },
+    #[fail(display = "Invalid metadata dump: {}", reason)]
+    InvalidDump { reason: String },
+    #[fail(display = "Can't answer {} offline", url)]
+    UnsupportedQuery { url: String },
+}
+
+/// A local copy of the AUR metadata dump, answering RPC queries without
+/// touching the network
+pub struct MetadataDb {
+    pkgs: Vec<AurPkg>,
+    by_name: HashMap<String, usize>,
+    index: SearchIndex,
+}
+
+impl MetadataDb {
+    /// `$XDG_DATA_HOME/cower`, falling back to `~/.local/share/cower`
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("cower"))
+    }
+
+    /// Parse a dump, gzipped or not, and index it
+    pub fn from_dump(dump: &[u8]) -> Result<Self, Error> {
+        MetadataDb::from_dump_with_index(dump, None)
+    }
+
+    /// Parse a dump, reusing `index` if it was built from the same dump.
+    /// Its ids are only trusted if it covers exactly the dump's packages.
+    fn from_dump_with_index(dump: &[u8], index: Option<SearchIndex>) -> Result<Self, Error> {
+        let json = decompress(dump)?;
+        let pkgs: Vec<AurPkg> =
+            serde_json::from_slice(&json).map_err(|e| MetadataError::InvalidDump {
+                reason: e.to_string(),
+            })?;
+
+        let fingerprint = fnv1a(&json);
+        let index = index
+            .filter(|index| {
+                index.fingerprint() == fingerprint && index.package_count() == pkgs.len()
+            })
+            .unwrap_or_else(|| SearchIndex::build(&pkgs, fingerprint));
+
+        let by_name = pkgs
+            .iter()
+            .enumerate()
+            .map(|(idx, pkg)| (pkg.name().to_string(), idx))
+            .collect();
+        Ok(MetadataDb {
+            pkgs,
+            by_name,
+            index,
+        })
+    }
+
+    /// Load the database installed in `dir` by `import`. A missing or
+    /// stale search index is rebuilt.
+    pub fn load(dir: &Path) -> Result<Self, Error> {
+        let path = dir.join(DB_FILE);
+        let mut dump = Vec::new();
+        File::open(&path)
+            .map_err(|_| MetadataError::NotSynced {
+                path: path.display().to_string(),
+            })?.read_to_end(&mut dump)?;
+
+        let index = SearchIndex::load(&dir.join(INDEX_FILE)).ok();
+        let saved = index
+            .as_ref()
+            .map(|index| (index.fingerprint(), index.package_count()));
+        let db = MetadataDb::from_dump_with_index(&dump, index)?;
+        if saved != Some((db.index.fingerprint(), db.index.package_count())) {
+            // Only saves rebuilding next time, not worth failing over
+            let _ = db.index.save(&dir.join(INDEX_FILE));
+        }
+        Ok(db)
+    }
+
+    /// Check `dump` and install it as the database in `dir`, replacing
+    /// any older one. Returns the number of packages imported.
+    pub fn import(dump: &[u8], dir: &Path) -> Result<usize, Error> {
+        let db = MetadataDb::from_dump(dump)?;
+
+        // Keep the database compressed, whatever we were given
+        let compressed = if is_gzip(dump) {
+            dump.to_vec()
+        } else {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(dump)?;
+            encoder.finish()?
+        };
+
+        fs::create_dir_all(dir)?;
+        let path = dir.join(DB_FILE);
+        let tmp = path.with_extension(format!("tmp.{}", process::id()));
+        File::create(&tmp)?.write_all(&compressed)?;
+        fs::rename(&tmp, &path)?;
+        db.index.save(&dir.join(INDEX_FILE))?;
+        Ok(db.len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.pkgs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pkgs.is_empty()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.pkgs.iter().map(|pkg| pkg.name())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&AurPkg> {
+        self.by_name.get(name).map(|&idx| &self.pkgs[idx])
+    }
+
+    /// Answer an RPC `info` or `search` URL the way the AUR would
+    pub fn query(&self, url: &Url) -> Result<Vec<AurPkg>, Error> {
+        let mut query_type = String::new();
+        let mut by = String::from("name-desc");
+        let mut arg = String::new();
+        let mut names = Vec::new();
+        for (key, val) in url.query_pairs() {
+            match key.as_ref() {
+                "type" => query_type = val.into_owned(),
+                "by" => by = val.into_owned(),
+                "arg" => arg = val.into_owned(),
+                "arg[]" => names.push(val.into_owned()),
+                _ => (),
+            }
+        }
+
+        let unsupported = || {
+            Error::from(MetadataError::UnsupportedQuery {
+                url: url.to_string(),
+            })
+        };
+        let pkgs: Vec<&AurPkg> = match (query_type.as_str(), by.as_str()) {
+            ("info", _) | ("multiinfo", _) => {
+                names.iter().filter_map(|name| self.get(name)).collect()
+            }
+            ("search", "maintainer") => {
+                let arg = arg.to_lowercase();
+                self.pkgs
+                    .iter()
+                    .filter(|pkg| pkg.maintainer().to_lowercase() == arg)
+                    .collect()
+            }
+            // Packages provide their own name too
+            ("search", "provides") => self
+                .pkgs
+                .iter()
+                .filter(|pkg| {
+                    pkg.name() == arg || pkg.provides().iter().any(|p| Depend::parse(p).name == arg)
+                }).collect(),
+            ("search", "replaces") => self
+                .pkgs
+                .iter()
+                .filter(|pkg| pkg.replaces().iter().any(|r| Depend::parse(r).name == arg))
+                .collect(),
+            ("search", by) => {
+                let field = match by {
+                    "name" => Field::Name,
+                    "name-desc" => Field::NameDesc,
+                    "keywords" => Field::Keywords,
+                    _ => return Err(unsupported()),
+                };
+                self.search(field, &arg)
+            }
+            _ => return Err(unsupported()),
+        };
+        Ok(pkgs.into_iter().cloned().collect())
+    }
+
+    /// Search with the index, most popular packages first
+    pub fn search(&self, field: Field, arg: &str) -> Vec<&AurPkg> {
+        self.index
+            .search(&self.pkgs, field, arg)
+            .into_iter()
+            .map(|id| &self.pkgs[id as usize])
+            .collect()
+    }
+}
+
+fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&[0x1f, 0x8b])
+}
+
+fn decompress(dump: &[u8]) -> Result<Vec<u8>, Error> {
+    if !is_gzip(dump) {
+        return Ok(dump.to_vec());
+    }
+
+    let mut json = Vec::new();
+    GzDecoder::new(dump)
+        .read_to_end(&mut json)
+        .map_err(|e| MetadataError::InvalidDump {
+            reason: e.to_string(),
+        })?;
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    const DUMP: &str = r#"[
+        {"ID":1,"Name":"cower","PackageBaseID":1,"PackageBase":"cower",
+         "Version":"17-2","Description":"A simple AUR agent with a pretentious name",
+         "URL":"https://github.com/falconindy/cower","NumVotes":590,
+         "Popularity":1.5,"OutOfDate":null,"Maintainer":"falconindy",
+         "FirstSubmitted":1293676237,"LastModified":1441804093,
+         "URLPath":"/cgit/aur.git/snapshot/cower.tar.gz",
+         "Depends":["curl","pacman"],"Replaces":["cower-git"]},
+        {"ID":2,"Name":"auracle-git","PackageBaseID":2,"PackageBase":"auracle-git",
+         "Version":"r1-1","Description":null,"URL":null,"NumVotes":40,
+         "Popularity":0.5,"OutOfDate":null,"Maintainer":null,
+         "FirstSubmitted":1500000000,"LastModified":1500000000,
+         "URLPath":"/cgit/aur.git/snapshot/auracle-git.tar.gz",
+         "Provides":["auracle=r1"]},
+        {"ID":3,"Name":"pacaur","PackageBaseID":3,"PackageBase":"pacaur",
+         "Version":"4.8.6-1","Description":"An AUR helper","URL":"",
+         "NumVotes":1000,"Popularity":2.0,"OutOfDate":1600000000,
+         "Maintainer":"falconindy","FirstSubmitted":1300000000,
+         "LastModified":1600000000,"URLPath":"/cgit/aur.git/snapshot/pacaur.tar.gz"}
+    ]"#;
+
+    fn db() -> MetadataDb {
+        MetadataDb::from_dump(DUMP.as_bytes()).unwrap()
+    }
+
+    fn query(db: &MetadataDb, query: &str) -> Vec<String> {
+        let url = Url::parse(&format!("https://aur.archlinux.org/rpc.php?v=5&{}", query)).unwrap();
+        let mut names: Vec<String> = db
+            .query(&url)
+            .unwrap()
+            .iter()
+            .map(|pkg| pkg.name().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_from_dump() {
+        let db = db();
+        assert_eq!(db.len(), 3);
+        assert_eq!(db.get("cower").unwrap().depends(), &["curl", "pacman"]);
+        assert_eq!(db.get("auracle-git").unwrap().maintainer(), "");
+        assert!(db.get("yay").is_none());
+    }
+
+    #[test]
+    fn test_info() {
+        let db = db();
+        assert_eq!(
+            query(&db, "type=info&arg[]=cower&arg[]=yay&arg[]=pacaur"),
+            vec!["cower", "pacaur"]
+        );
+    }
+
+    #[test]
+    fn test_search() {
+        let db = db();
+        assert_eq!(query(&db, "type=search&by=name&arg=AURA"), vec!["auracle-git"]);
+        assert_eq!(
+            query(&db, "type=search&by=name-desc&arg=aur"),
+            vec!["auracle-git", "cower", "pacaur"]
+        );
+        assert_eq!(
+            query(&db, "type=search&by=maintainer&arg=falconindy"),
+            vec!["cower", "pacaur"]
+        );
+        assert_eq!(query(&db, "type=search&by=provides&arg=auracle"), vec!["auracle-git"]);
+        assert_eq!(query(&db, "type=search&by=provides&arg=cower"), vec!["cower"]);
+        assert_eq!(query(&db, "type=search&by=replaces&arg=cower-git"), vec!["cower"]);
+        assert!(query(&db, "type=search&by=replaces&arg=cower").is_empty());
+
+        let url = Url::parse("https://aur.archlinux.org/rpc.php?type=search&by=depends&arg=x")
+            .unwrap();
+        assert!(db.query(&url).is_err());
+    }
+
+    #[test]
+    fn test_search_ranking() {
+        let db = db();
+        let names: Vec<&str> = db
+            .search(Field::NameDesc, "aur")
+            .iter()
+            .map(|pkg| pkg.name())
+            .collect();
+        assert_eq!(names, vec!["pacaur", "cower", "auracle-git"]);
+    }
+
+    #[test]
+    fn test_stale_index_is_rebuilt() {
+        let dir = TempDir::new("cower_test_metadata").unwrap();
+        MetadataDb::import(DUMP.as_bytes(), dir.path()).unwrap();
+
+        // An index from some other dump must not be trusted
+        let other = MetadataDb::from_dump(b"[]").unwrap();
+        other.index.save(&dir.path().join(INDEX_FILE)).unwrap();
+
+        let db = MetadataDb::load(dir.path()).unwrap();
+        assert_eq!(db.search(Field::Name, "cower").len(), 1);
+        let saved = SearchIndex::load(&dir.path().join(INDEX_FILE)).unwrap();
+        assert_eq!(saved.fingerprint(), db.index.fingerprint());
+    }
+
+    #[test]
+    fn test_index_with_wrong_count_is_rebuilt() {
+        let dir = TempDir::new("cower_test_metadata").unwrap();
+        MetadataDb::import(DUMP.as_bytes(), dir.path()).unwrap();
+
+        // Same fingerprint, but ids past the end of the dump
+        let db = MetadataDb::load(dir.path()).unwrap();
+        let mut pkgs = db.pkgs.clone();
+        pkgs.extend(db.pkgs.clone());
+        let index = SearchIndex::build(&pkgs, db.index.fingerprint());
+        index.save(&dir.path().join(INDEX_FILE)).unwrap();
+
+        let db = MetadataDb::load(dir.path()).unwrap();
+        assert_eq!(db.search(Field::Name, "cower").len(), 1);
+        let saved = SearchIndex::load(&dir.path().join(INDEX_FILE)).unwrap();
+        assert_eq!(saved.package_count(), 3);
+    }
+
+    #[test]
+    fn test_import_and_load() {
+        let dir = TempDir::new("cower_test_metadata").unwrap();
+        assert!(MetadataDb::load(dir.path()).is_err());
+
+        // Plain JSON gets compressed on import
+        assert_eq!(MetadataDb::import(DUMP.as_bytes(), dir.path()).unwrap(), 3);
+        let mut stored = Vec::new();
+        File::open(dir.path().join(DB_FILE))
+            .unwrap()
+            .read_to_end(&mut stored)
+            .unwrap();
+        assert!(is_gzip(&stored));
+
+        // A gzipped dump is installed as is
+        assert_eq!(MetadataDb::import(&stored, dir.path()).unwrap(), 3);
+        assert_eq!(MetadataDb::load(dir.path()).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_import_rejects_garbage() {
+        let dir = TempDir::new("cower_test_metadata").unwrap();
+        assert!(MetadataDb::import(b"<html>", dir.path()).is_err());
+        assert!(!dir.path().join(DB_FILE).exists());
+    }
+}
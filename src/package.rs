@@ -1,5 +1,8 @@
 use alpm::alpm_pkg_vercmp;
+use aur::AurTErrors;
+use config::Sortable;
 use failure::Error;
+use serde::{Deserialize, Deserializer};
 use serde_json;
 use std::cmp::Ordering;
 use std::ffi::CString;
@@ -11,19 +14,21 @@ struct Query {
     query_type: String,
     resultcount: u64,
     results: Vec<AurPkg>,
+    #[serde(default)]
+    error: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AurPkg {
     #[serde(rename = "Name")]
     name: String,
-    #[serde(rename = "Description")]
+    #[serde(rename = "Description", deserialize_with = "null_as_empty")]
     description: String,
-    #[serde(rename = "Maintainer")]
+    #[serde(rename = "Maintainer", deserialize_with = "null_as_empty")]
     maintainer: String,
     #[serde(rename = "PackageBase")]
     pkgbase: String,
-    #[serde(rename = "URL")]
+    #[serde(rename = "URL", deserialize_with = "null_as_empty")]
     upstream_url: String,
     #[serde(rename = "URLPath")]
     aur_urlpath: String,
@@ -69,11 +74,129 @@ pub struct AurPkg {
     keywords: Vec<String>,
 }
 
+impl AurPkg {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn maintainer(&self) -> &str {
+        &self.maintainer
+    }
+
+    pub fn pkgbase(&self) -> &str {
+        &self.pkgbase
+    }
+
+    pub fn upstream_url(&self) -> &str {
+        &self.upstream_url
+    }
+
+    pub fn aur_urlpath(&self) -> &str {
+        &self.aur_urlpath
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn votes(&self) -> i64 {
+        self.votes
+    }
+
+    pub fn popularity(&self) -> f64 {
+        self.popularity
+    }
+
+    pub fn out_of_date(&self) -> Option<u64> {
+        self.out_of_date
+    }
+
+    pub fn submitted(&self) -> u64 {
+        self.submitted_s
+    }
+
+    pub fn modified(&self) -> u64 {
+        self.modified_s
+    }
+
+    pub fn licenses(&self) -> &[String] {
+        &self.licenses
+    }
+
+    pub fn conflicts(&self) -> &[String] {
+        &self.conflicts
+    }
+
+    pub fn depends(&self) -> &[String] {
+        &self.depends
+    }
+
+    pub fn groups(&self) -> &[String] {
+        &self.groups
+    }
+
+    pub fn makedepends(&self) -> &[String] {
+        &self.makedepends
+    }
+
+    pub fn optdepends(&self) -> &[String] {
+        &self.optdepends
+    }
+
+    pub fn checkdepends(&self) -> &[String] {
+        &self.checkdepends
+    }
+
+    pub fn provides(&self) -> &[String] {
+        &self.provides
+    }
+
+    pub fn replaces(&self) -> &[String] {
+        &self.replaces
+    }
+
+    pub fn keywords(&self) -> &[String] {
+        &self.keywords
+    }
+}
+
+/// Orphans have no maintainer and some packages lack a description or
+/// URL, all of which the AUR reports as `null`
+fn null_as_empty<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
 pub fn aur_packages_from_json(json: &str) -> Result<Vec<AurPkg>, Error> {
     let p: Query = serde_json::from_str(json)?;
+    if let Some(message) = p.error {
+        return Err(Error::from(AurTErrors::RpcError { message }));
+    }
     Ok(p.results)
 }
 
+impl Sortable for AurPkg {
+    fn sort_func(key: &str) -> Option<fn(&Self, &Self) -> Ordering> {
+        match key {
+            "name" => Some(sort_name),
+            "version" => Some(sort_cmpver),
+            "maintainer" => Some(sort_cmpmaint),
+            "votes" => Some(sort_cmpvotes),
+            "popularity" => Some(sort_cmppopularity),
+            "outofdate" => Some(sort_cmpood),
+            "lastmodified" => Some(sort_cmplastmod),
+            "firstsubmitted" => Some(sort_cmpfirstsub),
+            _ => None,
+        }
+    }
+}
+
 pub fn sort_name(pkg1: &AurPkg, pkg2: &AurPkg) -> Ordering {
     pkg1.name.cmp(&pkg2.name)
 }
@@ -105,8 +228,16 @@ pub fn sort_cmpfirstsub(pkg1: &AurPkg, pkg2: &AurPkg) -> Ordering {
 }
 
 pub fn sort_cmpver(pkg1: &AurPkg, pkg2: &AurPkg) -> Ordering {
-    let ver_str_1 = CString::new(pkg1.version.clone()).unwrap();
-    let ver_str_2 = CString::new(pkg2.version.clone()).unwrap();
+    vercmp(&pkg1.version, &pkg2.version)
+}
+
+/// Compare two version strings the way pacman does
+pub fn vercmp(ver1: &str, ver2: &str) -> Ordering {
+    let (ver_str_1, ver_str_2) = match (CString::new(ver1), CString::new(ver2)) {
+        (Ok(ver_str_1), Ok(ver_str_2)) => (ver_str_1, ver_str_2),
+        // libalpm would stop at the NUL, so compare what we were given
+        _ => return ver1.cmp(ver2),
+    };
 
     // Call into libalpm, pass c strings and get back an int
     let cmp = unsafe { alpm_pkg_vercmp(ver_str_1.as_ptr(), ver_str_2.as_ptr()) }.signum();
@@ -122,6 +253,7 @@ pub fn sort_cmpver(pkg1: &AurPkg, pkg2: &AurPkg) -> Ordering {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use index::{Field, SearchIndex};
     use test::Bencher;
 
     const JSON_EXAMPLE: &str = r#"{
@@ -207,6 +339,47 @@ mod tests {
         b.iter(|| sort_cmpver(&&input[0], &&input[0]))
     }
 
+    /// Pretend dump for the search benchmarks, a third the size of the real one
+    fn synthetic(count: usize) -> Vec<AurPkg> {
+        let words = ["python", "git", "lib", "font", "theme", "bin", "rust", "qt"];
+        (0..count)
+            .map(|i| {
+                let name = format!("{}-{}-{}", words[i % 8], words[(i / 8) % 8], i);
+                let desc = format!("The {} package for {} number {}", words[(i / 3) % 8], name, i);
+                let json = format!(
+                    r#"{{"ID":{0},"Name":"{1}","PackageBaseID":{0},"PackageBase":"{1}",
+                        "Version":"1-1","Description":"{2}","URL":"","NumVotes":0,
+                        "Popularity":{3},"OutOfDate":null,"Maintainer":"",
+                        "FirstSubmitted":0,"LastModified":0,"URLPath":"","Keywords":["{4}"]}}"#,
+                    i,
+                    name,
+                    desc,
+                    i % 97,
+                    words[(i / 5) % 8]
+                );
+                serde_json::from_str(&json).unwrap()
+            }).collect()
+    }
+
+    #[bench]
+    fn bench_index_search(b: &mut Bencher) {
+        let pkgs = synthetic(30_000);
+        let index = SearchIndex::build(&pkgs, 0);
+        b.iter(|| index.search(&pkgs, Field::NameDesc, "font-rust-4"))
+    }
+
+    #[bench]
+    fn bench_linear_search(b: &mut Bencher) {
+        let pkgs = synthetic(30_000);
+        b.iter(|| {
+            pkgs.iter()
+                .filter(|pkg| {
+                    pkg.name().to_lowercase().contains("font-rust-4")
+                        || pkg.description().to_lowercase().contains("font-rust-4")
+                }).count()
+        })
+    }
+
     #[test]
     fn test_parsing_json() {
         let input = aur_packages_from_json(JSON_EXAMPLE);
@@ -248,6 +421,30 @@ mod tests {
         assert_eq!(input.keywords.len(), 0);
     }
 
+    #[test]
+    fn test_vercmp_with_nul() {
+        assert_eq!(vercmp("1\02", "1\03"), Ordering::Less);
+        assert_eq!(vercmp("2", "1\0"), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_parsing_error() {
+        let data = r#"{
+            "version":5,
+            "type":"error",
+            "resultcount":0,
+            "results":[],
+            "error":"Too many package results."
+        }"#;
+
+        let input = aur_packages_from_json(data);
+        assert!(input.is_err());
+        assert_eq!(
+            input.err().unwrap().to_string(),
+            "AUR RPC error: Too many package results."
+        );
+    }
+
     #[test]
     fn test_parsing_search() {
         let data = r#"{
@@ -0,0 +1,457 @@
+use alpm::*;
+use deps::{Depend, Repos};
+use failure::Error;
+use ignore::glob_to_regex;
+use regex::Regex;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::os::raw::{c_char, c_void};
+use std::path::{Path, PathBuf};
+use update::Foreign;
+
+/// Where pacman keeps its configuration
+pub const PACMAN_CONF: &str = "/etc/pacman.conf";
+
+#[derive(Debug, Fail)]
+pub enum PacmanError {
+    #[fail(display = "Unable to initialize libalpm: {}", message)]
+    InitFailed { message: String },
+    #[fail(display = "Unable to register the {} repository", repo)]
+    RegisterFailed { repo: String },
+}
+
+/// The parts of pacman.conf needed to find the databases
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacmanConf {
+    pub root: String,
+    pub dbpath: String,
+    /// Sync repositories, in the order pacman searches them
+    pub repos: Vec<String>,
+}
+
+impl Default for PacmanConf {
+    fn default() -> Self {
+        PacmanConf {
+            root: String::from("/"),
+            dbpath: String::from("/var/lib/pacman/"),
+            repos: Vec::new(),
+        }
+    }
+}
+
+impl PacmanConf {
+    /// Read the config at `path`, following its Include lines, and fall
+    /// back to the defaults if it doesn't exist
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        if !path.exists() {
+            return Ok(PacmanConf::default());
+        }
+        let mut conf = PacmanConf::default();
+        let mut in_options = false;
+        conf.parse_text(&fs::read_to_string(path)?, &mut in_options, &mut read_included, 0);
+        Ok(conf)
+    }
+
+    /// Parse the text of a pacman.conf, without following Include lines
+    pub fn parse(text: &str) -> Self {
+        let mut conf = PacmanConf::default();
+        let mut in_options = false;
+        conf.parse_text(text, &mut in_options, &mut |_| Vec::new(), 0);
+        conf
+    }
+
+    /// Apply `text`, with `in_options` tracking the section across
+    /// included files. `include` returns the text of the files named by
+    /// an Include line.
+    fn parse_text<F>(&mut self, text: &str, in_options: &mut bool, include: &mut F, depth: usize)
+    where
+        F: FnMut(&str) -> Vec<String>,
+    {
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.starts_with('[') && line.ends_with(']') {
+                let section = &line[1..line.len() - 1];
+                *in_options = section == "options";
+                if !*in_options {
+                    self.repos.push(section.to_string());
+                }
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let val = parts.next().unwrap_or("").trim();
+            if key == "Include" && !val.is_empty() {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    debug!("not following Include {}, nested too deeply", val);
+                    continue;
+                }
+                for included in include(val) {
+                    self.parse_text(&included, in_options, include, depth + 1);
+                }
+                continue;
+            }
+            if !*in_options {
+                continue;
+            }
+            match key {
+                "RootDir" if !val.is_empty() => self.root = val.to_string(),
+                "DBPath" if !val.is_empty() => self.dbpath = val.to_string(),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// How deeply Include lines are followed, as in pacman
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// The text of the files matching the Include glob `pattern`, skipping
+/// any that can't be read
+fn read_included(pattern: &str) -> Vec<String> {
+    expand_glob(pattern)
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .collect()
+}
+
+/// The files matching the shell glob `pattern`, sorted. Like the shell,
+/// wildcards don't match a leading dot.
+fn expand_glob(pattern: &str) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::new()];
+    for component in Path::new(pattern).components() {
+        let glob = component.as_os_str().to_string_lossy();
+        if !glob.contains(&['*', '?', '['][..]) {
+            for path in &mut paths {
+                path.push(component.as_os_str());
+            }
+            continue;
+        }
+
+        let regex = match Regex::new(&glob_to_regex(&glob)) {
+            Ok(regex) => regex,
+            Err(_) => return Vec::new(),
+        };
+        let mut matched = Vec::new();
+        for dir in &paths {
+            // A relative pattern starts out in the current directory
+            let readable = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            let entries = match fs::read_dir(readable) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if regex.is_match(&name) && (!name.starts_with('.') || glob.starts_with('.')) {
+                    matched.push(dir.join(entry.file_name()));
+                }
+            }
+        }
+        matched.sort();
+        paths = matched;
+    }
+    paths.into_iter().filter(|path| path.is_file()).collect()
+}
+
+/// A read-only libalpm handle with the sync repositories registered
+pub struct Pacman {
+    handle: *mut alpm_handle_t,
+}
+
+impl Pacman {
+    pub fn new(conf: &PacmanConf) -> Result<Self, Error> {
+        let root = CString::new(conf.root.as_str())?;
+        let dbpath = CString::new(conf.dbpath.as_str())?;
+
+        let mut err: alpm_errno_t = 0;
+        let handle = unsafe { alpm_initialize(root.as_ptr(), dbpath.as_ptr(), &mut err) };
+        if handle.is_null() {
+            let message = unsafe { from_c(alpm_strerror(err)) };
+            return Err(Error::from(PacmanError::InitFailed {
+                message: message.unwrap_or_default(),
+            }));
+        }
+        let pacman = Pacman { handle };
+
+        for repo in &conf.repos {
+            let name = CString::new(repo.as_str())?;
+            // Signatures only matter when installing, so don't check them
+            let db = unsafe { alpm_register_syncdb(pacman.handle, name.as_ptr(), 0) };
+            if db.is_null() {
+                return Err(Error::from(PacmanError::RegisterFailed { repo: repo.clone() }));
+            }
+        }
+        Ok(pacman)
+    }
+
+    /// The name of the first sync repo package called like `depend` that
+    /// satisfies it, ignoring provides
+    pub fn find_sync_package(&self, depend: &str) -> Option<String> {
+        let wanted = Depend::parse(depend);
+        let name = CString::new(wanted.name.as_str()).ok()?;
+        unsafe {
+            for db in list_iter(alpm_get_syncdbs(self.handle)) {
+                let pkg = alpm_db_get_pkg(db as *mut alpm_db_t, name.as_ptr());
+                if pkg.is_null() {
+                    continue;
+                }
+                if wanted.allows(&from_c(alpm_pkg_get_version(pkg)).unwrap_or_default()) {
+                    return Some(wanted.name);
+                }
+            }
+        }
+        None
+    }
+
+    /// Every sync repo package that satisfies `depend` through its
+    /// provides, in repo order
+    pub fn find_sync_providers(&self, depend: &str) -> Vec<String> {
+        let wanted = Depend::parse(depend);
+        let mut providers = Vec::new();
+        unsafe {
+            for db in list_iter(alpm_get_syncdbs(self.handle)) {
+                let pkgs = alpm_db_get_pkgcache(db as *mut alpm_db_t);
+                for pkg in list_iter(pkgs) {
+                    let pkg = pkg as *mut alpm_pkg_t;
+                    let provides = package_provides(pkg);
+                    if provides.is_empty() {
+                        continue;
+                    }
+                    let name = from_c(alpm_pkg_get_name(pkg)).unwrap_or_default();
+                    let version = from_c(alpm_pkg_get_version(pkg)).unwrap_or_default();
+                    if name != wanted.name
+                        && wanted.satisfied_by(&name, &version, &provides)
+                        && !providers.contains(&name)
+                    {
+                        providers.push(name);
+                    }
+                }
+            }
+        }
+        providers
+    }
+
+    /// The name of the installed package that satisfies `depend`
+    pub fn find_installed_satisfier(&self, depend: &str) -> Option<String> {
+        let depend = CString::new(depend).ok()?;
+        unsafe {
+            let pkgs = alpm_db_get_pkgcache(alpm_get_localdb(self.handle));
+            let pkg = alpm_find_satisfier(pkgs, depend.as_ptr());
+            if pkg.is_null() {
+                return None;
+            }
+            from_c(alpm_pkg_get_name(pkg))
+        }
+    }
+
+    /// Installed packages with a conflict that package `name` at
+    /// `version` matches, with the conflict as written
+    pub fn find_installed_conflicts(
+        &self,
+        name: &str,
+        version: &str,
+        provides: &[String],
+    ) -> Vec<(String, String)> {
+        let mut conflicts = Vec::new();
+        unsafe {
+            let pkgs = alpm_db_get_pkgcache(alpm_get_localdb(self.handle));
+            for pkg in list_iter(pkgs) {
+                let pkg = pkg as *mut alpm_pkg_t;
+                for conflict in depend_strings(alpm_pkg_get_conflicts(pkg)) {
+                    if Depend::parse(&conflict).satisfied_by(name, version, provides) {
+                        let installed = from_c(alpm_pkg_get_name(pkg)).unwrap_or_default();
+                        conflicts.push((installed, conflict));
+                    }
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Installed packages that none of the sync repos have, treating
+    /// those in `ignore_repos` as if they weren't there
+    pub fn foreign_packages(&self, ignore_repos: &[String]) -> Vec<Foreign> {
+        let mut foreign = Vec::new();
+        unsafe {
+            let dbs: Vec<*mut alpm_db_t> = list_iter(alpm_get_syncdbs(self.handle))
+                .into_iter()
+                .map(|db| db as *mut alpm_db_t)
+                .filter(|&db| {
+                    let name = from_c(alpm_db_get_name(db)).unwrap_or_default();
+                    !ignore_repos.contains(&name)
+                }).collect();
+
+            for pkg in list_iter(alpm_db_get_pkgcache(alpm_get_localdb(self.handle))) {
+                let pkg = pkg as *mut alpm_pkg_t;
+                let name = alpm_pkg_get_name(pkg);
+                if dbs.iter().any(|&db| !alpm_db_get_pkg(db, name).is_null()) {
+                    continue;
+                }
+                foreign.push(Foreign {
+                    name: from_c(name).unwrap_or_default(),
+                    version: from_c(alpm_pkg_get_version(pkg)).unwrap_or_default(),
+                    installed: alpm_pkg_get_installdate(pkg) as u64,
+                });
+            }
+        }
+        foreign
+    }
+}
+
+impl Repos for Pacman {
+    fn satisfier(&self, depend: &str) -> Option<String> {
+        self.find_sync_package(depend)
+    }
+
+    fn providers(&self, depend: &str) -> Vec<String> {
+        self.find_sync_providers(depend)
+    }
+
+    fn installed(&self, depend: &str) -> Option<String> {
+        self.find_installed_satisfier(depend)
+    }
+
+    fn conflicting(&self, name: &str, version: &str, provides: &[String]) -> Vec<(String, String)> {
+        self.find_installed_conflicts(name, version, provides)
+    }
+}
+
+impl Drop for Pacman {
+    fn drop(&mut self) {
+        unsafe {
+            alpm_release(self.handle);
+        }
+    }
+}
+
+/// The data pointers of an alpm_list_t
+unsafe fn list_iter(mut list: *mut alpm_list_t) -> Vec<*mut c_void> {
+    let mut items = Vec::new();
+    while !list.is_null() {
+        items.push((*list).data);
+        list = (*list).next;
+    }
+    items
+}
+
+/// The provides of `pkg`, as `name=version` strings
+unsafe fn package_provides(pkg: *mut alpm_pkg_t) -> Vec<String> {
+    depend_strings(alpm_pkg_get_provides(pkg))
+}
+
+/// A list of alpm_depend_t, written out like they are in a PKGBUILD
+unsafe fn depend_strings(list: *mut alpm_list_t) -> Vec<String> {
+    list_iter(list)
+        .into_iter()
+        .filter_map(|dep| {
+            let dep = &*(dep as *const alpm_depend_t);
+            let name = from_c(dep.name)?;
+            let op = match dep.mod_ {
+                ALPM_DEP_MOD_EQ => "=",
+                ALPM_DEP_MOD_GE => ">=",
+                ALPM_DEP_MOD_LE => "<=",
+                ALPM_DEP_MOD_GT => ">",
+                ALPM_DEP_MOD_LT => "<",
+                _ => return Some(name),
+            };
+            Some(match from_c(dep.version) {
+                Some(ref version) if !version.is_empty() => format!("{}{}{}", name, op, version),
+                _ => name,
+            })
+        }).collect()
+}
+
+/// Copy a string owned by libalpm
+unsafe fn from_c(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(s).to_string_lossy().into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_parse_conf() {
+        let conf = PacmanConf::parse(
+            r#"
+# General options
+[options]
+RootDir     = /mnt
+DBPath      = /mnt/var/lib/pacman/ # comment
+HoldPkg     = pacman glibc
+
+#[testing]
+#Include = /etc/pacman.d/mirrorlist
+
+[core]
+Include = /etc/pacman.d/mirrorlist
+
+[extra]
+Include = /etc/pacman.d/mirrorlist
+
+[custom]
+SigLevel = Optional TrustAll
+Server = file:///home/custompkgs
+"#,
+        );
+
+        assert_eq!(conf.root, "/mnt");
+        assert_eq!(conf.dbpath, "/mnt/var/lib/pacman/");
+        assert_eq!(conf.repos, vec!["core", "extra", "custom"]);
+    }
+
+    #[test]
+    fn test_load_follows_includes() {
+        let dir = TempDir::new("cower_test_pacman").unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        fs::create_dir(dir.path().join("repos.d")).unwrap();
+        fs::write(
+            path("pacman.conf"),
+            format!(
+                "[options]\nInclude = {}\n[core]\nInclude = {}\nInclude = {}\n",
+                path("options.conf"),
+                path("mirrorlist"),
+                path("repos.d/*.conf")
+            ),
+        ).unwrap();
+        fs::write(path("options.conf"), "DBPath = /srv/pacman/\n").unwrap();
+        fs::write(path("mirrorlist"), "Server = https://example.org/$repo\n").unwrap();
+        fs::write(path("repos.d/b.conf"), "[b]\n").unwrap();
+        fs::write(
+            path("repos.d/a.conf"),
+            format!("[a]\nInclude = {}\n", path("nested")),
+        ).unwrap();
+        fs::write(path("repos.d/.hidden.conf"), "[hidden]\n").unwrap();
+        fs::write(path("repos.d/c.txt"), "[c]\n").unwrap();
+        fs::write(path("nested"), "[nested]\n").unwrap();
+
+        let conf = PacmanConf::load(Path::new(&path("pacman.conf"))).unwrap();
+        assert_eq!(conf.dbpath, "/srv/pacman/");
+        assert_eq!(conf.repos, vec!["core", "a", "nested", "b"]);
+    }
+
+    #[test]
+    fn test_include_loop_stops() {
+        let dir = TempDir::new("cower_test_pacman").unwrap();
+        let conf = dir.path().join("pacman.conf");
+        fs::write(&conf, format!("[loop]\nInclude = {}\n", conf.display())).unwrap();
+        let conf = PacmanConf::load(&conf).unwrap();
+        assert_eq!(conf.repos.len(), MAX_INCLUDE_DEPTH + 1);
+    }
+
+    #[test]
+    fn test_parse_conf_defaults() {
+        let conf = PacmanConf::parse("[options]\nHoldPkg = pacman\n");
+        assert_eq!(conf, PacmanConf::default());
+    }
+}
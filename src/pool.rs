@@ -0,0 +1,200 @@
+use failure::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+#[derive(Debug, Fail)]
+pub enum PoolError {
+    #[fail(display = "Cancelled")]
+    Cancelled,
+    #[fail(display = "Worker thread panicked")]
+    WorkerPanicked,
+}
+
+/// A bounded pool of worker threads.
+///
+/// Tasks are handed out to at most `threads` workers at a time. Every
+/// task gets its own result, returned in the order the tasks were given,
+/// so one failure never stops the others. Once cancelled, tasks that
+/// haven't started yet fail with `PoolError::Cancelled`.
+pub struct TaskPool {
+    threads: usize,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl TaskPool {
+    pub fn new(threads: u64) -> Self {
+        TaskPool {
+            threads: threads.max(1) as usize,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// A flag that cancels the pool once set, e.g. from a signal handler
+    pub fn cancel_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.cancelled)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Run `func` over every task and collect the results in task order
+    pub fn map<I, T, F>(&self, tasks: Vec<I>, func: F) -> Vec<Result<T, Error>>
+    where
+        I: Send + 'static,
+        T: Send + 'static,
+        F: Fn(I) -> Result<T, Error> + Send + Sync + 'static,
+    {
+        let started = Instant::now();
+        let count = tasks.len();
+        let queue = Arc::new(Mutex::new(tasks.into_iter().enumerate()));
+        let func = Arc::new(func);
+        let (tx, rx) = channel();
+
+        let workers: Vec<_> = (0..self.threads.min(count))
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                let func = Arc::clone(&func);
+                let cancelled = Arc::clone(&self.cancelled);
+                let tx = tx.clone();
+
+                thread::spawn(move || loop {
+                    // Don't hold the lock while running the task
+                    let next = queue.lock().unwrap().next();
+                    let (idx, task) = match next {
+                        Some(next) => next,
+                        None => break,
+                    };
+
+                    let result = if cancelled.load(Ordering::SeqCst) {
+                        Err(Error::from(PoolError::Cancelled))
+                    } else {
+                        func(task)
+                    };
+
+                    if tx.send((idx, result)).is_err() {
+                        break;
+                    }
+                })
+            }).collect();
+        drop(tx);
+
+        let mut results: Vec<Option<Result<T, Error>>> = (0..count).map(|_| None).collect();
+        for (idx, result) in rx {
+            results[idx] = Some(result);
+        }
+        let threads = workers.len();
+        for worker in workers {
+            let _ = worker.join();
+        }
+        trace!("ran {} tasks on {} threads in {:?}", count, threads, started.elapsed());
+
+        // A task is only missing if its worker died while running it
+        results
+            .into_iter()
+            .map(|result| result.unwrap_or_else(|| Err(Error::from(PoolError::WorkerPanicked))))
+            .collect()
+    }
+}
+
+/// Split task results into the successes and the errors
+pub fn partition_results<T>(results: Vec<Result<T, Error>>) -> (Vec<T>, Vec<Error>) {
+    let mut ok = Vec::new();
+    let mut errors = Vec::new();
+    for result in results {
+        match result {
+            Ok(val) => ok.push(val),
+            Err(e) => errors.push(e),
+        }
+    }
+    (ok, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_results_keep_task_order() {
+        let pool = TaskPool::new(4);
+        let results = pool.map((0..50).collect(), |i: u64| {
+            // Make later tasks finish first
+            thread::sleep(Duration::from_millis(50 - i));
+            Ok(i * 2)
+        });
+
+        let results: Vec<u64> = results.into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(results, (0..50).map(|i| i * 2).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn test_concurrency_is_bounded() {
+        // (running, peak)
+        let counts = Arc::new(Mutex::new((0, 0)));
+
+        let pool = TaskPool::new(3);
+        let c = Arc::clone(&counts);
+        let results = pool.map((0..20).collect(), move |_: u32| {
+            {
+                let mut counts = c.lock().unwrap();
+                counts.0 += 1;
+                counts.1 = counts.1.max(counts.0);
+            }
+            thread::sleep(Duration::from_millis(5));
+            c.lock().unwrap().0 -= 1;
+            Ok(())
+        });
+
+        assert_eq!(results.len(), 20);
+        assert!(counts.lock().unwrap().1 <= 3);
+    }
+
+    #[test]
+    fn test_errors_do_not_stop_other_tasks() {
+        let pool = TaskPool::new(2);
+        let results = pool.map(vec![1, 2, 3, 4], |i: u32| {
+            if i % 2 == 0 {
+                Err(format_err!("task {} failed", i))
+            } else {
+                Ok(i)
+            }
+        });
+
+        let (ok, errors) = partition_results(results);
+        assert_eq!(ok, vec![1, 3]);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].to_string(), "task 2 failed");
+    }
+
+    #[test]
+    fn test_cancel_skips_pending_tasks() {
+        let pool = TaskPool::new(1);
+        let handle = pool.cancel_handle();
+        let results = pool.map(vec![1, 2, 3], move |i: u32| {
+            handle.store(true, Ordering::SeqCst);
+            Ok(i)
+        });
+
+        assert_eq!(results[0].as_ref().unwrap(), &1);
+        for result in &results[1..] {
+            let err = result.as_ref().err().unwrap();
+            assert!(err.downcast_ref::<PoolError>().is_some());
+        }
+        assert!(pool.is_cancelled());
+    }
+
+    #[test]
+    fn test_empty_task_list() {
+        let pool = TaskPool::new(4);
+        let results = pool.map(Vec::new(), |i: u32| Ok(i));
+        assert!(results.is_empty());
+    }
+}
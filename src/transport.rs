@@ -0,0 +1,213 @@
+use std::cmp;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use time;
+
+/// How often and how patiently to retry transient failures
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `attempt` (starting at 0). Uses
+    /// exponential backoff with full jitter, so that workers which failed
+    /// together don't all come back at the same moment.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self.ceiling(attempt);
+        let millis = ceiling.as_secs() * 1000 + u64::from(ceiling.subsec_millis());
+        if millis == 0 {
+            return ceiling;
+        }
+        Duration::from_millis(jitter() % (millis + 1))
+    }
+
+    /// The upper bound of the backoff for `attempt`
+    fn ceiling(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::max_value());
+        cmp::min(
+            self.base_delay
+                .checked_mul(factor)
+                .unwrap_or(self.max_delay),
+            self.max_delay,
+        )
+    }
+}
+
+/// Cheap randomness for jitter, no need for a real RNG here
+fn jitter() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let mut x = u64::from(nanos) ^ 0x9E37_79B9_7F4A_7C15;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    x
+}
+
+/// Limits the rate of requests across every thread sharing it
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Option<Duration>,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// Allow at most `per_second` requests per second, 0 for no limit
+    pub fn new(per_second: u64) -> Self {
+        let interval = if per_second == 0 {
+            None
+        } else {
+            Some(Duration::from_nanos(1_000_000_000 / per_second))
+        };
+        RateLimiter {
+            interval,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Block until the caller may send another request
+    pub fn acquire(&self) {
+        let wait = self.reserve();
+        if wait > Duration::from_millis(0) {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Reserve the next request slot, returning how long to wait for it.
+    /// The lock is only held while reserving, never while waiting.
+    pub fn reserve(&self) -> Duration {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return Duration::from_millis(0),
+        };
+
+        let mut next = self.next.lock().unwrap();
+        let now = Instant::now();
+        let slot = cmp::max(*next, now);
+        *next = slot + interval;
+        slot - now
+    }
+}
+
+/// Sleep for `duration`, waking early once `cancelled` is set. Returns
+/// whether the whole duration passed.
+pub fn sleep_unless(duration: Duration, cancelled: Option<&AtomicBool>) -> bool {
+    let cancelled = match cancelled {
+        Some(cancelled) => cancelled,
+        None => {
+            thread::sleep(duration);
+            return true;
+        }
+    };
+
+    let deadline = Instant::now() + duration;
+    loop {
+        if cancelled.load(Ordering::SeqCst) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        thread::sleep(cmp::min(deadline - now, Duration::from_millis(100)));
+    }
+}
+
+/// Parse a `Retry-After` header, given either in seconds or as an HTTP date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = time::strptime(value, "%a, %d %b %Y %H:%M:%S GMT").ok()?;
+    let delay = date.to_timespec().sec - time::get_time().sec;
+    Some(Duration::from_secs(cmp::max(delay, 0) as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_is_bounded() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+
+        assert_eq!(policy.ceiling(0), Duration::from_millis(100));
+        assert_eq!(policy.ceiling(1), Duration::from_millis(200));
+        assert_eq!(policy.ceiling(3), Duration::from_millis(800));
+        assert_eq!(policy.ceiling(4), Duration::from_secs(1));
+        assert_eq!(policy.ceiling(40), Duration::from_secs(1));
+
+        for attempt in 0..10 {
+            assert!(policy.backoff(attempt) <= policy.ceiling(attempt));
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(50);
+        let start = Instant::now();
+        for _ in 0..6 {
+            limiter.acquire();
+        }
+
+        // The first request goes out immediately, the other five wait 20ms each
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_unlimited_rate_limiter() {
+        let limiter = RateLimiter::new(0);
+        let start = Instant::now();
+        for _ in 0..1000 {
+            limiter.acquire();
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_sleep_unless() {
+        let cancelled = AtomicBool::new(false);
+        assert!(sleep_unless(Duration::from_millis(10), Some(&cancelled)));
+        assert!(sleep_unless(Duration::from_millis(10), None));
+
+        // Wakes up well before the full minute
+        cancelled.store(true, Ordering::SeqCst);
+        let start = Instant::now();
+        assert!(!sleep_unless(Duration::from_secs(60), Some(&cancelled)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::from_secs(0)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+}
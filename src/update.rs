@@ -0,0 +1,249 @@
+use deps::Depend;
+use failure::Error;
+use package::{vercmp, AurPkg};
+use std::cmp::Ordering;
+
+/// An installed package that isn't in any of the sync repositories
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Foreign {
+    pub name: String,
+    pub version: String,
+    /// When it was installed, in seconds since the epoch
+    pub installed: u64,
+}
+
+/// Name suffixes of packages built from a version control checkout
+pub const VCS_SUFFIXES: &[&str] = &["-git", "-svn", "-hg", "-bzr"];
+
+/// How an installed version compares to the one in the AUR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Outdated,
+    Current,
+    LocalNewer,
+    /// A VCS package that isn't behind the AUR, but whose upstream may
+    /// have moved on anyway
+    Vcs,
+}
+
+/// A foreign package that is still in the AUR
+#[derive(Debug, Clone)]
+pub struct Checked {
+    pub foreign: Foreign,
+    pub pkg: AurPkg,
+    pub status: Status,
+}
+
+/// A foreign package that the AUR no longer has
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vanished {
+    pub foreign: Foreign,
+    /// AUR packages that replace or provide it, in case it was renamed
+    pub renamed: Vec<String>,
+}
+
+/// What the AUR has to say about the foreign packages
+#[derive(Debug, Default)]
+pub struct Report {
+    pub packages: Vec<Checked>,
+    pub vanished: Vec<Vanished>,
+    /// Packages that no longer have a maintainer
+    pub orphaned: Vec<Foreign>,
+    /// Packages flagged out of date since they were installed, with when
+    pub flagged: Vec<(Foreign, u64)>,
+}
+
+/// Compare `foreign` packages with their AUR info in `pkgs`. `search`
+/// finds AUR packages that replace or provide a name, to suggest where
+/// vanished packages went.
+pub fn check<F>(foreign: Vec<Foreign>, pkgs: &[AurPkg], mut search: F) -> Result<Report, Error>
+where
+    F: FnMut(&str) -> Result<Vec<AurPkg>, Error>,
+{
+    let mut report = Report::default();
+    for foreign in foreign {
+        let pkg = match pkgs.iter().find(|pkg| pkg.name() == foreign.name) {
+            Some(pkg) => pkg,
+            None => {
+                let renamed = renamed(&foreign.name, search(&foreign.name)?);
+                report.vanished.push(Vanished { foreign, renamed });
+                continue;
+            }
+        };
+
+        if pkg.maintainer().is_empty() {
+            report.orphaned.push(foreign.clone());
+        }
+        if let Some(flagged) = pkg.out_of_date() {
+            if flagged >= foreign.installed {
+                report.flagged.push((foreign.clone(), flagged));
+            }
+        }
+        report.packages.push(Checked {
+            status: classify(&foreign, pkg),
+            foreign,
+            pkg: pkg.clone(),
+        });
+    }
+    Ok(report)
+}
+
+impl Report {
+    /// The packages to update. With `devel`, VCS packages always are.
+    pub fn updates(&self, devel: bool) -> Vec<&Checked> {
+        self.packages
+            .iter()
+            .filter(|checked| match checked.status {
+                Status::Outdated => true,
+                Status::Vcs => devel,
+                _ => false,
+            }).collect()
+    }
+}
+
+/// Whether `name` is built from a version control checkout
+pub fn is_vcs(name: &str) -> bool {
+    VCS_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+}
+
+/// Compare the installed version of `foreign` with `pkg`. A newer AUR
+/// version counts even for VCS packages, since the PKGBUILD changed.
+pub fn classify(foreign: &Foreign, pkg: &AurPkg) -> Status {
+    match vercmp(pkg.version(), &foreign.version) {
+        Ordering::Greater => Status::Outdated,
+        _ if is_vcs(&foreign.name) => Status::Vcs,
+        Ordering::Equal => Status::Current,
+        Ordering::Less => Status::LocalNewer,
+    }
+}
+
+/// The names of `candidates` that replace or provide `name`, those
+/// replacing it first
+fn renamed(name: &str, candidates: Vec<AurPkg>) -> Vec<String> {
+    let lists = |entries: &[String]| entries.iter().any(|e| Depend::parse(e).name == name);
+
+    let mut replacing = Vec::new();
+    let mut providing = Vec::new();
+    for pkg in candidates {
+        let found = pkg.name().to_string();
+        if found == name || replacing.contains(&found) || providing.contains(&found) {
+            continue;
+        }
+        if lists(pkg.replaces()) {
+            replacing.push(found);
+        } else if lists(pkg.provides()) {
+            providing.push(found);
+        }
+    }
+    replacing.append(&mut providing);
+    replacing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use package::aur_packages_from_json;
+
+    fn foreign(name: &str, version: &str, installed: u64) -> Foreign {
+        Foreign {
+            name: name.to_string(),
+            version: version.to_string(),
+            installed,
+        }
+    }
+
+    fn aur() -> Vec<AurPkg> {
+        aur_packages_from_json(
+            r#"{"version":5,"type":"multiinfo","resultcount":4,"results":[
+            {"ID":1,"Name":"cower","PackageBaseID":1,"PackageBase":"cower","Version":"2",
+             "Description":"","URL":"","NumVotes":0,"Popularity":0,"OutOfDate":null,
+             "Maintainer":"falconindy","FirstSubmitted":0,"LastModified":0,"URLPath":""},
+            {"ID":2,"Name":"pacaur","PackageBaseID":2,"PackageBase":"pacaur","Version":"1",
+             "Description":"","URL":"","NumVotes":0,"Popularity":0,"OutOfDate":2000,
+             "Maintainer":null,"FirstSubmitted":0,"LastModified":0,"URLPath":""},
+            {"ID":3,"Name":"auracle-git","PackageBaseID":3,"PackageBase":"auracle-git",
+             "Version":"1","Description":"","URL":"","NumVotes":0,"Popularity":0,
+             "OutOfDate":500,"Maintainer":"falconindy","FirstSubmitted":0,"LastModified":0,
+             "URLPath":"","Provides":["auracle"],"Replaces":["cower-git"]},
+            {"ID":4,"Name":"auracle","PackageBaseID":4,"PackageBase":"auracle",
+             "Version":"1","Description":"","URL":"","NumVotes":0,"Popularity":0,
+             "OutOfDate":null,"Maintainer":"falconindy","FirstSubmitted":0,"LastModified":0,
+             "URLPath":"","Provides":["cower-git"]}]}"#,
+        ).unwrap()
+    }
+
+    /// AUR packages that replace or provide `name`
+    fn search(name: &str) -> Result<Vec<AurPkg>, Error> {
+        let matches = |entries: &[String]| entries.iter().any(|e| Depend::parse(e).name == name);
+        Ok(aur()
+            .into_iter()
+            .filter(|pkg| matches(pkg.replaces()) || matches(pkg.provides()))
+            .collect())
+    }
+
+    #[test]
+    fn test_updates() {
+        let installed = vec![
+            foreign("cower", "1", 1000),
+            foreign("pacaur", "1", 1000),
+            foreign("auracle-git", "1", 1000),
+        ];
+        let report = check(installed, &aur(), search).unwrap();
+
+        let updates = report.updates(false);
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].foreign.name, "cower");
+        assert_eq!(updates[0].pkg.version(), "2");
+        assert_eq!(report.orphaned, vec![foreign("pacaur", "1", 1000)]);
+        // auracle-git was already flagged when it was installed
+        assert_eq!(report.flagged, vec![(foreign("pacaur", "1", 1000), 2000)]);
+        assert!(report.vanished.is_empty());
+    }
+
+    #[test]
+    fn test_vanished_and_renamed() {
+        let installed = vec![foreign("cower-git", "1", 0), foreign("yaourt", "1", 0)];
+        let report = check(installed, &aur(), search).unwrap();
+
+        assert!(report.packages.is_empty());
+        assert_eq!(
+            report.vanished,
+            vec![
+                Vanished {
+                    foreign: foreign("cower-git", "1", 0),
+                    renamed: vec!["auracle-git".to_string(), "auracle".to_string()],
+                },
+                Vanished {
+                    foreign: foreign("yaourt", "1", 0),
+                    renamed: Vec::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_classify() {
+        let pkgs = aur();
+        let cower = &pkgs[0];
+        let auracle = &pkgs[2];
+
+        assert_eq!(classify(&foreign("cower", "1", 0), cower), Status::Outdated);
+        assert_eq!(classify(&foreign("cower", "2", 0), cower), Status::Current);
+        assert_eq!(classify(&foreign("cower", "3", 0), cower), Status::LocalNewer);
+        assert_eq!(classify(&foreign("auracle-git", "2", 0), auracle), Status::Vcs);
+        assert_eq!(classify(&foreign("auracle-git", "1", 0), auracle), Status::Vcs);
+        assert_eq!(classify(&foreign("auracle-git", "0", 0), auracle), Status::Outdated);
+        assert!(is_vcs("foo-bzr") && !is_vcs("git"));
+    }
+
+    #[test]
+    fn test_devel_updates() {
+        let installed = vec![foreign("cower", "2", 0), foreign("auracle-git", "3", 0)];
+        let report = check(installed, &aur(), search).unwrap();
+
+        assert!(report.updates(false).is_empty());
+        let updates = report.updates(true);
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].foreign.name, "auracle-git");
+    }
+}